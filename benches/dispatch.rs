@@ -0,0 +1,74 @@
+//! Pickup latency: the time from `add_task` until an idle dispatcher has the task. Compares
+//! `Scheduler::next` with the loop it replaced, which polled `get_next` and slept 100ms when empty.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use criterion::{criterion_group, criterion_main, Criterion, SamplingMode};
+use dtqs::broker::{Delivery, Message};
+use dtqs::worker_scheduler::{ScheduledTask, Scheduler};
+use opentelemetry::Context;
+use serde_json::json;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+fn task() -> ScheduledTask {
+  ScheduledTask {
+    priority: 5,
+    delivery: Delivery { queue: "task_queue".into(), tag: 0, message: Message::default() },
+    task_data: json!({ "task_id": "bench", "task_type": "email", "tenant_id": "default" }),
+    trace_context: Context::new(),
+    consumer_generation: 0,
+  }
+}
+
+/// Total pickup latency over `iters` tasks, each queued once the dispatcher has gone idle. With
+/// `poll` set the dispatcher sleeps that long whenever the scheduler is empty.
+async fn pickup_latency(iters: u64, poll: Option<Duration>) -> Duration {
+  let scheduler = Arc::new(Scheduler::new(16, None));
+  let (picked_tx, mut picked_rx) = mpsc::unbounded_channel();
+  let dispatcher = {
+    let scheduler = scheduler.clone();
+    tokio::spawn(async move {
+      loop {
+        let dispatched = match poll {
+          None => scheduler.next().await,
+          Some(interval) => loop {
+            if let Some(dispatched) = scheduler.get_next().await {
+              break dispatched;
+            }
+            tokio::time::sleep(interval).await;
+          },
+        };
+        if picked_tx.send((Instant::now(), dispatched)).is_err() {
+          return;
+        }
+      }
+    })
+  };
+
+  let mut total = Duration::ZERO;
+  for _ in 0..iters {
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    let queued_at = Instant::now();
+    scheduler.add_task(task()).await;
+    let (picked_at, _) = picked_rx.recv().await.expect("dispatcher is running");
+    total += picked_at - queued_at;
+  }
+  dispatcher.abort();
+  total
+}
+
+fn bench_pickup_latency(c: &mut Criterion) {
+  let runtime = Runtime::new().unwrap();
+  let mut group = c.benchmark_group("pickup_latency");
+  group.sample_size(10).sampling_mode(SamplingMode::Flat);
+  group.bench_function("notify", |b| b.to_async(&runtime).iter_custom(|iters| pickup_latency(iters, None)));
+  group.measurement_time(Duration::from_secs(20));
+  group.bench_function("poll_100ms", |b| {
+    b.to_async(&runtime).iter_custom(|iters| pickup_latency(iters, Some(Duration::from_millis(100))))
+  });
+  group.finish();
+}
+
+criterion_group!(benches, bench_pickup_latency);
+criterion_main!(benches);
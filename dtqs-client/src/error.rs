@@ -0,0 +1,68 @@
+use std::fmt;
+use dtqs_types::ErrorBody;
+
+#[derive(Debug)]
+pub enum Error {
+  /// The API answered with an error body; `code` is one of the stable codes listed in the README.
+  Api { status: u16, body: ErrorBody },
+  /// The API answered with a non-success status and a body that is not an `ErrorBody`.
+  UnexpectedStatus { status: u16, body: String },
+  Http(reqwest::Error),
+  InvalidUrl(url::ParseError),
+  InvalidEvent(serde_json::Error),
+  /// `wait` gave up before the task reached a terminal status.
+  Timeout,
+}
+
+impl Error {
+  pub fn status(&self) -> Option<u16> {
+    match self {
+      Error::Api { status, .. } | Error::UnexpectedStatus { status, .. } => Some(*status),
+      Error::Http(e) => e.status().map(|s| s.as_u16()),
+      _ => None,
+    }
+  }
+
+  pub fn code(&self) -> Option<&str> {
+    match self {
+      Error::Api { body, .. } => Some(&body.error.code),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::Api { status, body } => write!(f, "{} {}: {} (request {})", status, body.error.code, body.error.message, body.error.request_id),
+      Error::UnexpectedStatus { status, body } => write!(f, "unexpected status {}: {}", status, body),
+      Error::Http(e) => write!(f, "HTTP error: {}", e),
+      Error::InvalidUrl(e) => write!(f, "invalid URL: {}", e),
+      Error::InvalidEvent(e) => write!(f, "invalid SSE event: {}", e),
+      Error::Timeout => write!(f, "timed out waiting for task"),
+    }
+  }
+}
+
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Error::Http(e) => Some(e),
+      Error::InvalidUrl(e) => Some(e),
+      Error::InvalidEvent(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<reqwest::Error> for Error {
+  fn from(e: reqwest::Error) -> Self {
+    Error::Http(e)
+  }
+}
+
+impl From<url::ParseError> for Error {
+  fn from(e: url::ParseError) -> Self {
+    Error::InvalidUrl(e)
+  }
+}
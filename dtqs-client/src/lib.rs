@@ -0,0 +1,286 @@
+//! Typed client for the DTQS API. Request and response types are the server's own, re-exported
+//! from `dtqs-types`, so they cannot drift from what the API accepts.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), dtqs_client::Error> {
+//! use dtqs_client::{Client, NewTask};
+//!
+//! let client = Client::builder("http://localhost:8080").token("secret").build()?;
+//! let submitted = client.submit(&NewTask {
+//!   task_type: "email".into(),
+//!   payload: serde_json::json!({"from": "a@example.com", "to": "b@example.com", "subject": "Hi", "content": "Hello"}),
+//!   priority: None,
+//!   schema_version: None,
+//! }).await?;
+//! let task = client.wait(submitted.task_id, None).await?;
+//! println!("{} finished as {}", task.id, task.status);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::time::Duration;
+use futures::{Stream, StreamExt};
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use tracing::warn;
+use url::Url;
+use uuid::Uuid;
+
+mod error;
+
+pub use error::Error;
+pub use dtqs_types::{ErrorBody, ErrorDetail, FieldError, NewTask, Task, TaskEvent, TaskListQuery, TaskResponse, TERMINAL_STATUSES};
+
+pub struct ClientBuilder {
+  base_url: String,
+  token: Option<String>,
+  timeout: Duration,
+  connect_timeout: Duration,
+  max_retries: u32,
+  retry_backoff: Duration,
+  poll_interval: Duration,
+}
+
+impl ClientBuilder {
+  pub fn new(base_url: impl Into<String>) -> Self {
+    Self {
+      base_url: base_url.into(),
+      token: None,
+      timeout: Duration::from_secs(30),
+      connect_timeout: Duration::from_secs(10),
+      max_retries: 3,
+      retry_backoff: Duration::from_millis(200),
+      poll_interval: Duration::from_secs(2),
+    }
+  }
+
+  /// API key or JWT sent as `Authorization: Bearer <token>`.
+  pub fn token(mut self, token: impl Into<String>) -> Self {
+    self.token = Some(token.into());
+    self
+  }
+
+  /// Limit for each request, excluding the long-lived `events` stream.
+  pub fn timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = timeout;
+    self
+  }
+
+  pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+    self.connect_timeout = timeout;
+    self
+  }
+
+  /// Retries after the first attempt; 0 disables retrying.
+  pub fn max_retries(mut self, max_retries: u32) -> Self {
+    self.max_retries = max_retries;
+    self
+  }
+
+  /// Delay before the first retry, doubled for each one after it.
+  pub fn retry_backoff(mut self, backoff: Duration) -> Self {
+    self.retry_backoff = backoff;
+    self
+  }
+
+  /// How often `wait` polls the task.
+  pub fn poll_interval(mut self, interval: Duration) -> Self {
+    self.poll_interval = interval;
+    self
+  }
+
+  pub fn build(self) -> Result<Client, Error> {
+    let mut base_url = Url::parse(&self.base_url)?;
+    if !base_url.path().ends_with('/') {
+      base_url.set_path(&format!("{}/", base_url.path()));
+    }
+    let http = reqwest::Client::builder()
+      .connect_timeout(self.connect_timeout)
+      .build()?;
+    Ok(Client {
+      http,
+      base_url,
+      token: self.token,
+      timeout: self.timeout,
+      max_retries: self.max_retries,
+      retry_backoff: self.retry_backoff,
+      poll_interval: self.poll_interval,
+    })
+  }
+}
+
+#[derive(Clone)]
+pub struct Client {
+  http: reqwest::Client,
+  base_url: Url,
+  token: Option<String>,
+  timeout: Duration,
+  max_retries: u32,
+  retry_backoff: Duration,
+  poll_interval: Duration,
+}
+
+impl Client {
+  pub fn builder(base_url: impl Into<String>) -> ClientBuilder {
+    ClientBuilder::new(base_url)
+  }
+
+  pub async fn submit(&self, task: &NewTask) -> Result<TaskResponse, Error> {
+    let url = self.url("submit")?;
+    // A submission that reached the API may already be stored, so only retry when it provably was not.
+    let response = self.send(false, || self.request(Method::POST, url.clone()).json(task)).await?;
+    json(response).await
+  }
+
+  pub async fn get(&self, task_id: Uuid) -> Result<Task, Error> {
+    let url = self.url(&format!("tasks/{}", task_id))?;
+    let response = self.send(true, || self.request(Method::GET, url.clone())).await?;
+    json(response).await
+  }
+
+  pub async fn list(&self, query: &TaskListQuery) -> Result<Vec<Task>, Error> {
+    let url = self.url("tasks")?;
+    let response = self.send(true, || self.request(Method::GET, url.clone()).query(query)).await?;
+    json(response).await
+  }
+
+  /// Not retried once the request may have reached the API: a repeat after a cancel that did go
+  /// through answers `409 conflict`, as the task is then already `cancelled`.
+  pub async fn cancel(&self, task_id: Uuid) -> Result<Task, Error> {
+    let url = self.url(&format!("tasks/{}/cancel", task_id))?;
+    let response = self.send(false, || self.request(Method::POST, url.clone())).await?;
+    json(response).await
+  }
+
+  /// Requeues a `failed` or `cancelled` task with its attempts reset.
+  pub async fn retry(&self, task_id: Uuid) -> Result<Task, Error> {
+    let url = self.url(&format!("tasks/{}/retry", task_id))?;
+    let response = self.send(false, || self.request(Method::POST, url.clone())).await?;
+    json(response).await
+  }
+
+  /// Polls the task until it reaches one of `TERMINAL_STATUSES`, or fails with `Error::Timeout`
+  /// once `timeout` has passed.
+  pub async fn wait(&self, task_id: Uuid, timeout: Option<Duration>) -> Result<Task, Error> {
+    let poll = async {
+      loop {
+        let task = self.get(task_id).await?;
+        if TERMINAL_STATUSES.contains(&task.status.as_str()) {
+          return Ok(task);
+        }
+        tokio::time::sleep(self.poll_interval).await;
+      }
+    };
+    match timeout {
+      Some(timeout) => tokio::time::timeout(timeout, poll).await.map_err(|_| Error::Timeout)?,
+      None => poll.await,
+    }
+  }
+
+  /// Follows `/sse` for the task. The server repeats the latest state every couple of seconds once
+  /// the task has left `pending`, so callers usually stop at the first terminal status.
+  pub async fn events(&self, task_id: Uuid) -> Result<impl Stream<Item = Result<TaskEvent, Error>> + use<>, Error> {
+    let mut url = self.url("sse")?;
+    url.query_pairs_mut().append_pair("task_id", &task_id.to_string());
+    let response = self.send(true, || self.authorize(self.http.get(url.clone()))).await?;
+
+    let body = response.bytes_stream().boxed();
+    Ok(futures::stream::try_unfold((body, Vec::new()), |(mut body, mut buffer)| async move {
+      loop {
+        if let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+          let raw: Vec<u8> = buffer.drain(..end + 2).collect();
+          match parse_event(&raw)? {
+            Some(event) => return Ok(Some((event, (body, buffer)))),
+            None => continue,
+          }
+        }
+        match body.next().await {
+          Some(chunk) => buffer.extend_from_slice(&chunk?),
+          None => return Ok(None),
+        }
+      }
+    }))
+  }
+
+  fn url(&self, path: &str) -> Result<Url, Error> {
+    Ok(self.base_url.join(path)?)
+  }
+
+  fn request(&self, method: Method, url: Url) -> RequestBuilder {
+    self.authorize(self.http.request(method, url).timeout(self.timeout))
+  }
+
+  fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+    match &self.token {
+      Some(token) => request.bearer_auth(token),
+      None => request,
+    }
+  }
+
+  /// Sends the request built by `build`, retrying connection failures and `429`s, plus timeouts
+  /// and `502`/`503`/`504` when the request is `idempotent`.
+  async fn send(&self, idempotent: bool, build: impl Fn() -> RequestBuilder) -> Result<Response, Error> {
+    let mut attempt = 0;
+    loop {
+      attempt += 1;
+      let delay = match build().send().await {
+        Ok(response) if response.status().is_success() => return Ok(response),
+        Ok(response) => {
+          let status = response.status();
+          let retryable = status == StatusCode::TOO_MANY_REQUESTS
+            || (idempotent && matches!(status, StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT));
+          if !retryable || attempt > self.max_retries {
+            return Err(api_error(response).await);
+          }
+          retry_after(&response).unwrap_or_else(|| self.backoff(attempt))
+        }
+        Err(e) => {
+          if !(e.is_connect() || (idempotent && e.is_timeout())) || attempt > self.max_retries {
+            return Err(e.into());
+          }
+          self.backoff(attempt)
+        }
+      };
+      warn!("DTQS request failed (attempt {}), retrying in {:?}", attempt, delay);
+      tokio::time::sleep(delay).await;
+    }
+  }
+
+  fn backoff(&self, attempt: u32) -> Duration {
+    self.retry_backoff.saturating_mul(1 << (attempt - 1).min(16)).min(Duration::from_secs(30))
+  }
+}
+
+async fn json<T: DeserializeOwned>(response: Response) -> Result<T, Error> {
+  Ok(response.json().await?)
+}
+
+async fn api_error(response: Response) -> Error {
+  let status = response.status().as_u16();
+  match response.text().await {
+    Ok(text) => match serde_json::from_str::<ErrorBody>(&text) {
+      Ok(body) => Error::Api { status, body },
+      Err(_) => Error::UnexpectedStatus { status, body: text },
+    },
+    Err(e) => e.into(),
+  }
+}
+
+fn retry_after(response: &Response) -> Option<Duration> {
+  let seconds = response.headers().get("retry-after")?.to_str().ok()?.parse().ok()?;
+  Some(Duration::from_secs(seconds))
+}
+
+/// Returns `None` for frames without data, such as keep-alive comments.
+fn parse_event(raw: &[u8]) -> Result<Option<TaskEvent>, Error> {
+  let text = String::from_utf8_lossy(raw);
+  let data: Vec<&str> = text
+    .lines()
+    .filter_map(|line| line.strip_prefix("data:"))
+    .map(|data| data.strip_prefix(' ').unwrap_or(data))
+    .collect();
+  if data.is_empty() {
+    return Ok(None);
+  }
+  serde_json::from_str(&data.join("\n")).map(Some).map_err(Error::InvalidEvent)
+}
@@ -0,0 +1,127 @@
+//! Runs the client against a stub API on an ephemeral port.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use dtqs_client::{Client, Error, NewTask, TaskEvent};
+use futures::StreamExt;
+use serde_json::json;
+use uuid::Uuid;
+use warp::http::{Response, StatusCode};
+use warp::hyper::Body;
+use warp::Filter;
+
+/// Serves every request with `respond(n)`, where `n` counts requests from 1.
+async fn stub(respond: impl Fn(usize) -> Response<Body> + Clone + Send + Sync + 'static) -> (Client, Arc<AtomicUsize>) {
+  let hits = Arc::new(AtomicUsize::new(0));
+  let counter = hits.clone();
+  let routes = warp::any().map(move || respond(counter.fetch_add(1, Ordering::SeqCst) + 1));
+  let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+  tokio::spawn(server);
+  let client = Client::builder(format!("http://{}", addr))
+    .max_retries(2)
+    .retry_backoff(Duration::from_millis(1))
+    .build()
+    .unwrap();
+  (client, hits)
+}
+
+fn status(status: u16) -> Response<Body> {
+  Response::builder().status(status).body(Body::empty()).unwrap()
+}
+
+fn json_response(status: u16, body: serde_json::Value) -> Response<Body> {
+  Response::builder().status(status).header("content-type", "application/json").body(Body::from(body.to_string())).unwrap()
+}
+
+fn error_body(status: u16, code: &str) -> Response<Body> {
+  json_response(status, json!({"error": {"code": code, "message": "nope", "status": status, "request_id": "req-1"}}))
+}
+
+fn task(id: Uuid, status: &str) -> serde_json::Value {
+  json!({
+    "id": id, "tenant_id": "acme", "task_type": "email", "task_type_version": 1, "payload": {},
+    "status": status, "priority": 5, "progress": 0, "attempts": 0,
+    "created_at": "2024-01-01T00:00:00Z", "updated_at": "2024-01-01T00:00:00Z",
+  })
+}
+
+fn new_task() -> NewTask {
+  NewTask { task_type: "email".into(), payload: json!({}), priority: None, schema_version: None }
+}
+
+#[tokio::test]
+async fn idempotent_requests_retry_unavailable_responses() {
+  let id = Uuid::new_v4();
+  let (client, hits) = stub(move |n| if n < 3 { status(503) } else { json_response(200, task(id, "pending")) }).await;
+  assert_eq!(client.get(id).await.unwrap().id, id);
+  assert_eq!(hits.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn retries_stop_after_max_retries() {
+  let (client, hits) = stub(|_| error_body(503, "publish_failed")).await;
+  let error = client.get(Uuid::new_v4()).await.unwrap_err();
+  assert_eq!(error.status(), Some(503));
+  assert_eq!(hits.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn submit_and_cancel_are_not_repeated_after_reaching_the_api() {
+  let (client, hits) = stub(|_| status(503)).await;
+  assert_eq!(client.submit(&new_task()).await.unwrap_err().status(), Some(503));
+  assert_eq!(client.cancel(Uuid::new_v4()).await.unwrap_err().status(), Some(503));
+  assert_eq!(hits.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn rate_limited_requests_are_retried_after_the_given_delay() {
+  let id = Uuid::new_v4();
+  let (client, hits) = stub(move |n| match n {
+    1 => {
+      let mut response = error_body(429, "rate_limited");
+      response.headers_mut().insert("retry-after", "0".parse().unwrap());
+      response
+    }
+    _ => json_response(200, json!({"task_id": id, "status": "pending", "sse_url": "/sse"})),
+  }).await;
+  assert_eq!(client.submit(&new_task()).await.unwrap().task_id, id);
+  assert_eq!(hits.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn error_bodies_map_to_api_errors() {
+  let (client, _) = stub(|_| error_body(404, "not_found")).await;
+  let error = client.get(Uuid::new_v4()).await.unwrap_err();
+  assert!(matches!(&error, Error::Api { status: 404, body } if body.error.request_id == "req-1"));
+  assert_eq!(error.code(), Some("not_found"));
+
+  let (client, _) = stub(|_| Response::builder().status(500).body(Body::from("upstream exploded")).unwrap()).await;
+  let error = client.get(Uuid::new_v4()).await.unwrap_err();
+  assert!(matches!(&error, Error::UnexpectedStatus { status: 500, body } if body == "upstream exploded"));
+  assert_eq!(error.code(), None);
+}
+
+#[tokio::test]
+async fn events_are_parsed_across_chunk_boundaries() {
+  let id = Uuid::new_v4();
+  let stream = format!(
+    ": keep-alive\n\ndata: {{\"task_id\":\"{id}\",\"status\":\"in_progress\",\ndata: \"progress\":40}}\n\nevent: update\ndata: {{\"task_id\":\"{id}\",\"status\":\"completed\",\"progress\":100}}\n\n"
+  );
+  let (client, _) = stub(move |_| {
+    // Split mid-frame, so the client has to buffer until the blank line.
+    let chunks: Vec<Result<String, std::io::Error>> = stream.as_bytes().chunks(7).map(|c| Ok(String::from_utf8_lossy(c).into_owned())).collect();
+    Response::builder().status(StatusCode::OK).header("content-type", "text/event-stream").body(Body::wrap_stream(futures::stream::iter(chunks))).unwrap()
+  }).await;
+
+  let events: Vec<TaskEvent> = client.events(id).await.unwrap().map(Result::unwrap).collect().await;
+  let events: Vec<(&str, i32)> = events.iter().map(|e| (e.status.as_str(), e.progress)).collect();
+  assert_eq!(events, [("in_progress", 40), ("completed", 100)]);
+}
+
+#[tokio::test]
+async fn malformed_events_are_reported() {
+  let (client, _) = stub(|_| Response::builder().header("content-type", "text/event-stream").body(Body::from("data: {not json}\n\n")).unwrap()).await;
+  let mut events = Box::pin(client.events(Uuid::new_v4()).await.unwrap());
+  assert!(matches!(events.next().await, Some(Err(Error::InvalidEvent(_)))));
+}
@@ -0,0 +1,85 @@
+//! Request and response bodies of the DTQS API, shared by the server and `dtqs-client` so the
+//! client does not pull in the server's dependencies.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Task {
+  pub id: Uuid,
+  pub tenant_id: String,
+  pub task_type: String,
+  pub task_type_version: Option<i32>,
+  pub payload: serde_json::Value,
+  pub status: String,
+  pub priority: i32,
+  pub progress: i32,
+  pub attempts: i32,
+  pub created_at: DateTime<Utc>,
+  pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct NewTask {
+  pub task_type: String,
+  /// Must match the JSON Schema registered for `task_type`.
+  pub payload: serde_json::Value,
+  /// Lower values are scheduled first; defaults to 5.
+  pub priority: Option<u8>,
+  /// Schema version to validate against; defaults to the latest.
+  pub schema_version: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TaskResponse {
+  pub task_id: Uuid,
+  pub status: String,
+  pub sse_url: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TaskListQuery {
+  pub status: Option<String>,
+  pub task_type: Option<String>,
+  /// Defaults to 50, at most 500.
+  pub limit: Option<i64>,
+  pub offset: Option<i64>,
+}
+
+/// Statuses after which a task never changes again.
+pub const TERMINAL_STATUSES: [&str; 3] = ["completed", "failed", "cancelled"];
+
+/// One `data:` payload on the `/sse` stream.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct TaskEvent {
+  pub task_id: Uuid,
+  pub status: String,
+  pub progress: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ErrorBody {
+  pub error: ErrorDetail,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ErrorDetail {
+  /// Stable machine-readable error code, e.g. `validation_failed`.
+  pub code: String,
+  pub message: String,
+  pub status: u16,
+  pub request_id: String,
+  /// Per-field failures, present for payload validation errors.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub details: Option<Vec<FieldError>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct FieldError {
+  /// JSON pointer to the offending value; empty for the payload root.
+  pub path: String,
+  pub message: String,
+}
@@ -0,0 +1,6 @@
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS tenant_id VARCHAR(64) NOT NULL DEFAULT 'default';
+ALTER TABLE logs ADD COLUMN IF NOT EXISTS tenant_id VARCHAR(64) NULL;
+ALTER TABLE worker_nodes ADD COLUMN IF NOT EXISTS tenant_id VARCHAR(64) NULL;
+
+CREATE INDEX IF NOT EXISTS tasks_tenant_status_idx ON tasks (tenant_id, status, created_at);
+CREATE INDEX IF NOT EXISTS logs_tenant_idx ON logs (tenant_id, created_at);
\ No newline at end of file
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS tenant_quotas (
+    tenant_id VARCHAR(64) NOT NULL,
+    task_type VARCHAR(64) NOT NULL DEFAULT '*',
+    max_outstanding INTEGER NULL,
+    max_payload_bytes BIGINT NULL,
+    PRIMARY KEY (tenant_id, task_type)
+);
\ No newline at end of file
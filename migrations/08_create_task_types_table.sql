@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS task_types (
+    name VARCHAR(64) NOT NULL,
+    version INTEGER NOT NULL,
+    schema JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (name, version)
+);
+
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS task_type_version INTEGER NULL;
+
+INSERT INTO task_types (name, version, schema)
+VALUES
+    ('email', 1, '{"type": "object", "required": ["from", "to", "subject", "content"], "properties": {"from": {"type": "string"}, "to": {"type": "string"}, "subject": {"type": "string"}, "content": {"type": "string"}}}'),
+    ('image', 1, '{"type": "object", "required": ["img_src", "resize_factor"], "properties": {"img_src": {"type": "string"}, "resize_factor": {}}}'),
+    ('video', 1, '{"type": "object", "required": ["vid_src", "resize_factor"], "properties": {"vid_src": {"type": "string"}, "resize_factor": {}}}')
+ON CONFLICT DO NOTHING;
\ No newline at end of file
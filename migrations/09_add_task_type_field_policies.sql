@@ -0,0 +1,13 @@
+ALTER TABLE task_types ADD COLUMN IF NOT EXISTS field_policies JSONB NOT NULL DEFAULT '{}';
+
+UPDATE task_types
+SET field_policies = '{"from": {"kind": "email"}, "to": {"kind": "email"}, "subject": {"kind": "text", "max_length": 255}, "content": {"kind": "text", "max_length": 100000}}'
+WHERE name = 'email' AND version = 1;
+
+UPDATE task_types
+SET field_policies = '{"img_src": {"kind": "url", "schemes": ["http", "https"]}}'
+WHERE name = 'image' AND version = 1;
+
+UPDATE task_types
+SET field_policies = '{"vid_src": {"kind": "url", "schemes": ["http", "https"]}}'
+WHERE name = 'video' AND version = 1;
\ No newline at end of file
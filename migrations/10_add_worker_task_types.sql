@@ -0,0 +1 @@
+ALTER TABLE worker_nodes ADD COLUMN IF NOT EXISTS task_types TEXT[] NULL;
\ No newline at end of file
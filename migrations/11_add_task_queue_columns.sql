@@ -0,0 +1,8 @@
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS queue VARCHAR(255) NULL;
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS queue_message JSONB NULL;
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS queue_headers JSONB NULL;
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS visible_at TIMESTAMPTZ NULL;
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS lease_id UUID NULL;
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS lease_expires_at TIMESTAMPTZ NULL;
+
+CREATE INDEX IF NOT EXISTS tasks_queue_idx ON tasks (queue, priority, created_at) WHERE queue IS NOT NULL;
\ No newline at end of file
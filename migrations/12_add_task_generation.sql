@@ -0,0 +1 @@
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS generation INTEGER NOT NULL DEFAULT 0;
\ No newline at end of file
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use anyhow::{Result, anyhow};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use jsonwebtoken::jwk::JwkSet;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+use warp::Filter;
+use crate::config::Config;
+use crate::error::ApiError;
+
+pub const SCOPE_TASKS_READ: &str = "tasks:read";
+pub const SCOPE_TASKS_WRITE: &str = "tasks:write";
+pub const SCOPE_ADMIN: &str = "admin";
+pub const DEFAULT_TENANT: &str = "default";
+
+#[derive(Debug, Clone)]
+pub struct Principal {
+  pub subject: String,
+  pub tenant_id: String,
+  pub scopes: Vec<String>,
+}
+
+impl Principal {
+  pub fn has_scope(&self, scope: &str) -> bool {
+    self.scopes.iter().any(|s| s == scope || s == "*")
+  }
+}
+
+pub struct JwtValidator {
+  keys: JwkSet,
+  audience: Option<String>,
+  issuer: Option<String>,
+  tenant_claim: String,
+  scopes_claim: String,
+}
+
+impl JwtValidator {
+  pub async fn load(config: &Config, jwks_source: &str) -> Result<Self> {
+    let raw = if jwks_source.starts_with("http://") || jwks_source.starts_with("https://") {
+      reqwest::get(jwks_source).await?.error_for_status()?.text().await?
+    } else {
+      tokio::fs::read_to_string(jwks_source).await?
+    };
+    let keys: JwkSet = serde_json::from_str(&raw)?;
+    info!("Loaded {} JWKS key(s) from {}", keys.keys.len(), jwks_source);
+    Ok(Self {
+      keys,
+      audience: config.jwt_audience.clone(),
+      issuer: config.jwt_issuer.clone(),
+      tenant_claim: config.jwt_tenant_claim.clone(),
+      scopes_claim: config.jwt_scopes_claim.clone(),
+    })
+  }
+
+  pub fn validate(&self, token: &str) -> Result<Principal> {
+    let header = decode_header(token)?;
+    let jwk = match &header.kid {
+      Some(kid) => self.keys.find(kid).ok_or_else(|| anyhow!("Unknown key id '{}'", kid))?,
+      None if self.keys.keys.len() == 1 => &self.keys.keys[0],
+      None => return Err(anyhow!("Token has no key id")),
+    };
+    if let Some(key_algorithm) = jwk.common.key_algorithm
+      && key_algorithm.to_string().parse::<Algorithm>()? != header.alg {
+      return Err(anyhow!("Token algorithm does not match key"));
+    }
+
+    let mut validation = Validation::new(header.alg);
+    match &self.audience {
+      Some(audience) => validation.set_audience(&[audience]),
+      None => validation.validate_aud = false,
+    }
+    if let Some(issuer) = &self.issuer {
+      validation.set_issuer(&[issuer]);
+    }
+    let claims = decode::<HashMap<String, Value>>(token, &DecodingKey::from_jwk(jwk)?, &validation)?.claims;
+
+    let subject = claims.get("sub")
+      .and_then(|v| v.as_str())
+      .ok_or_else(|| anyhow!("Missing 'sub' claim"))?
+      .to_string();
+    let tenant_id = claims.get(&self.tenant_claim)
+      .and_then(|v| v.as_str())
+      .ok_or_else(|| anyhow!("Missing '{}' claim", self.tenant_claim))?
+      .to_string();
+    let scopes = match claims.get(&self.scopes_claim) {
+      Some(Value::String(s)) => s.split_whitespace().map(String::from).collect(),
+      Some(Value::Array(items)) => items.iter().filter_map(|v| v.as_str()).map(String::from).collect(),
+      _ => vec![],
+    };
+    Ok(Principal { subject, tenant_id, scopes })
+  }
+}
+
+pub struct Authenticator {
+  api_keys: HashMap<String, Principal>,
+  jwt: Option<JwtValidator>,
+}
+
+impl Authenticator {
+  pub async fn from_config(config: &Config) -> Result<Self> {
+    let api_keys = match &config.api_keys {
+      Some(spec) => parse_api_keys(spec)?,
+      None => HashMap::new(),
+    };
+    let jwt = match &config.jwks_source {
+      Some(source) => Some(JwtValidator::load(config, source).await?),
+      None => None,
+    };
+    if api_keys.is_empty() && jwt.is_none() {
+      warn!("No API_KEYS or JWKS_SOURCE configured, authentication is disabled");
+    }
+    Ok(Self { api_keys, jwt })
+  }
+
+  pub fn authenticate(&self, authorization: Option<&str>) -> Result<Principal, ApiError> {
+    if self.api_keys.is_empty() && self.jwt.is_none() {
+      return Ok(Principal {
+        subject: "anonymous".into(),
+        tenant_id: DEFAULT_TENANT.into(),
+        scopes: vec!["*".into()],
+      });
+    }
+
+    let token = authorization
+      .and_then(|h| h.strip_prefix("Bearer "))
+      .map(str::trim)
+      .ok_or_else(|| ApiError::Unauthorized("Missing bearer token".into()))?;
+
+    if let Some(principal) = self.api_keys.get(token) {
+      return Ok(principal.clone());
+    }
+    match &self.jwt {
+      Some(jwt) => jwt.validate(token).map_err(|e| {
+        warn!("Rejected bearer token: {}", e);
+        ApiError::Unauthorized("Invalid bearer token".into())
+      }),
+      None => Err(ApiError::Unauthorized("Invalid API key".into())),
+    }
+  }
+}
+
+/// Parses `key:tenant[:scope+scope]` entries separated by commas.
+fn parse_api_keys(spec: &str) -> Result<HashMap<String, Principal>> {
+  let mut keys = HashMap::new();
+  for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+    let mut parts = entry.splitn(3, ':');
+    let key = parts.next().unwrap_or_default();
+    let tenant_id = parts.next().ok_or_else(|| anyhow!("API key entry is missing a tenant"))?;
+    let scopes = match parts.next() {
+      Some(scopes) => scopes.split('+').map(String::from).collect(),
+      None => vec![SCOPE_TASKS_READ.to_string(), SCOPE_TASKS_WRITE.to_string()],
+    };
+    let subject = api_key_subject(key);
+    keys.insert(key.to_string(), Principal { subject, tenant_id: tenant_id.to_string(), scopes });
+  }
+  Ok(keys)
+}
+
+/// Identifies a key by a digest prefix, so keys sharing a common prefix get distinct rate limit
+/// buckets and no key material ends up in logs.
+fn api_key_subject(key: &str) -> String {
+  let digest = format!("{:x}", Sha256::digest(key.as_bytes()));
+  format!("apikey:{}", &digest[..16])
+}
+
+pub fn with_principal(auth: Arc<Authenticator>, scope: &'static str) -> impl Filter<Extract = (Principal,), Error = warp::Rejection> + Clone {
+  warp::header::optional::<String>("authorization")
+    .and_then(move |authorization: Option<String>| {
+      let auth = auth.clone();
+      async move {
+        let principal = auth.authenticate(authorization.as_deref()).map_err(warp::reject::custom)?;
+        if !principal.has_scope(scope) {
+          return Err(warp::reject::custom(ApiError::Forbidden(format!("Missing scope '{}'", scope))));
+        }
+        Ok(principal)
+      }
+    })
+}
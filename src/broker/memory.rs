@@ -0,0 +1,197 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::sync::Notify;
+use crate::topology::{dead_letter_queue, MAX_PRIORITY};
+use super::{Broker, Deliveries, Delivery, Message};
+
+/// Place in a queue: priority level (same levels as `amqp_priority`, lowest first), then publish order.
+type Slot = (u8, u64);
+
+/// Queues in process memory, delivered in the same order as the RabbitMQ queues: priority first,
+/// then publish order, with requeued messages going back to their old place. Nothing survives a
+/// restart. Cheap to clone; clones share the queues.
+#[derive(Clone, Default)]
+pub struct MemoryBroker {
+  inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+  state: Mutex<State>,
+  /// Woken on every publish, settlement and cancellation.
+  changed: Notify,
+}
+
+#[derive(Default)]
+struct State {
+  queues: HashMap<String, BTreeMap<Slot, Message>>,
+  consumers: HashMap<String, Consumer>,
+  unsettled: HashMap<u64, Unsettled>,
+  next_seq: u64,
+  next_tag: u64,
+}
+
+struct Consumer {
+  prefetch: usize,
+  in_flight: usize,
+}
+
+struct Unsettled {
+  consumer_tag: String,
+  slot: Slot,
+}
+
+impl MemoryBroker {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Messages waiting in `queue`, not counting deliveries that are still unsettled.
+  pub fn len(&self, queue: &str) -> usize {
+    self.inner.state.lock().unwrap().queues.get(queue).map_or(0, BTreeMap::len)
+  }
+
+  fn settle(&self, delivery: &Delivery) -> Result<Unsettled> {
+    let mut state = self.inner.state.lock().unwrap();
+    let unsettled = state.unsettled
+      .remove(&delivery.tag)
+      .ok_or_else(|| anyhow!("delivery {} was already settled", delivery.tag))?;
+    if let Some(consumer) = state.consumers.get_mut(&unsettled.consumer_tag) {
+      consumer.in_flight -= 1;
+    }
+    Ok(unsettled)
+  }
+
+  fn push(&self, queue: &str, message: Message) {
+    self.inner.state.lock().unwrap().push(queue, message);
+    self.inner.changed.notify_waiters();
+  }
+}
+
+impl State {
+  fn push(&mut self, queue: &str, message: Message) {
+    self.next_seq += 1;
+    let level = message.priority.clamp(0, MAX_PRIORITY.into()) as u8;
+    self.queues.entry(queue.to_string()).or_default().insert((level, self.next_seq), message);
+  }
+
+  /// The next message for `consumer_tag` if it has prefetch to spare; `Err` once it is cancelled.
+  fn deliver(&mut self, queue: &str, consumer_tag: &str) -> Result<Option<Delivery>, ()> {
+    let consumer = self.consumers.get_mut(consumer_tag).ok_or(())?;
+    if consumer.prefetch > 0 && consumer.in_flight >= consumer.prefetch {
+      return Ok(None);
+    }
+    let Some((slot, message)) = self.queues.get_mut(queue).and_then(BTreeMap::pop_first) else {
+      return Ok(None);
+    };
+    consumer.in_flight += 1;
+    self.next_tag += 1;
+    self.unsettled.insert(self.next_tag, Unsettled { consumer_tag: consumer_tag.to_string(), slot });
+    Ok(Some(Delivery { queue: queue.to_string(), tag: self.next_tag, message }))
+  }
+}
+
+#[async_trait]
+impl Broker for MemoryBroker {
+  fn name(&self) -> &'static str {
+    "memory"
+  }
+
+  fn status(&self) -> Result<(), String> {
+    Ok(())
+  }
+
+  async fn ready(&self) {}
+
+  async fn declare_queue(&self, queue: &str) -> Result<()> {
+    self.inner.state.lock().unwrap().queues.entry(queue.to_string()).or_default();
+    Ok(())
+  }
+
+  async fn publish(&self, queue: &str, message: Message) -> Result<()> {
+    self.push(queue, message);
+    Ok(())
+  }
+
+  async fn consume(&self, queue: &str, consumer_tag: &str, prefetch: u16) -> Result<Deliveries> {
+    {
+      let mut state = self.inner.state.lock().unwrap();
+      if state.consumers.contains_key(consumer_tag) {
+        return Err(anyhow!("consumer tag {} is already in use", consumer_tag));
+      }
+      state.consumers.insert(consumer_tag.to_string(), Consumer { prefetch: prefetch.into(), in_flight: 0 });
+    }
+    let consumer = (self.inner.clone(), queue.to_string(), consumer_tag.to_string());
+    let deliveries = futures::stream::unfold(consumer, |(inner, queue, consumer_tag)| async move {
+      loop {
+        let waker = inner.clone();
+        let changed = waker.changed.notified();
+        tokio::pin!(changed);
+        // Registered before looking, so a publish in between is not missed.
+        changed.as_mut().enable();
+        let next = inner.state.lock().unwrap().deliver(&queue, &consumer_tag);
+        match next {
+          Ok(Some(delivery)) => return Some((Ok(delivery), (inner, queue, consumer_tag))),
+          Ok(None) => {}
+          Err(()) => return None,
+        }
+        changed.await;
+      }
+    });
+    Ok(Box::pin(deliveries))
+  }
+
+  async fn cancel(&self, consumer_tag: &str) -> Result<()> {
+    self.inner.state.lock().unwrap()
+      .consumers
+      .remove(consumer_tag)
+      .ok_or_else(|| anyhow!("no consumer {}", consumer_tag))?;
+    self.inner.changed.notify_waiters();
+    Ok(())
+  }
+
+  async fn ack(&self, delivery: &Delivery) -> Result<()> {
+    self.settle(delivery)?;
+    self.inner.changed.notify_waiters();
+    Ok(())
+  }
+
+  async fn nack(&self, delivery: &Delivery, requeue: bool) -> Result<()> {
+    let unsettled = self.settle(delivery)?;
+    if requeue {
+      self.inner.state.lock().unwrap()
+        .queues
+        .entry(delivery.queue.clone())
+        .or_default()
+        .insert(unsettled.slot, delivery.message.clone());
+    }
+    self.inner.changed.notify_waiters();
+    Ok(())
+  }
+
+  async fn delay(&self, delivery: &Delivery, delay: Duration) -> Result<()> {
+    self.settle(delivery)?;
+    self.inner.changed.notify_waiters();
+    let broker = self.clone();
+    let delivery = delivery.clone();
+    tokio::spawn(async move {
+      tokio::time::sleep(delay).await;
+      broker.push(&delivery.queue, delivery.message);
+    });
+    Ok(())
+  }
+
+  async fn dead_letter(&self, delivery: &Delivery) -> Result<()> {
+    self.settle(delivery)?;
+    self.push(&dead_letter_queue(&delivery.queue), delivery.message.clone());
+    Ok(())
+  }
+
+  async fn close(&self) {
+    *self.inner.state.lock().unwrap() = State::default();
+    self.inner.changed.notify_waiters();
+  }
+}
@@ -0,0 +1,96 @@
+//! Queue backends behind one interface. The API publishes and workers consume through `Broker`, so
+//! RabbitMQ can be swapped for the `tasks` table via `PostgresBroker`, for Redis Streams via
+//! `RedisBroker`, or for the in-process `MemoryBroker` in tests and single-node mode.
+
+pub mod memory;
+pub mod postgres;
+pub mod rabbitmq;
+pub mod redis_streams;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use sqlx::{Pool, Postgres};
+use crate::messaging::RabbitConnection;
+
+pub use memory::MemoryBroker;
+pub use postgres::PostgresBroker;
+pub use rabbitmq::RabbitBroker;
+pub use redis_streams::RedisBroker;
+
+#[derive(Clone, Debug, Default)]
+pub struct Message {
+  pub payload: Vec<u8>,
+  /// Task priority, 0 first.
+  pub priority: i32,
+  /// Carries the trace context (`traceparent`, `tracestate`).
+  pub headers: HashMap<String, String>,
+}
+
+/// A message handed to a consumer. It counts against the consumer's prefetch until it is settled
+/// with exactly one of `ack`, `nack`, `delay` or `dead_letter`.
+#[derive(Clone, Debug)]
+pub struct Delivery {
+  pub queue: String,
+  /// Assigned by the broker that handed out the delivery; unique within it.
+  pub tag: u64,
+  pub message: Message,
+}
+
+/// Ends when the consumer is cancelled, or with an error when the connection is lost.
+pub type Deliveries = BoxStream<'static, Result<Delivery>>;
+
+#[async_trait]
+pub trait Broker: Send + Sync {
+  /// Backend name, reported by the readiness checks.
+  fn name(&self) -> &'static str;
+
+  /// `Ok` while connected.
+  fn status(&self) -> Result<(), String>;
+
+  /// Waits until consumers can be started, e.g. after a reconnect.
+  async fn ready(&self);
+
+  /// Creates `queue` if needed and keeps it declared across reconnects.
+  async fn declare_queue(&self, queue: &str) -> Result<()>;
+
+  async fn publish(&self, queue: &str, message: Message) -> Result<()>;
+
+  /// Delivers from `queue`, highest priority first, with at most `prefetch` unsettled deliveries
+  /// (0 for no limit).
+  async fn consume(&self, queue: &str, consumer_tag: &str, prefetch: u16) -> Result<Deliveries>;
+
+  /// Stops a consumer. Its unsettled deliveries can still be settled.
+  async fn cancel(&self, consumer_tag: &str) -> Result<()>;
+
+  async fn ack(&self, delivery: &Delivery) -> Result<()>;
+
+  /// Returns the delivery to its queue, or drops it when `requeue` is false.
+  async fn nack(&self, delivery: &Delivery, requeue: bool) -> Result<()>;
+
+  /// Settles the delivery and puts it back on its queue after `delay`.
+  async fn delay(&self, delivery: &Delivery, delay: Duration) -> Result<()>;
+
+  /// Settles the delivery and moves it to its queue's `dead_letter_queue`.
+  async fn dead_letter(&self, delivery: &Delivery) -> Result<()>;
+
+  /// Closes the connection; in-process brokers drop their messages.
+  async fn close(&self);
+}
+
+/// Connects to the `backend` selected by `BROKER`: `rabbitmq` (the default), `postgres`, which
+/// queues in the `tasks` table, `redis`, or `memory`, which only reaches consumers in the same
+/// process. The `postgres` and `redis` backends hand out leases of `queue_lease`. RabbitMQ
+/// declares `queues` up front; the others declare them when consuming or need no declaring.
+pub async fn connect(backend: &str, rabbitmq_url: &str, redis_url: &str, db_pool: &Pool<Postgres>, queue_lease: Duration, queues: &[String]) -> Result<Arc<dyn Broker>> {
+  match backend {
+    "rabbitmq" => Ok(Arc::new(RabbitBroker::new(RabbitConnection::connect(rabbitmq_url, queues).await?))),
+    "postgres" => Ok(Arc::new(PostgresBroker::connect(db_pool.clone(), queue_lease).await?)),
+    "redis" => Ok(Arc::new(RedisBroker::connect(redis_url, queue_lease).await?)),
+    "memory" => Ok(Arc::new(MemoryBroker::new())),
+    other => Err(anyhow!("unknown broker backend {}", other)),
+  }
+}
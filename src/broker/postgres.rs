@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use serde_json::Value;
+use sqlx::postgres::PgListener;
+use sqlx::{Pool, Postgres};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+use crate::topology::dead_letter_queue;
+use super::{Broker, Deliveries, Delivery, Message};
+
+/// `NOTIFY` channel announcing that a queue has a task ready; the payload is the queue name.
+const CHANNEL: &str = "dtqs_queue";
+
+/// Consumers also look for work this often, to pick up delayed tasks and expired leases.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Uses the `tasks` table itself as the queue. Publishing stamps a task row with its queue and
+/// message; consumers claim the most urgent visible row with `FOR UPDATE SKIP LOCKED` and hold a
+/// lease on it, renewed while the delivery is unsettled. A worker that dies stops renewing, and
+/// its tasks become visible to others once their leases expire.
+pub struct PostgresBroker {
+  inner: Arc<Inner>,
+  background: Vec<JoinHandle<()>>,
+}
+
+struct Inner {
+  db_pool: Pool<Postgres>,
+  lease: Duration,
+  state: Mutex<State>,
+  /// Woken by `NOTIFY`, settlements and cancellations.
+  changed: Notify,
+  listening: AtomicBool,
+}
+
+#[derive(Default)]
+struct State {
+  consumers: HashMap<String, Consumer>,
+  unsettled: HashMap<u64, Lease>,
+  next_tag: u64,
+}
+
+struct Consumer {
+  prefetch: usize,
+  in_flight: usize,
+}
+
+struct Lease {
+  consumer_tag: String,
+  task_id: Uuid,
+  lease_id: Uuid,
+}
+
+impl PostgresBroker {
+  /// Starts listening for `NOTIFY`s and renewing the leases of unsettled deliveries every third
+  /// of `lease`.
+  pub async fn connect(db_pool: Pool<Postgres>, lease: Duration) -> Result<Self> {
+    let mut listener = PgListener::connect_with(&db_pool).await?;
+    listener.listen(CHANNEL).await?;
+    let inner = Arc::new(Inner {
+      db_pool,
+      lease,
+      state: Mutex::new(State::default()),
+      changed: Notify::new(),
+      listening: AtomicBool::new(true),
+    });
+    let listen = {
+      let inner = inner.clone();
+      tokio::spawn(async move {
+        loop {
+          match listener.recv().await {
+            Ok(_) => {
+              inner.listening.store(true, Ordering::SeqCst);
+              inner.changed.notify_waiters();
+            }
+            // Consumers fall back to polling until the listener reconnects.
+            Err(e) => {
+              if inner.listening.swap(false, Ordering::SeqCst) {
+                warn!("Lost the {} listener connection: {:?}", CHANNEL, e);
+              }
+              tokio::time::sleep(POLL_INTERVAL).await;
+              // Reconnects and re-issues `LISTEN` rather than waiting for the next `NOTIFY`.
+              if sqlx::query!("SELECT 1 AS ok").fetch_one(&mut listener).await.is_ok() {
+                info!("Reconnected the {} listener", CHANNEL);
+                inner.listening.store(true, Ordering::SeqCst);
+                // Anything published while disconnected was never announced.
+                inner.changed.notify_waiters();
+              }
+            }
+          }
+        }
+      })
+    };
+    let renew = {
+      let inner = inner.clone();
+      tokio::spawn(async move {
+        let mut interval = tokio::time::interval(inner.lease / 3);
+        loop {
+          interval.tick().await;
+          if let Err(e) = inner.renew().await {
+            error!("Failed to renew task leases: {:?}", e);
+          }
+        }
+      })
+    };
+    info!("Using PostgreSQL as the queue with {:?} leases", lease);
+    Ok(Self { inner, background: vec![listen, renew] })
+  }
+
+  /// Forgets an unsettled delivery and returns the task and lease it holds.
+  fn settle(&self, delivery: &Delivery) -> Result<(Uuid, Uuid)> {
+    let mut state = self.inner.state.lock().unwrap();
+    let lease = state.unsettled
+      .remove(&delivery.tag)
+      .ok_or_else(|| anyhow!("delivery {} was already settled", delivery.tag))?;
+    if let Some(consumer) = state.consumers.get_mut(&lease.consumer_tag) {
+      consumer.in_flight -= 1;
+    }
+    self.inner.changed.notify_waiters();
+    Ok((lease.task_id, lease.lease_id))
+  }
+
+  async fn notify(&self, queue: &str) -> Result<()> {
+    sqlx::query!("SELECT pg_notify($1, $2)", CHANNEL, queue).execute(&self.inner.db_pool).await?;
+    Ok(())
+  }
+}
+
+impl Inner {
+  /// Claims the most urgent visible task in `queue` for `consumer_tag` if it has prefetch to
+  /// spare. `Err(None)` once the consumer is cancelled.
+  async fn claim(&self, queue: &str, consumer_tag: &str) -> Result<Option<Delivery>, Option<anyhow::Error>> {
+    {
+      let state = self.state.lock().unwrap();
+      let consumer = state.consumers.get(consumer_tag).ok_or(None)?;
+      if consumer.prefetch > 0 && consumer.in_flight >= consumer.prefetch {
+        return Ok(None);
+      }
+    }
+    let claimed = sqlx::query!(
+        r#"
+        UPDATE tasks SET lease_id = gen_random_uuid(), lease_expires_at = NOW() + make_interval(secs => $2)
+        WHERE id = (
+          SELECT id FROM tasks
+          WHERE queue = $1 AND visible_at <= NOW() AND (lease_expires_at IS NULL OR lease_expires_at < NOW())
+          ORDER BY priority, created_at
+          LIMIT 1
+          FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, lease_id AS "lease_id!", priority, queue_message AS "queue_message!", queue_headers
+        "#,
+        queue,
+        self.lease.as_secs_f64()
+      )
+      .fetch_optional(&self.db_pool)
+      .await
+      .map_err(|e| Some(e.into()))?;
+    let Some(row) = claimed else {
+      return Ok(None);
+    };
+    let mut state = self.state.lock().unwrap();
+    let Some(consumer) = state.consumers.get_mut(consumer_tag) else {
+      // Cancelled while claiming; the lease lapses and another consumer picks the task up.
+      return Err(None);
+    };
+    consumer.in_flight += 1;
+    state.next_tag += 1;
+    let tag = state.next_tag;
+    state.unsettled.insert(tag, Lease { consumer_tag: consumer_tag.to_string(), task_id: row.id, lease_id: row.lease_id });
+    let message = Message {
+      payload: serde_json::to_vec(&row.queue_message).map_err(|e| Some(e.into()))?,
+      priority: row.priority,
+      headers: row.queue_headers.and_then(|headers| serde_json::from_value(headers).ok()).unwrap_or_default(),
+    };
+    Ok(Some(Delivery { queue: queue.to_string(), tag, message }))
+  }
+
+  async fn renew(&self) -> Result<()> {
+    let lease_ids: Vec<Uuid> = self.state.lock().unwrap().unsettled.values().map(|lease| lease.lease_id).collect();
+    if lease_ids.is_empty() {
+      return Ok(());
+    }
+    sqlx::query!(
+        "UPDATE tasks SET lease_expires_at = NOW() + make_interval(secs => $2) WHERE lease_id = ANY($1)",
+        &lease_ids,
+        self.lease.as_secs_f64()
+      )
+      .execute(&self.db_pool)
+      .await?;
+    Ok(())
+  }
+}
+
+/// Fails when the lease was lost, i.e. it expired and another consumer claimed the task.
+fn settled(result: sqlx::postgres::PgQueryResult, task_id: Uuid) -> Result<()> {
+  if result.rows_affected() == 0 {
+    return Err(anyhow!("lease on task {} has expired", task_id));
+  }
+  Ok(())
+}
+
+#[async_trait]
+impl Broker for PostgresBroker {
+  fn name(&self) -> &'static str {
+    "postgres_queue"
+  }
+
+  fn status(&self) -> Result<(), String> {
+    if self.inner.listening.load(Ordering::SeqCst) {
+      Ok(())
+    } else {
+      Err(format!("not listening on {}", CHANNEL))
+    }
+  }
+
+  async fn ready(&self) {}
+
+  async fn declare_queue(&self, _queue: &str) -> Result<()> {
+    Ok(())
+  }
+
+  /// `message` must be a task message; its `task_id` names the row that becomes the queue entry.
+  async fn publish(&self, queue: &str, message: Message) -> Result<()> {
+    let queue_message: Value = serde_json::from_slice(&message.payload)?;
+    let task_id: Uuid = queue_message.get("task_id")
+      .and_then(Value::as_str)
+      .and_then(|id| id.parse().ok())
+      .context("message has no task_id")?;
+    let result = sqlx::query!(
+        "UPDATE tasks SET queue = $2, queue_message = $3, queue_headers = $4, visible_at = NOW(), lease_id = NULL, lease_expires_at = NULL
+         WHERE id = $1",
+        task_id,
+        queue,
+        queue_message,
+        serde_json::to_value(&message.headers)?
+      )
+      .execute(&self.inner.db_pool)
+      .await?;
+    if result.rows_affected() == 0 {
+      return Err(anyhow!("task {} does not exist", task_id));
+    }
+    self.notify(queue).await
+  }
+
+  async fn consume(&self, queue: &str, consumer_tag: &str, prefetch: u16) -> Result<Deliveries> {
+    {
+      let mut state = self.inner.state.lock().unwrap();
+      if state.consumers.contains_key(consumer_tag) {
+        return Err(anyhow!("consumer tag {} is already in use", consumer_tag));
+      }
+      state.consumers.insert(consumer_tag.to_string(), Consumer { prefetch: prefetch.into(), in_flight: 0 });
+    }
+    let consumer = (self.inner.clone(), queue.to_string(), consumer_tag.to_string());
+    let deliveries = futures::stream::unfold(consumer, |(inner, queue, consumer_tag)| async move {
+      loop {
+        let waker = inner.clone();
+        let changed = waker.changed.notified();
+        tokio::pin!(changed);
+        // Registered before claiming, so a NOTIFY in between is not missed.
+        changed.as_mut().enable();
+        match inner.claim(&queue, &consumer_tag).await {
+          Ok(Some(delivery)) => return Some((Ok(delivery), (inner, queue, consumer_tag))),
+          Ok(None) => {}
+          Err(None) => return None,
+          Err(Some(e)) => warn!("Failed to claim a task from {}: {:?}", queue, e),
+        }
+        tokio::select! {
+          _ = changed => {}
+          _ = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+      }
+    });
+    Ok(Box::pin(deliveries))
+  }
+
+  async fn cancel(&self, consumer_tag: &str) -> Result<()> {
+    self.inner.state.lock().unwrap()
+      .consumers
+      .remove(consumer_tag)
+      .ok_or_else(|| anyhow!("no consumer {}", consumer_tag))?;
+    self.inner.changed.notify_waiters();
+    Ok(())
+  }
+
+  async fn ack(&self, delivery: &Delivery) -> Result<()> {
+    let (task_id, lease_id) = self.settle(delivery)?;
+    let result = sqlx::query!(
+        "UPDATE tasks SET queue = NULL, queue_message = NULL, queue_headers = NULL, visible_at = NULL, lease_id = NULL, lease_expires_at = NULL
+         WHERE id = $1 AND lease_id = $2",
+        task_id,
+        lease_id
+      )
+      .execute(&self.inner.db_pool)
+      .await?;
+    settled(result, task_id)
+  }
+
+  async fn nack(&self, delivery: &Delivery, requeue: bool) -> Result<()> {
+    if !requeue {
+      return self.ack(delivery).await;
+    }
+    let (task_id, lease_id) = self.settle(delivery)?;
+    let result = sqlx::query!(
+        "UPDATE tasks SET lease_id = NULL, lease_expires_at = NULL WHERE id = $1 AND lease_id = $2",
+        task_id,
+        lease_id
+      )
+      .execute(&self.inner.db_pool)
+      .await?;
+    settled(result, task_id)?;
+    self.notify(&delivery.queue).await
+  }
+
+  async fn delay(&self, delivery: &Delivery, delay: Duration) -> Result<()> {
+    let (task_id, lease_id) = self.settle(delivery)?;
+    let result = sqlx::query!(
+        "UPDATE tasks SET visible_at = NOW() + make_interval(secs => $3), lease_id = NULL, lease_expires_at = NULL
+         WHERE id = $1 AND lease_id = $2",
+        task_id,
+        lease_id,
+        delay.as_secs_f64()
+      )
+      .execute(&self.inner.db_pool)
+      .await?;
+    settled(result, task_id)
+  }
+
+  /// Moves the task to the dead-letter queue, where it stays until retried through the API.
+  async fn dead_letter(&self, delivery: &Delivery) -> Result<()> {
+    let (task_id, lease_id) = self.settle(delivery)?;
+    let result = sqlx::query!(
+        "UPDATE tasks SET queue = $3, lease_id = NULL, lease_expires_at = NULL WHERE id = $1 AND lease_id = $2",
+        task_id,
+        lease_id,
+        dead_letter_queue(&delivery.queue)
+      )
+      .execute(&self.inner.db_pool)
+      .await?;
+    settled(result, task_id)
+  }
+
+  /// Stops listening and renewing; unsettled tasks become visible again when their leases expire.
+  async fn close(&self) {
+    for task in &self.background {
+      task.abort();
+    }
+  }
+}
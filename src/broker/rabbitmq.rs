@@ -0,0 +1,156 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::StreamExt;
+use lapin::acker::Acker;
+use lapin::options::{BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions, BasicQosOptions};
+use lapin::types::{AMQPValue, FieldTable};
+use lapin::BasicProperties;
+use tokio_retry::Retry;
+use tokio_retry::strategy::ExponentialBackoff;
+use crate::messaging::{RabbitConnection, DELAY, MAX_RETRIES};
+use crate::topology::{declare_dead_letter_queue, declare_delay_queue, message_properties, MAX_PRIORITY};
+use super::{Broker, Deliveries, Delivery, Message};
+
+/// RabbitMQ through a `RabbitConnection`. Delayed messages wait in a TTL queue per delay that
+/// dead-letters them back onto their queue, and dead letters go to a durable `<queue>.dead`.
+pub struct RabbitBroker {
+  rabbit: RabbitConnection,
+  next_tag: Arc<AtomicU64>,
+  /// Ackers of unsettled deliveries with their consumer tag. Delivery tags restart with every
+  /// channel, so deliveries get their own.
+  unsettled: Arc<Mutex<HashMap<u64, (String, Acker)>>>,
+}
+
+impl RabbitBroker {
+  pub fn new(rabbit: RabbitConnection) -> Self {
+    Self { rabbit, next_tag: Arc::new(AtomicU64::new(0)), unsettled: Arc::new(Mutex::new(HashMap::new())) }
+  }
+
+  fn acker(&self, delivery: &Delivery) -> Result<Acker> {
+    self.unsettled.lock().unwrap()
+      .remove(&delivery.tag)
+      .map(|(_, acker)| acker)
+      .ok_or_else(|| anyhow!("delivery {} was already settled", delivery.tag))
+  }
+
+  /// Publishes on the connection's current channel, retrying so a publish can outlast a quick
+  /// reconnect.
+  async fn send(&self, queue: &str, payload: &[u8], properties: BasicProperties) -> Result<()> {
+    Retry::start(ExponentialBackoff::from_millis(DELAY).take(MAX_RETRIES), || async {
+      self.rabbit.channel()?.basic_publish("", queue, BasicPublishOptions::default(), payload, properties.clone()).await?;
+      Ok::<_, anyhow::Error>(())
+    })
+      .await
+  }
+}
+
+#[async_trait]
+impl Broker for RabbitBroker {
+  fn name(&self) -> &'static str {
+    "rabbitmq"
+  }
+
+  fn status(&self) -> Result<(), String> {
+    self.rabbit.status()
+  }
+
+  async fn ready(&self) {
+    self.rabbit.wait_for_channel().await;
+  }
+
+  async fn declare_queue(&self, queue: &str) -> Result<()> {
+    self.rabbit.declare_queue(queue).await
+  }
+
+  async fn publish(&self, queue: &str, message: Message) -> Result<()> {
+    let properties = message_properties(message.priority).with_headers(field_table(&message.headers));
+    self.send(queue, &message.payload, properties).await
+  }
+
+  async fn consume(&self, queue: &str, consumer_tag: &str, prefetch: u16) -> Result<Deliveries> {
+    let channel = self.rabbit.channel()?;
+    channel.basic_qos(prefetch, BasicQosOptions::default()).await?;
+    let consumer = channel.basic_consume(queue, consumer_tag, BasicConsumeOptions::default(), FieldTable::default()).await?;
+    let queue = queue.to_string();
+    let consumer_tag = consumer_tag.to_string();
+    let unsettled = self.unsettled.clone();
+    let next_tag = self.next_tag.clone();
+    let deliveries = consumer.map(move |delivery| match delivery {
+      Ok(delivery) => {
+        let tag = next_tag.fetch_add(1, Ordering::SeqCst);
+        unsettled.lock().unwrap().insert(tag, (consumer_tag.clone(), delivery.acker));
+        let message = Message {
+          // Undoes `amqp_priority`.
+          priority: (MAX_PRIORITY - delivery.properties.priority().unwrap_or(0).min(MAX_PRIORITY)).into(),
+          headers: delivery.properties.headers().as_ref().map(string_headers).unwrap_or_default(),
+          payload: delivery.data,
+        };
+        Ok(Delivery { queue: queue.clone(), tag, message })
+      }
+      Err(e) => {
+        // The channel is gone and its deliveries with it; RabbitMQ redelivers them.
+        unsettled.lock().unwrap().retain(|_, (consumer, _)| *consumer != consumer_tag);
+        Err(e.into())
+      }
+    });
+    Ok(Box::pin(deliveries))
+  }
+
+  async fn cancel(&self, consumer_tag: &str) -> Result<()> {
+    self.rabbit.channel()?.basic_cancel(consumer_tag, BasicCancelOptions::default()).await?;
+    Ok(())
+  }
+
+  async fn ack(&self, delivery: &Delivery) -> Result<()> {
+    self.acker(delivery)?.ack(BasicAckOptions::default()).await?;
+    Ok(())
+  }
+
+  async fn nack(&self, delivery: &Delivery, requeue: bool) -> Result<()> {
+    self.acker(delivery)?.nack(BasicNackOptions { multiple: false, requeue }).await?;
+    Ok(())
+  }
+
+  async fn delay(&self, delivery: &Delivery, delay: Duration) -> Result<()> {
+    let holding = declare_delay_queue(&self.rabbit.channel()?, &delivery.queue, delay).await?;
+    self.publish(&holding, delivery.message.clone()).await?;
+    self.ack(delivery).await
+  }
+
+  async fn dead_letter(&self, delivery: &Delivery) -> Result<()> {
+    let dead = declare_dead_letter_queue(&self.rabbit.channel()?, &delivery.queue).await?;
+    self.publish(&dead, delivery.message.clone()).await?;
+    self.ack(delivery).await
+  }
+
+  async fn close(&self) {
+    self.rabbit.close().await;
+  }
+}
+
+fn field_table(headers: &HashMap<String, String>) -> FieldTable {
+  let headers: BTreeMap<_, _> = headers
+    .iter()
+    .map(|(key, value)| (key.as_str().into(), AMQPValue::LongString(value.as_str().into())))
+    .collect();
+  FieldTable::from(headers)
+}
+
+fn string_headers(headers: &FieldTable) -> HashMap<String, String> {
+  headers
+    .inner()
+    .iter()
+    .filter_map(|(key, value)| {
+      let value = match value {
+        AMQPValue::LongString(value) => std::str::from_utf8(value.as_bytes()).ok()?.to_string(),
+        AMQPValue::ShortString(value) => value.to_string(),
+        _ => return None,
+      };
+      Some((key.to_string(), value))
+    })
+    .collect()
+}
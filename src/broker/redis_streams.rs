@@ -0,0 +1,489 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use redis::aio::{ConnectionManager, MultiplexedConnection};
+use redis::streams::StreamPendingReply;
+use redis::{Client, RedisResult, Script};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+use crate::topology::{dead_letter_queue, MAX_PRIORITY};
+use super::{Broker, Deliveries, Delivery, Message};
+
+/// Consumer group read by every worker.
+const GROUP: &str = "dtqs";
+
+/// Longest a consumer blocks waiting for new entries before it looks for due retries and expired
+/// leases again.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Moves due retries onto their streams, then hands out one entry, most urgent stream first: an
+/// entry whose consumer stopped renewing it, otherwise a new one.
+/// KEYS: delayed set, streams by level. ARGV: group, consumer, now (ms), lease (ms).
+const CLAIM: &str = r#"
+for _, member in ipairs(redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[3], 'LIMIT', 0, 100)) do
+  -- <level>\n<id>\n<priority>\n<headers>\n<payload>
+  local fields, start = {}, 1
+  for i = 1, 4 do
+    local stop = string.find(member, '\n', start, true)
+    fields[i] = string.sub(member, start, stop - 1)
+    start = stop + 1
+  end
+  redis.call('XADD', KEYS[2 + tonumber(fields[1])], '*', 'payload', string.sub(member, start), 'priority', fields[3], 'headers', fields[4])
+  redis.call('ZREM', KEYS[1], member)
+end
+for i = 2, #KEYS do
+  -- Each XAUTOCLAIM scans only part of the pending list, so follow its cursor to the end.
+  local cursor, entry = '0-0', nil
+  repeat
+    local claimed = redis.call('XAUTOCLAIM', KEYS[i], ARGV[1], ARGV[2], ARGV[4], cursor, 'COUNT', 1)
+    cursor, entry = claimed[1], claimed[2][1]
+    if not (entry and entry[2]) then
+      entry = nil
+    end
+  until entry or cursor == '0-0'
+  if not entry then
+    local read = redis.call('XREADGROUP', 'GROUP', ARGV[1], ARGV[2], 'COUNT', 1, 'STREAMS', KEYS[i], '>')
+    entry = read and read[1][2][1]
+  end
+  if entry then
+    return {i - 2, entry[1], entry[2]}
+  end
+end
+return false
+"#;
+
+/// Settles an entry if the consumer still owns it. Returns 0 if another consumer reclaimed it.
+/// KEYS: stream, destination. ARGV: group, consumer, id, action, action arguments.
+const SETTLE: &str = r#"
+if #redis.call('XPENDING', KEYS[1], ARGV[1], ARGV[3], ARGV[3], 1, ARGV[2]) == 0 then
+  return 0
+end
+if ARGV[4] == 'requeue' then
+  -- Idle for a full lease, so the next claim reclaims it ahead of newer entries.
+  redis.call('XCLAIM', KEYS[1], ARGV[1], ARGV[2], 0, ARGV[3], 'IDLE', ARGV[5], 'JUSTID')
+  return 1
+end
+if ARGV[4] == 'delay' then
+  redis.call('ZADD', KEYS[2], ARGV[5], ARGV[6])
+elseif ARGV[4] == 'dead_letter' then
+  redis.call('XADD', KEYS[2], '*', 'payload', ARGV[5], 'priority', ARGV[6], 'headers', ARGV[7])
+end
+redis.call('XACK', KEYS[1], ARGV[1], ARGV[3])
+redis.call('XDEL', KEYS[1], ARGV[3])
+return 1
+"#;
+
+/// Resets the idle time of the entries the consumer still owns.
+/// KEYS: stream. ARGV: group, consumer, ids.
+const RENEW: &str = r#"
+for i = 3, #ARGV do
+  if #redis.call('XPENDING', KEYS[1], ARGV[1], ARGV[i], ARGV[i], 1, ARGV[2]) > 0 then
+    redis.call('XCLAIM', KEYS[1], ARGV[1], ARGV[2], 0, ARGV[i], 'JUSTID')
+  end
+end
+"#;
+
+/// Redis Streams with one consumer group. A queue is a stream per priority level (`<queue>.p0` to
+/// `<queue>.p10`), read most urgent first, and retries wait in the `<queue>.delayed` sorted set
+/// until due. Unsettled entries stay pending in the group with their idle time reset while the
+/// task runs; once one has been idle for a lease, any consumer reclaims it with `XAUTOCLAIM`.
+pub struct RedisBroker {
+  inner: Arc<Inner>,
+  renew: JoinHandle<()>,
+}
+
+struct Inner {
+  client: Client,
+  conn: ConnectionManager,
+  lease: Duration,
+  claim: Script,
+  settle: Script,
+  renew: Script,
+  state: Mutex<State>,
+  /// Woken by settlements and cancellations.
+  changed: Notify,
+  /// Whether the last command reached Redis.
+  connected: AtomicBool,
+  /// Queues whose consumer groups exist, so publishing does not recreate them every time.
+  declared: Mutex<HashSet<String>>,
+}
+
+#[derive(Default)]
+struct State {
+  consumers: HashMap<String, Consumer>,
+  unsettled: HashMap<u64, Pending>,
+  next_tag: u64,
+}
+
+struct Consumer {
+  prefetch: usize,
+  in_flight: usize,
+}
+
+struct Pending {
+  consumer_tag: String,
+  stream: String,
+  id: String,
+}
+
+fn stream(queue: &str, level: u8) -> String {
+  format!("{}.p{}", queue, level)
+}
+
+fn streams(queue: &str) -> Vec<String> {
+  (0..=MAX_PRIORITY).map(|level| stream(queue, level)).collect()
+}
+
+fn delayed_set(queue: &str) -> String {
+  format!("{}.delayed", queue)
+}
+
+/// Same levels as `amqp_priority`, most urgent first.
+fn level(priority: i32) -> u8 {
+  priority.clamp(0, MAX_PRIORITY.into()) as u8
+}
+
+fn now_millis() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// A queue's entries across its streams, split into those waiting for a consumer and those
+/// delivered but not settled yet. Retries still in the delayed set are not counted.
+pub struct StreamDepth {
+  pub waiting: u64,
+  pub pending: u64,
+}
+
+/// Reads `queue`'s depth without joining the consumer group, for operator views such as
+/// `dtqs_cli queue-stats`.
+pub async fn stream_depth(conn: &mut MultiplexedConnection, queue: &str) -> Result<StreamDepth> {
+  let mut depth = StreamDepth { waiting: 0, pending: 0 };
+  for stream in streams(queue) {
+    let entries: u64 = redis::cmd("XLEN").arg(&stream).query_async(conn).await?;
+    if entries == 0 {
+      continue;
+    }
+    let pending = match redis::cmd("XPENDING").arg(&stream).arg(GROUP).query_async::<StreamPendingReply>(conn).await {
+      Ok(pending) => pending.count() as u64,
+      // Nothing has consumed the queue yet.
+      Err(e) if e.code() == Some("NOGROUP") => 0,
+      Err(e) => return Err(e.into()),
+    };
+    depth.waiting += entries.saturating_sub(pending);
+    depth.pending += pending;
+  }
+  Ok(depth)
+}
+
+impl RedisBroker {
+  /// Connects to `redis_url` and starts renewing unsettled entries every third of `lease`.
+  pub async fn connect(redis_url: &str, lease: Duration) -> Result<Self> {
+    let client = Client::open(redis_url)?;
+    let conn = ConnectionManager::new(client.clone()).await?;
+    let inner = Arc::new(Inner {
+      client,
+      conn,
+      lease,
+      claim: Script::new(CLAIM),
+      settle: Script::new(SETTLE),
+      renew: Script::new(RENEW),
+      state: Mutex::new(State::default()),
+      changed: Notify::new(),
+      connected: AtomicBool::new(true),
+      declared: Mutex::new(HashSet::new()),
+    });
+    let renew = {
+      let inner = inner.clone();
+      tokio::spawn(async move {
+        let mut interval = tokio::time::interval(inner.lease / 3);
+        loop {
+          interval.tick().await;
+          if let Err(e) = inner.renew().await {
+            error!("Failed to renew pending entries: {:?}", e);
+          }
+        }
+      })
+    };
+    info!("Using Redis Streams with {:?} leases", lease);
+    Ok(Self { inner, renew })
+  }
+
+  /// Forgets an unsettled delivery, then runs `action` on its entry through the settle script.
+  /// `destination` defaults to the entry's own stream.
+  async fn settle(&self, delivery: &Delivery, destination: Option<String>, action: &str, args: &[&[u8]]) -> Result<()> {
+    let pending = {
+      let mut state = self.inner.state.lock().unwrap();
+      let pending = state.unsettled
+        .remove(&delivery.tag)
+        .ok_or_else(|| anyhow!("delivery {} was already settled", delivery.tag))?;
+      if let Some(consumer) = state.consumers.get_mut(&pending.consumer_tag) {
+        consumer.in_flight -= 1;
+      }
+      pending
+    };
+    self.inner.changed.notify_waiters();
+    let mut invocation = self.inner.settle.prepare_invoke();
+    invocation.key(&pending.stream).key(destination.as_ref().unwrap_or(&pending.stream)).arg(GROUP).arg(&pending.consumer_tag).arg(&pending.id).arg(action);
+    for arg in args {
+      invocation.arg(*arg);
+    }
+    let settled: i64 = self.inner.track(invocation.invoke_async(&mut self.inner.conn.clone()).await)?;
+    if settled == 0 {
+      return Err(anyhow!("lease on entry {} of {} has expired", pending.id, pending.stream));
+    }
+    Ok(())
+  }
+}
+
+impl Inner {
+  /// Claims the next entry of `queue` for `consumer_tag` if it has prefetch to spare. `Err(None)`
+  /// once the consumer is cancelled.
+  async fn claim(&self, queue: &str, consumer_tag: &str) -> Result<Option<Delivery>, Option<anyhow::Error>> {
+    {
+      let state = self.state.lock().unwrap();
+      let consumer = state.consumers.get(consumer_tag).ok_or(None)?;
+      if consumer.prefetch > 0 && consumer.in_flight >= consumer.prefetch {
+        return Ok(None);
+      }
+    }
+    let mut invocation = self.claim.prepare_invoke();
+    invocation.key(delayed_set(queue));
+    for stream in streams(queue) {
+      invocation.key(stream);
+    }
+    invocation.arg(GROUP).arg(consumer_tag).arg(now_millis()).arg(self.lease.as_millis() as u64);
+    let claimed: Option<(u8, String, HashMap<String, Vec<u8>>)> = self
+      .track(invocation.invoke_async(&mut self.conn.clone()).await)
+      .map_err(|e| Some(e.into()))?;
+    let Some((level, id, mut fields)) = claimed else {
+      return Ok(None);
+    };
+    let message = Message {
+      payload: fields.remove("payload").unwrap_or_default(),
+      priority: fields.get("priority")
+        .and_then(|priority| std::str::from_utf8(priority).ok()?.parse().ok())
+        .unwrap_or(level.into()),
+      headers: fields.get("headers")
+        .and_then(|headers| serde_json::from_slice(headers).ok())
+        .unwrap_or_default(),
+    };
+    let mut state = self.state.lock().unwrap();
+    let Some(consumer) = state.consumers.get_mut(consumer_tag) else {
+      // Cancelled while claiming; the entry is reclaimed once its lease runs out.
+      return Err(None);
+    };
+    consumer.in_flight += 1;
+    state.next_tag += 1;
+    let tag = state.next_tag;
+    state.unsettled.insert(tag, Pending { consumer_tag: consumer_tag.to_string(), stream: stream(queue, level), id });
+    Ok(Some(Delivery { queue: queue.to_string(), tag, message }))
+  }
+
+  /// Creates the consumer group on every stream of `queue`, reading from the start so entries
+  /// published before the group existed are not skipped.
+  async fn create_groups(&self, queue: &str) -> Result<()> {
+    for stream in streams(queue) {
+      let created = redis::cmd("XGROUP")
+        .arg("CREATE")
+        .arg(&stream)
+        .arg(GROUP)
+        .arg("0")
+        .arg("MKSTREAM")
+        .query_async::<()>(&mut self.conn.clone())
+        .await;
+      match self.track(created) {
+        Err(e) if e.code() != Some("BUSYGROUP") => return Err(e.into()),
+        _ => {}
+      }
+    }
+    self.declared.lock().unwrap().insert(queue.to_string());
+    Ok(())
+  }
+
+  /// Records whether a command reached Redis for `status`, logging when that changes. Every
+  /// command goes through here; errors Redis itself replied with count as connected.
+  fn track<T>(&self, result: RedisResult<T>) -> RedisResult<T> {
+    let lost = match &result {
+      Err(e) if e.is_io_error() || e.is_connection_dropped() => Some(e),
+      _ => None,
+    };
+    if self.connected.swap(lost.is_none(), Ordering::SeqCst) != lost.is_none() {
+      match lost {
+        Some(e) => warn!("Lost the Redis connection: {:?}", e),
+        None => info!("Reconnected to Redis"),
+      }
+    }
+    result
+  }
+
+  async fn renew(&self) -> Result<()> {
+    let mut owned: HashMap<(String, String), Vec<String>> = HashMap::new();
+    for pending in self.state.lock().unwrap().unsettled.values() {
+      owned.entry((pending.stream.clone(), pending.consumer_tag.clone())).or_default().push(pending.id.clone());
+    }
+    for ((stream, consumer_tag), ids) in owned {
+      self.track(self.renew.key(stream).arg(GROUP).arg(consumer_tag).arg(ids).invoke_async::<()>(&mut self.conn.clone()).await)?;
+    }
+    Ok(())
+  }
+
+  /// Blocks until an entry is added to one of `streams` or `POLL_INTERVAL` passes.
+  async fn wait(&self, conn: &mut ConnectionManager, streams: &[String]) {
+    let waited = redis::cmd("XREAD")
+      .arg("BLOCK")
+      .arg(POLL_INTERVAL.as_millis() as u64)
+      .arg("STREAMS")
+      .arg(streams)
+      .arg(vec!["$"; streams.len()])
+      .query_async::<()>(conn)
+      .await;
+    if let Err(e) = self.track(waited) {
+      // `track` already reported a lost connection.
+      if self.connected.load(Ordering::SeqCst) {
+        warn!("Failed to wait for new entries: {:?}", e);
+      }
+      tokio::time::sleep(POLL_INTERVAL).await;
+    }
+  }
+}
+
+#[async_trait]
+impl Broker for RedisBroker {
+  fn name(&self) -> &'static str {
+    "redis"
+  }
+
+  fn status(&self) -> Result<(), String> {
+    if self.inner.connected.load(Ordering::SeqCst) {
+      Ok(())
+    } else {
+      Err("reconnecting".into())
+    }
+  }
+
+  async fn ready(&self) {}
+
+  /// Creates the consumer groups of `queue` the first time it is declared.
+  async fn declare_queue(&self, queue: &str) -> Result<()> {
+    if self.inner.declared.lock().unwrap().contains(queue) {
+      return Ok(());
+    }
+    self.inner.create_groups(queue).await
+  }
+
+  async fn publish(&self, queue: &str, message: Message) -> Result<()> {
+    let added = redis::cmd("XADD")
+      .arg(stream(queue, level(message.priority)))
+      .arg("*")
+      .arg("payload")
+      .arg(&message.payload)
+      .arg("priority")
+      .arg(message.priority)
+      .arg("headers")
+      .arg(serde_json::to_string(&message.headers)?)
+      .query_async::<()>(&mut self.inner.conn.clone())
+      .await;
+    self.inner.track(added)?;
+    Ok(())
+  }
+
+  async fn consume(&self, queue: &str, consumer_tag: &str, prefetch: u16) -> Result<Deliveries> {
+    self.inner.create_groups(queue).await?;
+    // Blocking reads get a connection of their own so they do not hold up other commands.
+    let waiting = self.inner.track(ConnectionManager::new(self.inner.client.clone()).await)?;
+    {
+      let mut state = self.inner.state.lock().unwrap();
+      if state.consumers.contains_key(consumer_tag) {
+        return Err(anyhow!("consumer tag {} is already in use", consumer_tag));
+      }
+      state.consumers.insert(consumer_tag.to_string(), Consumer { prefetch: prefetch.into(), in_flight: 0 });
+    }
+    let consumer = (self.inner.clone(), queue.to_string(), consumer_tag.to_string(), waiting);
+    let deliveries = futures::stream::unfold(consumer, |(inner, queue, consumer_tag, mut waiting)| async move {
+      let streams = streams(&queue);
+      loop {
+        let waker = inner.clone();
+        let changed = waker.changed.notified();
+        tokio::pin!(changed);
+        changed.as_mut().enable();
+        match inner.claim(&queue, &consumer_tag).await {
+          Ok(Some(delivery)) => return Some((Ok(delivery), (inner, queue, consumer_tag, waiting))),
+          Ok(None) => {}
+          Err(None) => return None,
+          Err(Some(e)) => {
+            if inner.connected.load(Ordering::SeqCst) {
+              warn!("Failed to claim an entry from {}: {:?}", queue, e);
+            }
+            // Redis lost its data, so every cached declaration is stale. Before Redis 7 script
+            // errors only carry the code in their message.
+            if e.to_string().contains("NOGROUP") {
+              inner.declared.lock().unwrap().clear();
+              if let Err(e) = inner.create_groups(&queue).await {
+                warn!("Failed to recreate the consumer groups of {}: {:?}", queue, e);
+              }
+            }
+          }
+        }
+        tokio::select! {
+          _ = changed => {}
+          _ = inner.wait(&mut waiting, &streams) => {}
+        }
+      }
+    });
+    Ok(Box::pin(deliveries))
+  }
+
+  async fn cancel(&self, consumer_tag: &str) -> Result<()> {
+    self.inner.state.lock().unwrap()
+      .consumers
+      .remove(consumer_tag)
+      .ok_or_else(|| anyhow!("no consumer {}", consumer_tag))?;
+    self.inner.changed.notify_waiters();
+    Ok(())
+  }
+
+  async fn ack(&self, delivery: &Delivery) -> Result<()> {
+    self.settle(delivery, None, "ack", &[]).await
+  }
+
+  async fn nack(&self, delivery: &Delivery, requeue: bool) -> Result<()> {
+    if !requeue {
+      return self.ack(delivery).await;
+    }
+    let idle = (self.inner.lease.as_millis() as u64).to_string();
+    self.settle(delivery, None, "requeue", &[idle.as_bytes()]).await
+  }
+
+  async fn delay(&self, delivery: &Delivery, delay: Duration) -> Result<()> {
+    let message = &delivery.message;
+    let mut member = format!(
+      "{}\n{}\n{}\n{}\n",
+      level(message.priority),
+      Uuid::new_v4(),
+      message.priority,
+      serde_json::to_string(&message.headers)?
+    ).into_bytes();
+    member.extend_from_slice(&message.payload);
+    let due = (now_millis() + delay.as_millis() as u64).to_string();
+    self.settle(delivery, Some(delayed_set(&delivery.queue)), "delay", &[due.as_bytes(), &member]).await
+  }
+
+  /// Adds the entry to the dead-letter queue's streams, which nothing consumes.
+  async fn dead_letter(&self, delivery: &Delivery) -> Result<()> {
+    let message = &delivery.message;
+    let dead = stream(&dead_letter_queue(&delivery.queue), level(message.priority));
+    let priority = message.priority.to_string();
+    let headers = serde_json::to_string(&message.headers)?;
+    self.settle(delivery, Some(dead), "dead_letter", &[&message.payload, priority.as_bytes(), headers.as_bytes()]).await
+  }
+
+  /// Stops renewing; unsettled entries are reclaimed by other consumers once their leases expire.
+  async fn close(&self) {
+    self.renew.abort();
+  }
+}
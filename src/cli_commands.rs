@@ -0,0 +1,386 @@
+//! Non-interactive `dtqs_cli` subcommands. Task commands go through the API with `dtqs_client`, so
+//! they get the same validation, quotas, tenant scoping and retries as any other client; operator
+//! views (`workers`, `tail-logs`, `queue-stats`) read PostgreSQL and the broker directly, like the
+//! dashboard.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::Duration;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
+use dtqs_client::Client;
+use lapin::Channel;
+use redis::aio::MultiplexedConnection;
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+use crate::config::Config;
+use crate::database::setup_database;
+use crate::broker::redis_streams::stream_depth;
+use crate::messaging::create_rabbit_channel;
+use crate::task_types;
+use crate::topology::inspect_queue;
+use crate::models::Task;
+use crate::routes::tasks::{NewTask, TaskListQuery};
+
+#[derive(Parser)]
+#[command(name = "dtqs_cli", about = "DTQS dashboard and scripting commands")]
+pub struct Cli {
+  /// `KEY=VALUE` file with the same keys as the environment; variables already set in the
+  /// environment override it.
+  #[arg(long, global = true)]
+  pub config: Option<PathBuf>,
+  #[arg(long, short, global = true, value_enum, default_value_t = OutputFormat::Table)]
+  pub output: OutputFormat,
+  #[command(subcommand)]
+  pub command: Option<Command>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+  Table,
+  Json,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+  /// Full-screen dashboard (the default).
+  Dashboard,
+  /// Submit a task.
+  Submit {
+    #[arg(long = "type")]
+    task_type: String,
+    /// JSON payload, `@path` to read it from a file, or `-` for stdin.
+    #[arg(long)]
+    payload: String,
+    #[arg(long)]
+    priority: Option<u8>,
+    #[arg(long)]
+    schema_version: Option<i32>,
+  },
+  /// Show one task.
+  Get { task_id: Uuid },
+  /// List tasks, newest first.
+  List {
+    #[arg(long)]
+    status: Option<String>,
+    #[arg(long = "type")]
+    task_type: Option<String>,
+    #[arg(long, default_value_t = 50)]
+    limit: i64,
+    #[arg(long, default_value_t = 0)]
+    offset: i64,
+  },
+  /// Cancel a pending or in-progress task.
+  Cancel { task_id: Uuid },
+  /// Requeue a failed or cancelled task.
+  Retry { task_id: Uuid },
+  /// Print recent worker logs; JSON output is one object per line.
+  TailLogs {
+    #[arg(long, default_value_t = 20)]
+    limit: i64,
+    #[arg(long)]
+    worker: Option<String>,
+    /// Keep polling for new entries.
+    #[arg(long, short)]
+    follow: bool,
+  },
+  /// List worker nodes.
+  Workers,
+  /// Show queue depth and task counts by status.
+  QueueStats,
+}
+
+#[derive(Serialize)]
+struct WorkerRow {
+  node_id: String,
+  status: String,
+  last_health_check: DateTime<Utc>,
+  current_task_id: Option<Uuid>,
+  task_types: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct LogRow {
+  id: i32,
+  created_at: DateTime<Utc>,
+  worker_node_id: Option<String>,
+  message: String,
+}
+
+#[derive(Serialize)]
+pub struct QueueDepth {
+  pub queue: String,
+  /// Waiting for a consumer; with `postgres` this includes tasks being processed.
+  pub messages: i64,
+  /// Delivered but not settled yet; only reported by `redis`.
+  pub unacked: Option<i64>,
+  /// Only reported by `rabbitmq`.
+  pub consumers: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct QueueStats {
+  queues: Vec<QueueDepth>,
+  tasks: BTreeMap<String, i64>,
+}
+
+pub async fn run(command: Command, config: &Config, output: OutputFormat) -> Result<()> {
+  match command {
+    Command::Dashboard => unreachable!("the dashboard is run by dtqs_cli itself"),
+    Command::Submit { task_type, payload, priority, schema_version } => {
+      let new_task = NewTask { task_type, payload: read_payload(&payload)?, priority, schema_version };
+      let response = client(config)?.submit(&new_task).await?;
+      match output {
+        OutputFormat::Json => print_json(&response),
+        OutputFormat::Table => print_table(&["TASK ID", "STATUS"], vec![vec![response.task_id.to_string(), response.status]]),
+      }
+    }
+    Command::Get { task_id } => {
+      let task = client(config)?.get(task_id).await?;
+      print_tasks(output, vec![task])
+    }
+    Command::List { status, task_type, limit, offset } => {
+      let query = TaskListQuery { status, task_type, limit: Some(limit), offset: Some(offset) };
+      let tasks = client(config)?.list(&query).await?;
+      print_tasks(output, tasks)
+    }
+    Command::Cancel { task_id } => {
+      let task = client(config)?.cancel(task_id).await?;
+      print_tasks(output, vec![task])
+    }
+    Command::Retry { task_id } => {
+      let task = client(config)?.retry(task_id).await?;
+      print_tasks(output, vec![task])
+    }
+    Command::TailLogs { limit, worker, follow } => tail_logs(config, output, limit, worker, follow).await,
+    Command::Workers => {
+      let db_pool = setup_database(&config.database_url).await;
+      let workers = sqlx::query_as!(
+          WorkerRow,
+          "SELECT node_id, status, last_health_check, current_task_id, task_types FROM worker_nodes
+           WHERE tenant_id = $1 OR tenant_id IS NULL
+           ORDER BY last_health_check DESC",
+          config.tenant_id
+        )
+        .fetch_all(&db_pool)
+        .await?;
+      match output {
+        OutputFormat::Json => print_json(&workers),
+        OutputFormat::Table => print_table(
+          &["NODE", "STATUS", "TASK TYPES", "LAST HEALTH CHECK", "CURRENT TASK"],
+          workers.into_iter().map(|w| vec![
+            w.node_id,
+            w.status,
+            w.task_types.map(|types| types.join(",")).unwrap_or_default(),
+            w.last_health_check.format("%Y-%m-%d %H:%M:%S").to_string(),
+            w.current_task_id.map(|id| id.to_string()).unwrap_or_default(),
+          ]).collect(),
+        ),
+      }
+    }
+    Command::QueueStats => {
+      let db_pool = setup_database(&config.database_url).await;
+      let queues = config.routing().queues(&config.tenant_id, &task_types::names(&db_pool).await?);
+      let depths = DepthSource::open(config, &db_pool).await?.depths(queues).await?;
+      let counts = sqlx::query!("SELECT status, COUNT(*) AS count FROM tasks WHERE tenant_id = $1 GROUP BY status", config.tenant_id)
+        .fetch_all(&db_pool)
+        .await?;
+      let stats = QueueStats {
+        queues: depths,
+        tasks: counts.into_iter().map(|row| (row.status, row.count.unwrap_or(0))).collect(),
+      };
+      match output {
+        OutputFormat::Json => print_json(&stats),
+        OutputFormat::Table => {
+          let mut rows = Vec::new();
+          for depth in stats.queues {
+            rows.push(vec![format!("{} messages", depth.queue), depth.messages.to_string()]);
+            if let Some(unacked) = depth.unacked {
+              rows.push(vec![format!("{} unacked", depth.queue), unacked.to_string()]);
+            }
+            if let Some(consumers) = depth.consumers {
+              rows.push(vec![format!("{} consumers", depth.queue), consumers.to_string()]);
+            }
+          }
+          rows.extend(stats.tasks.into_iter().map(|(status, count)| vec![format!("tasks {}", status), count.to_string()]));
+          print_table(&["METRIC", "VALUE"], rows)
+        }
+      }
+    }
+  }
+}
+
+async fn tail_logs(config: &Config, output: OutputFormat, limit: i64, worker: Option<String>, follow: bool) -> Result<()> {
+  let db_pool = setup_database(&config.database_url).await;
+  let mut rows = sqlx::query_as!(
+      LogRow,
+      "SELECT id, created_at, worker_node_id, message FROM logs
+       WHERE tenant_id = $1 AND ($2::VARCHAR IS NULL OR worker_node_id = $2)
+       ORDER BY id DESC LIMIT $3",
+      config.tenant_id,
+      worker,
+      limit
+    )
+    .fetch_all(&db_pool)
+    .await?;
+  rows.reverse();
+  let mut last_id = 0;
+  loop {
+    for row in &rows {
+      last_id = row.id;
+      match output {
+        OutputFormat::Json => println!("{}", serde_json::to_string(row)?),
+        OutputFormat::Table => println!(
+          "{}  {:<16}  {}",
+          row.created_at.format("%Y-%m-%d %H:%M:%S"),
+          row.worker_node_id.as_deref().unwrap_or("-"),
+          row.message
+        ),
+      }
+    }
+    if !follow {
+      return Ok(());
+    }
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    rows = sqlx::query_as!(
+        LogRow,
+        "SELECT id, created_at, worker_node_id, message FROM logs
+         WHERE tenant_id = $1 AND ($2::VARCHAR IS NULL OR worker_node_id = $2) AND id > $3
+         ORDER BY id",
+        config.tenant_id,
+        worker,
+        last_id
+      )
+      .fetch_all(&db_pool)
+      .await?;
+  }
+}
+
+/// Where queue depths are read from, opened once so the dashboard can keep polling it.
+pub enum DepthSource {
+  Rabbit { url: String, channel: Channel },
+  Postgres(Pool<Postgres>),
+  Redis(MultiplexedConnection),
+}
+
+impl DepthSource {
+  /// Connects to the backend `config.broker` selects.
+  pub async fn open(config: &Config, db_pool: &Pool<Postgres>) -> Result<Self> {
+    match config.broker.as_str() {
+      "rabbitmq" => Ok(DepthSource::Rabbit { url: config.rabbitmq_url.clone(), channel: create_rabbit_channel(&config.rabbitmq_url).await? }),
+      "postgres" => Ok(DepthSource::Postgres(db_pool.clone())),
+      "redis" => Ok(DepthSource::Redis(redis::Client::open(config.redis_url.as_str())?.get_multiplexed_async_connection().await?)),
+      other => Err(anyhow!("queue depths cannot be read from BROKER={}", other)),
+    }
+  }
+
+  pub async fn depths(&mut self, queues: Vec<String>) -> Result<Vec<QueueDepth>> {
+    match self {
+      DepthSource::Rabbit { url, channel } => rabbit_depths(url, channel, queues).await,
+      DepthSource::Postgres(db_pool) => postgres_depths(db_pool, queues).await,
+      DepthSource::Redis(conn) => {
+        let mut depths = Vec::new();
+        for queue in queues {
+          let depth = stream_depth(conn, &queue).await?;
+          depths.push(QueueDepth { queue, messages: depth.waiting as i64, unacked: Some(depth.pending as i64), consumers: None });
+        }
+        Ok(depths)
+      }
+    }
+  }
+}
+
+/// Inspects the queues passively, so reading them never creates them. Queues nothing has been
+/// routed to yet do not exist and are left out.
+async fn rabbit_depths(url: &str, channel: &mut Channel, queues: Vec<String>) -> Result<Vec<QueueDepth>> {
+  let mut depths = Vec::new();
+  for queue in queues {
+    match inspect_queue(channel, &queue).await {
+      Ok(declared) => depths.push(QueueDepth { queue, messages: declared.message_count().into(), unacked: None, consumers: Some(declared.consumer_count()) }),
+      // RabbitMQ closes the channel after a failed passive declare.
+      Err(e) if matches!(e.downcast_ref::<lapin::Error>(), Some(lapin::Error::ProtocolError(e)) if e.get_id() == 404) => {
+        *channel = create_rabbit_channel(url).await?;
+      }
+      Err(e) => return Err(e.context(format!("failed to inspect queue {}", queue))),
+    }
+  }
+  Ok(depths)
+}
+
+/// The `postgres` backend queues tasks in their own rows, stamped with the queue until settled.
+async fn postgres_depths(db_pool: &Pool<Postgres>, queues: Vec<String>) -> Result<Vec<QueueDepth>> {
+  let counts = sqlx::query!(
+      r#"SELECT queue AS "queue!", COUNT(*) AS "count!" FROM tasks WHERE queue = ANY($1) GROUP BY queue"#,
+      &queues
+    )
+    .fetch_all(db_pool)
+    .await?;
+  let counts: BTreeMap<String, i64> = counts.into_iter().map(|row| (row.queue, row.count)).collect();
+  Ok(queues.into_iter().map(|queue| QueueDepth { messages: counts.get(&queue).copied().unwrap_or(0), queue, unacked: None, consumers: None }).collect())
+}
+
+fn client(config: &Config) -> Result<Client> {
+  let mut builder = Client::builder(&config.api_url);
+  if let Some(token) = &config.api_token {
+    builder = builder.token(token);
+  }
+  Ok(builder.build()?)
+}
+
+fn read_payload(arg: &str) -> Result<serde_json::Value> {
+  let text = if arg == "-" {
+    let mut text = String::new();
+    std::io::stdin().read_to_string(&mut text)?;
+    text
+  } else if let Some(path) = arg.strip_prefix('@') {
+    std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path))?
+  } else {
+    arg.to_string()
+  };
+  serde_json::from_str(&text).context("payload is not valid JSON")
+}
+
+fn print_tasks(output: OutputFormat, tasks: Vec<Task>) -> Result<()> {
+  match output {
+    OutputFormat::Json if tasks.len() == 1 => print_json(&tasks[0]),
+    OutputFormat::Json => print_json(&tasks),
+    OutputFormat::Table => print_table(
+      &["ID", "TYPE", "STATUS", "PRIORITY", "PROGRESS", "ATTEMPTS", "CREATED"],
+      tasks.into_iter().map(|t| vec![
+        t.id.to_string(),
+        t.task_type,
+        t.status,
+        t.priority.to_string(),
+        format!("{}%", t.progress),
+        t.attempts.to_string(),
+        t.created_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+      ]).collect(),
+    ),
+  }
+}
+
+fn print_json<T: Serialize + ?Sized>(value: &T) -> Result<()> {
+  println!("{}", serde_json::to_string_pretty(value)?);
+  Ok(())
+}
+
+fn print_table(headers: &[&str], rows: Vec<Vec<String>>) -> Result<()> {
+  let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+  for row in &rows {
+    for (width, cell) in widths.iter_mut().zip(row) {
+      *width = (*width).max(cell.chars().count());
+    }
+  }
+  let line = |cells: Vec<&str>| {
+    let padded: Vec<String> = cells.iter().zip(&widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+    println!("{}", padded.join("  ").trim_end());
+  };
+  line(headers.to_vec());
+  for row in &rows {
+    line(row.iter().map(String::as_str).collect());
+  }
+  Ok(())
+}
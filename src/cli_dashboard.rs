@@ -1,6 +1,9 @@
 //! Overview (worker nodes and active tasks)
 //! Queue (next 5 pending tasks)
 //! Logs (latest log entries)
+//!
+//! With a subcommand (`dtqs_cli list`, `dtqs_cli cancel <id>`, ...) it runs that command and
+//! exits instead; see `cli_commands`.
 
 use std::{
   error::Error,
@@ -24,11 +27,11 @@ use tui::{
   Terminal,
 };
 use sqlx::{Pool, Postgres};
-use crate::{config::Config, database::setup_database};
-use crate::messaging::create_rabbit_channel;
-use lapin::Channel;
+use clap::Parser;
+use dtqs::{config::Config, database::setup_database};
+use dtqs::cli_commands::{self, Cli, Command, DepthSource};
+use dtqs::task_types;
 use tokio::runtime::Runtime;
-use futures_lite::StreamExt;
 
 struct TaskInfo {
   id: String,
@@ -61,7 +64,7 @@ struct App {
   workers: Vec<WorkerNodeInfo>,
   queued_tasks: Vec<TaskInfo>,
   logs: Vec<LogEntry>,
-  pending_count: u32,
+  pending_count: i64,
 }
 
 impl App {
@@ -92,7 +95,7 @@ impl App {
   }
 }
 
-async fn fetch_db_state(pool: &Pool<Postgres>) -> Result<App, sqlx::Error> {
+async fn fetch_db_state(pool: &Pool<Postgres>, tenant_id: &str) -> Result<App, sqlx::Error> {
   let mut app = App::new();
   let worker_rows = sqlx::query!(
         r#"
@@ -100,14 +103,16 @@ async fn fetch_db_state(pool: &Pool<Postgres>) -> Result<App, sqlx::Error> {
             wn.node_id,
             wn.status,
             to_char(wn.last_health_check, 'YYYY-MM-DD HH24:MI:SS') as last_health_check,
-            t.id as task_id,
-            t.task_type,
-            t.status as task_status,
-            t.progress
+            t.id as "task_id?",
+            t.task_type as "task_type?",
+            t.status as "task_status?",
+            t.progress as "progress?"
         FROM worker_nodes wn
-        LEFT JOIN tasks t ON wn.current_task_id = t.id
+        LEFT JOIN tasks t ON wn.current_task_id = t.id AND t.tenant_id = $1
+        WHERE wn.tenant_id = $1 OR wn.tenant_id IS NULL
         ORDER BY wn.last_health_check DESC
-        "#
+        "#,
+        tenant_id
     )
     .fetch_all(pool)
     .await?;
@@ -130,10 +135,11 @@ async fn fetch_db_state(pool: &Pool<Postgres>) -> Result<App, sqlx::Error> {
         r#"
         SELECT id, task_type, status, progress
         FROM tasks
-        WHERE status = 'pending'
+        WHERE status = 'pending' AND tenant_id = $1
         ORDER BY created_at
         LIMIT 5
-        "#
+        "#,
+        tenant_id
     )
     .fetch_all(pool)
     .await?;
@@ -143,17 +149,19 @@ async fn fetch_db_state(pool: &Pool<Postgres>) -> Result<App, sqlx::Error> {
       id: row.id.to_string(),
       task_type: row.task_type,
       status: row.status,
-      progress: row.progress.unwrap_or(0) as u8,
+      progress: row.progress as u8,
     })
     .collect();
 
   let log_rows = sqlx::query!(
         r#"
-        SELECT to_char(timestamp, 'YYYY-MM-DD HH24:MI:SS') as timestamp, message
+        SELECT to_char(created_at, 'YYYY-MM-DD HH24:MI:SS') as timestamp, message
         FROM logs
-        ORDER BY timestamp DESC
+        WHERE tenant_id = $1
+        ORDER BY created_at DESC
         LIMIT 20
-        "#
+        "#,
+        tenant_id
     )
     .fetch_all(pool)
     .await?;
@@ -168,39 +176,53 @@ async fn fetch_db_state(pool: &Pool<Postgres>) -> Result<App, sqlx::Error> {
   Ok(app)
 }
 
-async fn fetch_rabbitmq_state(channel: &Channel) -> Result<u32, lapin::Error> {
-  let queue = channel
-    .queue_declare("task_queue", lapin::options::QueueDeclareOptions { passive: true, ..Default::default() }, lapin::types::FieldTable::default())
-    .await?;
-  Ok(queue.message_count())
+fn main() -> Result<(), Box<dyn Error>> {
+  let cli = Cli::parse();
+  let config = match &cli.config {
+    Some(path) => Config::from_file(path)?,
+    None => Config::from_env(),
+  };
+  match cli.command {
+    None | Some(Command::Dashboard) => run_dashboard(config),
+    Some(command) => {
+      if let Err(e) = Runtime::new()?.block_on(cli_commands::run(command, &config, cli.output)) {
+        eprintln!("error: {:#}", e);
+        std::process::exit(1);
+      }
+      Ok(())
+    }
+  }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn run_dashboard(config: Config) -> Result<(), Box<dyn Error>> {
   enable_raw_mode()?;
   let mut stdout = io::stdout();
   execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
   let backend = CrosstermBackend::new(stdout);
   let mut terminal = Terminal::new(backend)?;
 
-  let config = Config::from_env();
   let rt = Runtime::new()?;
   let db_pool = rt.block_on(setup_database(&config.database_url));
-  let rabbit_channel = rt.block_on(create_rabbit_channel(&config.rabbitmq_url))
-    .expect("Failed to create RabbitMQ channel");
+  let mut depth_source = rt.block_on(DepthSource::open(&config, &db_pool))
+    .expect("Failed to connect to the broker");
+  let queue_names = rt.block_on(task_types::names(&db_pool))
+    .map(|names| config.routing().queues(&config.tenant_id, &names))
+    .expect("Failed to load task types");
 
   let db_pool_arc = Arc::new(db_pool);
-  let rabbit_channel_arc = Arc::new(rabbit_channel);
 
   let (tx, rx) = std::sync::mpsc::channel::<App>();
 
   {
     let db_pool_clone = db_pool_arc.clone();
-    let rabbit_channel_clone = rabbit_channel_arc.clone();
+    let tenant_id = config.tenant_id.clone();
     thread::spawn(move || {
       let rt_bg = Runtime::new().unwrap();
       loop {
-        let mut app_state = rt_bg.block_on(fetch_db_state(&db_pool_clone)).unwrap_or_else(|_| App::new());
-        let pending = rt_bg.block_on(fetch_rabbitmq_state(&rabbit_channel_clone)).unwrap_or(0);
+        let mut app_state = rt_bg.block_on(fetch_db_state(&db_pool_clone, &tenant_id)).unwrap_or_else(|_| App::new());
+        let pending = rt_bg.block_on(depth_source.depths(queue_names.clone()))
+          .map(|depths| depths.iter().map(|depth| depth.messages).sum())
+          .unwrap_or(0);
         app_state.pending_count = pending;
         let _ = tx.send(app_state);
         thread::sleep(Duration::from_secs(2));
@@ -225,14 +247,12 @@ fn main() -> Result<(), Box<dyn Error>> {
     let timeout = tick_rate
       .checked_sub(last_tick.elapsed())
       .unwrap_or_else(|| Duration::from_secs(0));
-    if event::poll(timeout)? {
-      if let CEvent::Key(key) = event::read()? {
-        match key.code {
-          KeyCode::Char('q') => break,
-          KeyCode::Right => app.next_tab(),
-          KeyCode::Left => app.previous_tab(),
-          _ => {}
-        }
+    if event::poll(timeout)? && let CEvent::Key(key) = event::read()? {
+      match key.code {
+        KeyCode::Char('q') => break,
+        KeyCode::Right => app.next_tab(),
+        KeyCode::Left => app.previous_tab(),
+        _ => {}
       }
     }
     if last_tick.elapsed() >= tick_rate {
@@ -257,7 +277,7 @@ fn ui<B: Backend>(f: &mut tui::Frame<B>, app: &App) {
     ].as_ref())
     .split(f.size());
 
-  let tab_titles = vec!["Overview", "Queue", "Logs"];
+  let tab_titles = ["Overview", "Queue", "Logs"];
   let tabs = Tabs::new(
     tab_titles
       .iter()
@@ -331,7 +351,7 @@ fn render_queue<B: Backend>(f: &mut tui::Frame<B>, app: &App, area: Rect) {
       Span::raw(format!("Type: {} | Status: {} | Progress: {}%", t.task_type, t.status, t.progress))
     ]))
   }).collect();
-  let header = format!("Next 5 Tasks in Queue (Pending in RabbitMQ: {})", app.pending_count);
+  let header = format!("Next 5 Tasks in Queue (Pending in the broker: {})", app.pending_count);
   let tasks_list = List::new(task_items)
     .block(Block::default().borders(Borders::ALL).title(header));
   f.render_widget(tasks_list, area);
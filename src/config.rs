@@ -1,21 +1,106 @@
+use std::collections::HashMap;
 use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use crate::topology::Routing;
 
 #[derive(Debug, Clone)]
 pub struct Config {
   pub database_url: String,
+  /// `rabbitmq`, `postgres`, `redis`, or `memory` for single-node mode.
+  pub broker: String,
   pub rabbitmq_url: String,
+  pub redis_url: String,
+  /// How long a `postgres` or `redis` consumer holds a task without renewing its lease.
+  pub queue_lease_secs: u64,
   pub server_port: u16,
+  pub api_keys: Option<String>,
+  pub jwks_source: Option<String>,
+  pub jwt_audience: Option<String>,
+  pub jwt_issuer: Option<String>,
+  pub jwt_tenant_claim: String,
+  pub jwt_scopes_claim: String,
+  pub tenant_id: String,
+  pub per_tenant_queues: bool,
+  pub per_type_queues: bool,
+  /// `TASK_QUEUE_POOLS=image=media,video=media` routes both types to one `media` queue.
+  pub task_queue_pools: HashMap<String, String>,
+  pub rate_limit_backend: String,
+  pub api_url: String,
+  pub api_token: Option<String>,
+  pub shutdown_grace_secs: u64,
 }
 
 impl Config {
   pub fn from_env() -> Self {
+    Self::from_lookup(|key| env::var(key).ok())
+  }
+
+  /// Reads `KEY=VALUE` lines (blank lines and `#` comments ignored) using the same keys as
+  /// `from_env`. Variables set in the environment take precedence over the file.
+  pub fn from_file(path: &Path) -> io::Result<Self> {
+    let file: HashMap<String, String> = fs::read_to_string(path)?
+      .lines()
+      .map(str::trim)
+      .filter(|line| !line.is_empty() && !line.starts_with('#'))
+      .filter_map(|line| line.split_once('='))
+      .map(|(key, value)| (key.trim().to_string(), value.trim().trim_matches('"').to_string()))
+      .collect();
+    Ok(Self::from_lookup(|key| env::var(key).ok().or_else(|| file.get(key).cloned())))
+  }
+
+  fn from_lookup(var: impl Fn(&str) -> Option<String>) -> Self {
+    let server_port = var("SERVER_PORT")
+      .unwrap_or_else(|| "8080".into())
+      .parse()
+      .unwrap_or(8080);
     Self {
-      database_url: env::var("DATABASE_URL").unwrap(),
-      rabbitmq_url: env::var("RABBITMQ_URL").unwrap(),
-      server_port: env::var("SERVER_PORT")
-        .unwrap_or_else(|_| "8080".into())
-        .parse()
-        .unwrap_or(8080),
+      database_url: var("DATABASE_URL").unwrap(),
+      broker: var("BROKER").unwrap_or_else(|| "rabbitmq".into()),
+      rabbitmq_url: var("RABBITMQ_URL").unwrap_or_default(),
+      redis_url: var("REDIS_URL").unwrap_or_default(),
+      queue_lease_secs: var("QUEUE_LEASE_SECS").and_then(|v| v.parse().ok()).unwrap_or(30),
+      server_port,
+      api_keys: var("API_KEYS"),
+      jwks_source: var("JWKS_SOURCE"),
+      jwt_audience: var("JWT_AUDIENCE"),
+      jwt_issuer: var("JWT_ISSUER"),
+      jwt_tenant_claim: var("JWT_TENANT_CLAIM").unwrap_or_else(|| "tenant_id".into()),
+      jwt_scopes_claim: var("JWT_SCOPES_CLAIM").unwrap_or_else(|| "scope".into()),
+      tenant_id: var("TENANT_ID").unwrap_or_else(|| "default".into()),
+      per_tenant_queues: var("PER_TENANT_QUEUES").map(|v| v == "true").unwrap_or(false),
+      per_type_queues: var("PER_TYPE_QUEUES").map(|v| v == "true").unwrap_or(false),
+      task_queue_pools: parse_map("TASK_QUEUE_POOLS", var("TASK_QUEUE_POOLS")),
+      rate_limit_backend: var("RATE_LIMIT_BACKEND").unwrap_or_else(|| "memory".into()),
+      api_url: var("DTQS_API_URL").unwrap_or_else(|| format!("http://localhost:{}", server_port)),
+      api_token: var("DTQS_API_TOKEN"),
+      shutdown_grace_secs: var("SHUTDOWN_GRACE_SECS").and_then(|v| v.parse().ok()).unwrap_or(30),
     }
   }
+
+  pub fn routing(&self) -> Routing {
+    Routing {
+      per_tenant_queues: self.per_tenant_queues,
+      per_type_queues: self.per_type_queues,
+      pools: self.task_queue_pools.clone(),
+    }
+  }
+}
+
+/// Parses `name=value,...` settings such as `TASK_QUEUE_POOLS`; empty when unset. Panics on a
+/// malformed entry rather than dropping it, so a typo cannot quietly change routing.
+pub fn parse_map<T: FromStr>(key: &str, value: Option<String>) -> HashMap<String, T> {
+  let Some(value) = value else {
+    return HashMap::new();
+  };
+  value
+    .split(',')
+    .filter(|entry| !entry.trim().is_empty())
+    .map(|entry| {
+      let parsed = entry.split_once('=').and_then(|(name, value)| Some((name.trim().to_string(), value.trim().parse().ok()?)));
+      parsed.unwrap_or_else(|| panic!("Invalid {} entry: {}", key, entry))
+    })
+    .collect()
 }
\ No newline at end of file
@@ -14,4 +14,16 @@ pub async fn setup_database(database_url: &str) -> Pool<Postgres> {
     .expect("Failed to run database migrations");
   info!("Database migrations complete");
   pool
+}
+
+/// Versions of the migrations built into this binary that the database has not applied.
+pub async fn pending_migrations(pool: &Pool<Postgres>) -> Result<Vec<i64>, sqlx::Error> {
+  let applied: Vec<i64> = sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+    .fetch_all(pool)
+    .await?;
+  Ok(MIGRATOR.iter()
+    .filter(|migration| !migration.migration_type.is_down_migration())
+    .map(|migration| migration.version)
+    .filter(|version| !applied.contains(version))
+    .collect())
 }
\ No newline at end of file
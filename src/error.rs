@@ -0,0 +1,158 @@
+use std::convert::Infallible;
+use tracing::{error, warn};
+use warp::http::StatusCode;
+use warp::Reply;
+use crate::quota::QuotaExceeded;
+use crate::rate_limit::RateLimited;
+use crate::request_id;
+use crate::task_types::{FieldError, TaskTypeError};
+
+#[derive(Debug)]
+pub enum ApiError {
+  Validation(String),
+  InvalidPayload(Vec<FieldError>),
+  BadRequest(String),
+  Unauthorized(String),
+  Forbidden(String),
+  NotFound(String),
+  Conflict(String),
+  RateLimited { bucket_key: String, retry_after: u64 },
+  QuotaExceeded(String),
+  Database(String),
+  Publish(String),
+  Internal(String),
+}
+impl warp::reject::Reject for ApiError {}
+
+impl ApiError {
+  pub fn status(&self) -> StatusCode {
+    match self {
+      ApiError::Validation(_) | ApiError::InvalidPayload(_) | ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+      ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+      ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+      ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+      ApiError::Conflict(_) => StatusCode::CONFLICT,
+      ApiError::RateLimited { .. } | ApiError::QuotaExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
+      ApiError::Publish(_) => StatusCode::SERVICE_UNAVAILABLE,
+      ApiError::Database(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+  }
+
+  /// Stable identifier clients can match on; never change an existing code.
+  pub fn code(&self) -> &'static str {
+    match self {
+      ApiError::Validation(_) | ApiError::InvalidPayload(_) => "validation_failed",
+      ApiError::BadRequest(_) => "bad_request",
+      ApiError::Unauthorized(_) => "unauthorized",
+      ApiError::Forbidden(_) => "forbidden",
+      ApiError::NotFound(_) => "not_found",
+      ApiError::Conflict(_) => "conflict",
+      ApiError::RateLimited { .. } => "rate_limited",
+      ApiError::QuotaExceeded(_) => "quota_exceeded",
+      ApiError::Database(_) => "database_error",
+      ApiError::Publish(_) => "publish_failed",
+      ApiError::Internal(_) => "internal_error",
+    }
+  }
+
+  pub fn message(&self) -> String {
+    match self {
+      ApiError::Validation(m)
+      | ApiError::BadRequest(m)
+      | ApiError::Unauthorized(m)
+      | ApiError::Forbidden(m)
+      | ApiError::NotFound(m)
+      | ApiError::Conflict(m)
+      | ApiError::QuotaExceeded(m)
+      | ApiError::Database(m)
+      | ApiError::Publish(m)
+      | ApiError::Internal(m) => m.clone(),
+      ApiError::InvalidPayload(errors) => format!("Payload failed schema validation with {} error(s)", errors.len()),
+      ApiError::RateLimited { bucket_key, .. } => format!("Rate limit exceeded for {}", bucket_key),
+    }
+  }
+
+  pub fn details(&self) -> Option<Vec<FieldError>> {
+    match self {
+      ApiError::InvalidPayload(errors) => Some(errors.clone()),
+      _ => None,
+    }
+  }
+}
+
+impl From<RateLimited> for ApiError {
+  fn from(e: RateLimited) -> Self {
+    ApiError::RateLimited { bucket_key: e.bucket_key, retry_after: e.retry_after }
+  }
+}
+
+impl From<TaskTypeError> for ApiError {
+  fn from(e: TaskTypeError) -> Self {
+    match e {
+      TaskTypeError::UnknownType(name) => ApiError::Validation(format!("Unsupported task type '{}'", name)),
+      TaskTypeError::UnknownVersion(name, version) => ApiError::Validation(format!("Unknown schema version {} for task type '{}'", version, name)),
+      TaskTypeError::InvalidPayload(errors) => ApiError::InvalidPayload(errors),
+      TaskTypeError::InvalidSchema(message) => ApiError::Validation(format!("Invalid JSON Schema: {}", message)),
+      TaskTypeError::Database(e) => {
+        error!("Task type registry query failed: {:?}", e);
+        ApiError::Database("Failed to access task type registry.".to_string())
+      }
+    }
+  }
+}
+
+impl From<QuotaExceeded> for ApiError {
+  fn from(e: QuotaExceeded) -> Self {
+    ApiError::QuotaExceeded(e.message)
+  }
+}
+
+pub use dtqs_types::{ErrorBody, ErrorDetail};
+
+pub async fn handle_rejection(err: warp::Rejection) -> Result<warp::reply::Response, Infallible> {
+  let (status, code, message) = if let Some(e) = err.find::<ApiError>() {
+    (e.status(), e.code(), e.message())
+  } else if err.is_not_found() {
+    (StatusCode::NOT_FOUND, "not_found", "Route not found".to_string())
+  } else if let Some(e) = err.find::<warp::body::BodyDeserializeError>() {
+    (StatusCode::BAD_REQUEST, "invalid_body", e.to_string())
+  } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+    (StatusCode::BAD_REQUEST, "invalid_query", e.to_string())
+  } else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
+    (StatusCode::BAD_REQUEST, "missing_header", e.to_string())
+  } else if let Some(e) = err.find::<warp::reject::InvalidHeader>() {
+    (StatusCode::BAD_REQUEST, "invalid_header", e.to_string())
+  } else if let Some(e) = err.find::<warp::reject::UnsupportedMediaType>() {
+    (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", e.to_string())
+  } else if let Some(e) = err.find::<warp::reject::PayloadTooLarge>() {
+    (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", e.to_string())
+  } else if let Some(e) = err.find::<warp::reject::LengthRequired>() {
+    (StatusCode::LENGTH_REQUIRED, "length_required", e.to_string())
+  } else if let Some(e) = err.find::<warp::reject::MethodNotAllowed>() {
+    (StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", e.to_string())
+  } else {
+    (StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "Internal server error".to_string())
+  };
+
+  let request_id = request_id::current();
+  if status.is_server_error() {
+    error!("Request {} failed with {}: {} ({:?})", request_id, code, message, err);
+  } else {
+    warn!("Request {} rejected with {}: {}", request_id, code, message);
+  }
+
+  let body = ErrorBody {
+    error: ErrorDetail {
+      code: code.to_string(),
+      message,
+      status: status.as_u16(),
+      request_id,
+      details: err.find::<ApiError>().and_then(ApiError::details),
+    },
+  };
+  let mut response = warp::reply::with_status(warp::reply::json(&body), status).into_response();
+  if let Some(ApiError::RateLimited { retry_after, .. }) = err.find::<ApiError>() {
+    response.headers_mut().insert("retry-after", retry_after.to_string().parse().unwrap());
+  }
+  Ok(response)
+}
@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use tokio::sync::RwLock;
+use tracing::error;
+use warp::http::StatusCode;
+use warp::Filter;
+use crate::database::pending_migrations;
+use crate::broker::Broker;
+
+#[derive(Serialize)]
+struct Readiness {
+  ready: bool,
+  /// `ok`, or why the check failed.
+  checks: BTreeMap<&'static str, String>,
+}
+
+fn readiness_reply(checks: Vec<(&'static str, Result<(), String>)>) -> warp::reply::WithStatus<warp::reply::Json> {
+  let ready = checks.iter().all(|(_, result)| result.is_ok());
+  let checks = checks
+    .into_iter()
+    .map(|(name, result)| (name, result.err().unwrap_or_else(|| "ok".to_string())))
+    .collect();
+  let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+  warp::reply::with_status(warp::reply::json(&Readiness { ready, checks }), status)
+}
+
+async fn check_postgres(db_pool: &Pool<Postgres>) -> Result<(), String> {
+  sqlx::query("SELECT 1").execute(db_pool).await.map(|_| ()).map_err(|e| e.to_string())
+}
+
+async fn check_migrations(db_pool: &Pool<Postgres>) -> Result<(), String> {
+  match pending_migrations(db_pool).await {
+    Ok(pending) if pending.is_empty() => Ok(()),
+    Ok(pending) => Err(format!("pending migrations: {:?}", pending)),
+    Err(e) => Err(e.to_string()),
+  }
+}
+
+/// `/healthz` answers as long as the process serves requests; `/readyz` also requires Postgres,
+/// a connected broker and an up-to-date schema.
+pub fn api_health_routes(db_pool: Pool<Postgres>, broker: Arc<dyn Broker>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  let readyz = warp::path("readyz")
+    .and(warp::get())
+    .then(move || {
+      let db_pool = db_pool.clone();
+      let broker = broker.clone();
+      async move {
+        readiness_reply(vec![
+          ("postgres", check_postgres(&db_pool).await),
+          (broker.name(), broker.status()),
+          ("migrations", check_migrations(&db_pool).await),
+        ])
+      }
+    });
+  healthz().or(readyz)
+}
+
+fn healthz() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  warp::path("healthz")
+    .and(warp::get())
+    .map(|| warp::reply::json(&json!({ "status": "ok" })))
+}
+
+/// Consumer, heartbeat and lifecycle state reported by the worker's `/readyz` and its `worker_nodes` row.
+pub struct WorkerHealth {
+  worker_id: String,
+  db_pool: Pool<Postgres>,
+  /// Task types this worker accepts, published in `worker_nodes.task_types`.
+  task_types: Vec<String>,
+  consumers_expected: AtomicUsize,
+  consumers_running: AtomicUsize,
+  heartbeat_interval: Duration,
+  last_heartbeat: RwLock<Option<Instant>>,
+  status: RwLock<&'static str>,
+}
+
+impl WorkerHealth {
+  pub fn new(worker_id: String, db_pool: Pool<Postgres>, task_types: Vec<String>, consumers_expected: usize, heartbeat_interval: Duration) -> Self {
+    Self {
+      worker_id,
+      db_pool,
+      task_types,
+      consumers_expected: AtomicUsize::new(consumers_expected),
+      consumers_running: AtomicUsize::new(0),
+      heartbeat_interval,
+      last_heartbeat: RwLock::new(None),
+      status: RwLock::new("active"),
+    }
+  }
+
+  /// Counts a consumer started after `new`, e.g. for a newly discovered tenant queue.
+  pub fn consumer_added(&self) {
+    self.consumers_expected.fetch_add(1, Ordering::SeqCst);
+  }
+
+  pub fn consumer_started(&self) {
+    self.consumers_running.fetch_add(1, Ordering::SeqCst);
+  }
+
+  pub fn consumer_stopped(&self) {
+    self.consumers_running.fetch_sub(1, Ordering::SeqCst);
+  }
+
+  /// Upserts this worker's `worker_nodes` row every `heartbeat_interval`.
+  pub fn spawn_heartbeat(self: Arc<Self>) {
+    tokio::spawn(async move {
+      let mut interval = tokio::time::interval(self.heartbeat_interval);
+      loop {
+        interval.tick().await;
+        self.beat().await;
+      }
+    });
+  }
+
+  /// Records `status` (`active`, `draining` or `stopped`) in `worker_nodes` right away; anything
+  /// other than `active` also fails readiness.
+  pub async fn set_status(&self, status: &'static str) {
+    *self.status.write().await = status;
+    self.beat().await;
+  }
+
+  async fn beat(&self) {
+    let status = *self.status.read().await;
+    let result = sqlx::query!(
+        "INSERT INTO worker_nodes (node_id, status, task_types, last_health_check) VALUES ($1, $2, $3, NOW())
+         ON CONFLICT (node_id) DO UPDATE SET status = $2, task_types = $3, last_health_check = NOW()",
+        self.worker_id,
+        status,
+        &self.task_types
+      )
+      .execute(&self.db_pool)
+      .await;
+    match result {
+      Ok(_) => *self.last_heartbeat.write().await = Some(Instant::now()),
+      Err(e) => error!("Failed to record heartbeat for {}: {:?}", self.worker_id, e),
+    }
+  }
+
+  async fn check_status(&self) -> Result<(), String> {
+    match *self.status.read().await {
+      "active" => Ok(()),
+      status => Err(format!("worker is {}", status)),
+    }
+  }
+
+  fn check_consumers(&self) -> Result<(), String> {
+    let running = self.consumers_running.load(Ordering::SeqCst);
+    let expected = self.consumers_expected.load(Ordering::SeqCst);
+    if running >= expected {
+      Ok(())
+    } else {
+      Err(format!("{} of {} consumers running", running, expected))
+    }
+  }
+
+  /// Stale after three missed heartbeats.
+  async fn check_heartbeat(&self) -> Result<(), String> {
+    match *self.last_heartbeat.read().await {
+      Some(at) if at.elapsed() <= self.heartbeat_interval * 3 => Ok(()),
+      Some(at) => Err(format!("last heartbeat {}s ago", at.elapsed().as_secs())),
+      None => Err("no heartbeat recorded yet".to_string()),
+    }
+  }
+}
+
+pub fn worker_health_routes(health: Arc<WorkerHealth>, broker: Arc<dyn Broker>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  let readyz = warp::path("readyz")
+    .and(warp::get())
+    .then(move || {
+      let health = health.clone();
+      let broker = broker.clone();
+      async move {
+        readiness_reply(vec![
+          ("status", health.check_status().await),
+          ("postgres", check_postgres(&health.db_pool).await),
+          (broker.name(), broker.status()),
+          ("consumers", health.check_consumers()),
+          ("heartbeat", health.check_heartbeat().await),
+        ])
+      }
+    });
+  healthz().or(readyz)
+}
@@ -1,9 +1,22 @@
+pub mod auth;
+pub mod broker;
+pub mod cli_commands;
 pub mod config;
 pub mod database;
+pub mod error;
+pub mod health;
 pub mod models;
 pub mod messaging;
+pub mod metrics;
+pub mod quota;
+pub mod rate_limit;
+pub mod request_id;
 pub mod routes;
+pub mod sanitize;
+pub mod shutdown;
+pub mod task_types;
+pub mod telemetry;
+pub mod topology;
 pub mod worker_scheduler;
 pub mod worker_processing;
-pub mod worker;
-mod cli_dashboard;
\ No newline at end of file
+pub mod worker_runtime;
\ No newline at end of file
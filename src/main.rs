@@ -1,20 +1,82 @@
 use warp::Filter;
-use tracing_subscriber;
-use dtqs::{config::Config, database::setup_database, messaging::create_rabbit_channel, routes::routes};
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::time::Duration;
+use tracing::{info, warn};
+use std::env;
+use tokio::sync::watch;
+use warp::hyper::{service::{make_service_fn, service_fn}, Server};
+use dtqs::{auth::Authenticator, broker, rate_limit::RateLimiter, request_id, task_types::TaskTypeRegistry, config::Config, database::setup_database, error::handle_rejection, health::{self, WorkerHealth}, metrics, routes::routes, shutdown, telemetry, topology::TASK_QUEUE};
+use dtqs::worker_runtime::{self, WorkerSettings};
 
 #[tokio::main]
 async fn main() {
-  tracing_subscriber::fmt::init();
+  let tracer_provider = telemetry::init("dtqs-api");
   let config = Config::from_env();
   let db_pool = setup_database(&config.database_url).await;
-  let rabbit_channel = create_rabbit_channel(&config.rabbitmq_url)
+  let broker = broker::connect(&config.broker, &config.rabbitmq_url, &config.redis_url, &db_pool, Duration::from_secs(config.queue_lease_secs), &[TASK_QUEUE.to_string()])
     .await
-    .expect("Failed to create RabbitMQ channel");
+    .expect("Failed to connect to the broker");
+  let authenticator = Arc::new(Authenticator::from_config(&config)
+    .await
+    .expect("Failed to initialise authentication"));
+  let rate_limiter = Arc::new(RateLimiter::new(db_pool.clone(), &config.rate_limit_backend));
+  rate_limiter.reload_rules()
+    .await
+    .expect("Failed to load rate limit rules");
+  rate_limiter.clone().spawn_rule_refresh(Duration::from_secs(10));
+  let task_types = Arc::new(TaskTypeRegistry::new(db_pool.clone()));
+  task_types.reload()
+    .await
+    .expect("Failed to load task types");
+  task_types.clone().spawn_refresh(Duration::from_secs(10));
+
+  // Single-node mode: the in-memory broker only reaches consumers in this process, so the API runs
+  // the worker loop as well.
+  let (worker_stop_tx, worker_stop_rx) = watch::channel(false);
+  let worker = (config.broker == "memory").then(|| {
+    let settings = WorkerSettings::from_config(env::var("WORKER_ID").unwrap_or_else(|_| "single-node".into()), &config);
+    let worker_health = Arc::new(WorkerHealth::new(settings.worker_id.clone(), db_pool.clone(), settings.task_types.clone(), settings.queues.len(), Duration::from_secs(10)));
+    worker_health.clone().spawn_heartbeat();
+    tokio::spawn(worker_runtime::run(settings, broker.clone(), db_pool.clone(), worker_health, worker_stop_rx))
+  });
+
+  let api = health::api_health_routes(db_pool.clone(), broker.clone())
+    .or(routes(db_pool, broker.clone(), authenticator, rate_limiter, task_types, config.clone()))
+    .or(metrics::metrics_route())
+    .recover(handle_rejection)
+    .with(warp::log::custom(metrics::record_request))
+    .with(warp::trace(request_id::span));
 
-  let api = routes(db_pool, rabbit_channel)
-    .or(warp::path("metrics").map(|| "prometheus_metrics_placeholder"));
+  let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+  // Served through hyper directly so every request, rejected or not, runs with its request id.
+  let service = warp::service(api);
+  let make_service = make_service_fn(move |_| {
+    let service = service.clone();
+    async move { Ok::<_, Infallible>(service_fn(move |request| request_id::handle(service.clone(), request))) }
+  });
+  let server = Server::bind(&([0, 0, 0, 0], config.server_port).into())
+    .serve(make_service)
+    .with_graceful_shutdown(async {
+      let _ = stop_rx.await;
+    });
+  let server = tokio::spawn(server);
 
-  warp::serve(api)
-    .run(([0, 0, 0, 0], config.server_port))
-    .await;
+  shutdown::signal().await;
+  info!("Shutting down: waiting up to {}s for in-flight requests", config.shutdown_grace_secs);
+  let _ = stop_tx.send(());
+  let _ = worker_stop_tx.send(true);
+  // Open `/sse` streams never finish on their own, so they are dropped once the grace period ends.
+  match tokio::time::timeout(Duration::from_secs(config.shutdown_grace_secs), server).await {
+    Ok(_) => info!("API stopped"),
+    Err(_) => warn!("Grace period elapsed with requests still in flight"),
+  }
+  // The worker applies the same grace period to its running tasks.
+  if let Some(worker) = worker {
+    let _ = worker.await;
+  }
+  broker.close().await;
+  if let Some(provider) = tracer_provider {
+    let _ = provider.shutdown();
+  }
 }
@@ -1,14 +1,23 @@
-use lapin::{Connection, ConnectionProperties, Channel, options::BasicPublishOptions, BasicProperties};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use lapin::{Connection, ConnectionProperties, Channel};
+use tokio::sync::{watch, Mutex, Notify};
 use tokio_retry::Retry;
 use tokio_retry::strategy::ExponentialBackoff;
-use tracing::info;
-use anyhow::Result;
+use tracing::{info, warn};
+use anyhow::{anyhow, Result};
+use crate::broker::{Broker, Message};
+use crate::metrics::{PUBLISH_DURATION, PUBLISH_FAILURES};
+use crate::telemetry::trace_headers;
+use crate::topology::declare_queue;
 
-static MAX_RETRIES: usize = 5;
-static DELAY: u64 = 100;
+pub(crate) static MAX_RETRIES: usize = 5;
+pub(crate) static DELAY: u64 = 100;
 
 pub async fn create_rabbit_channel(rabbitmq_url: &str) -> Result<Channel> {
-  let conn = Retry::spawn(ExponentialBackoff::from_millis(DELAY).take(MAX_RETRIES), || {
+  let conn = Retry::start(ExponentialBackoff::from_millis(DELAY).take(MAX_RETRIES), || {
     Connection::connect(rabbitmq_url, ConnectionProperties::default())
   })
     .await?;
@@ -17,10 +26,157 @@ pub async fn create_rabbit_channel(rabbitmq_url: &str) -> Result<Channel> {
   Ok(channel)
 }
 
-pub async fn publish_message(channel: &Channel, queue: &str, payload: &[u8]) -> Result<()> {
-  Retry::spawn(ExponentialBackoff::from_millis(DELAY).take(MAX_RETRIES), || async {
-    channel.basic_publish("", queue, BasicPublishOptions::default(), payload, BasicProperties::default()).await
-  })
-    .await?;
-  Ok(())
+/// Keeps an AMQP connection and channel open for the life of the process. When the broker goes
+/// away it reconnects with backoff and re-declares every queue declared through it; consumers
+/// restart by waiting on `wait_for_channel` again. Cheap to clone.
+#[derive(Clone)]
+pub struct RabbitConnection {
+  inner: Arc<Inner>,
+}
+
+struct Inner {
+  url: String,
+  queues: std::sync::Mutex<BTreeSet<String>>,
+  /// `None` while reconnecting.
+  channel: watch::Sender<Option<Channel>>,
+  connection: Mutex<Option<Connection>>,
+  lost: Notify,
+  closing: AtomicBool,
+}
+
+impl RabbitConnection {
+  /// Connects and declares `queues`, retrying like `create_rabbit_channel`, then keeps the
+  /// connection alive in the background.
+  pub async fn connect(url: &str, queues: &[String]) -> Result<Self> {
+    let rabbit = Self {
+      inner: Arc::new(Inner {
+        url: url.to_string(),
+        queues: std::sync::Mutex::new(queues.iter().cloned().collect()),
+        channel: watch::Sender::new(None),
+        connection: Mutex::new(None),
+        lost: Notify::new(),
+        closing: AtomicBool::new(false),
+      }),
+    };
+    let channel = Retry::start(ExponentialBackoff::from_millis(DELAY).take(MAX_RETRIES), || rabbit.open()).await?;
+    rabbit.inner.channel.send_replace(Some(channel));
+    info!("RabbitMQ channel created");
+    tokio::spawn(rabbit.clone().supervise());
+    Ok(rabbit)
+  }
+
+  /// The current channel, or an error while the connection is down.
+  pub fn channel(&self) -> Result<Channel> {
+    match &*self.inner.channel.borrow() {
+      Some(channel) if channel.status().connected() => Ok(channel.clone()),
+      _ => Err(anyhow!("RabbitMQ is not connected")),
+    }
+  }
+
+  /// Waits until a connected channel is available.
+  pub async fn wait_for_channel(&self) -> Channel {
+    let mut channel = self.inner.channel.subscribe();
+    let current = channel
+      .wait_for(|channel| channel.as_ref().is_some_and(|channel| channel.status().connected()))
+      .await
+      .expect("RabbitConnection owns the sender");
+    current.clone().unwrap()
+  }
+
+  /// Declares `queue` now and again after every reconnect.
+  pub async fn declare_queue(&self, queue: &str) -> Result<()> {
+    let added = self.inner.queues.lock().unwrap().insert(queue.to_string());
+    let result = declare_queue(&self.channel()?, queue).await;
+    if result.is_err() && added {
+      self.inner.queues.lock().unwrap().remove(queue);
+    }
+    result
+  }
+
+  /// `Ok` while the channel is open; used by the readiness checks.
+  pub fn status(&self) -> Result<(), String> {
+    match &*self.inner.channel.borrow() {
+      Some(channel) if channel.status().connected() => Ok(()),
+      Some(channel) => Err(format!("channel is {:?}", channel.status().state())),
+      None => Err("reconnecting".to_string()),
+    }
+  }
+
+  /// Closes the connection and stops reconnecting.
+  pub async fn close(&self) {
+    self.inner.closing.store(true, Ordering::SeqCst);
+    if let Some(connection) = self.inner.connection.lock().await.take()
+      && let Err(e) = connection.close(200, "shutting down").await {
+      warn!("Failed to close RabbitMQ connection: {:?}", e);
+    }
+  }
+
+  async fn open(&self) -> Result<Channel> {
+    let connection = Connection::connect(&self.inner.url, ConnectionProperties::default()).await?;
+    let inner = self.inner.clone();
+    connection.on_error(move |e| {
+      warn!("RabbitMQ connection error: {:?}", e);
+      inner.lost.notify_one();
+    });
+    let channel = connection.create_channel().await?;
+    let queues: Vec<String> = self.inner.queues.lock().unwrap().iter().cloned().collect();
+    for queue in &queues {
+      declare_queue(&channel, queue).await?;
+    }
+    if let Some(previous) = self.inner.connection.lock().await.replace(connection) {
+      let _ = previous.close(200, "reconnecting").await;
+    }
+    Ok(channel)
+  }
+
+  /// Checks the channel every second, or as soon as the connection reports an error, and
+  /// reconnects with exponential backoff (capped at 30 seconds) until it succeeds.
+  async fn supervise(self) {
+    loop {
+      tokio::select! {
+        _ = self.inner.lost.notified() => {}
+        _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+      }
+      if self.inner.closing.load(Ordering::SeqCst) {
+        return;
+      }
+      if self.status().is_ok() {
+        continue;
+      }
+      warn!("RabbitMQ connection lost, reconnecting");
+      self.inner.channel.send_replace(None);
+      let backoff = ExponentialBackoff::from_millis(2).factor(100).max_delay(Duration::from_secs(30));
+      for (attempt, delay) in backoff.enumerate() {
+        if self.inner.closing.load(Ordering::SeqCst) {
+          return;
+        }
+        match self.open().await {
+          Ok(channel) => {
+            info!("Reconnected to RabbitMQ after {} attempts", attempt + 1);
+            self.inner.channel.send_replace(Some(channel));
+            break;
+          }
+          Err(e) => {
+            warn!("RabbitMQ reconnect attempt {} failed, retrying in {:?}: {:?}", attempt + 1, delay, e);
+            tokio::time::sleep(delay).await;
+          }
+        }
+      }
+    }
+  }
+}
+
+/// Publishes the task `payload` with its `priority` through whichever broker is configured. The
+/// current trace context travels in the headers so the consuming worker's spans join the
+/// publisher's trace.
+#[tracing::instrument(name = "publish_message", skip(broker, payload), fields(messaging.destination = queue))]
+pub async fn publish_message(broker: &dyn Broker, queue: &str, payload: &[u8], priority: i32) -> Result<()> {
+  let message = Message { payload: payload.to_vec(), priority, headers: trace_headers() };
+  let timer = PUBLISH_DURATION.start_timer();
+  let result = broker.publish(queue, message).await;
+  timer.observe_duration();
+  if result.is_err() {
+    PUBLISH_FAILURES.inc();
+  }
+  result
 }
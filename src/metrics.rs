@@ -0,0 +1,111 @@
+use std::sync::LazyLock;
+use prometheus::{
+  register_counter_vec, register_histogram, register_histogram_vec, register_int_counter,
+  register_int_gauge, register_gauge, CounterVec, Encoder, Gauge, Histogram, HistogramVec,
+  IntCounter, IntGauge, TextEncoder,
+};
+use uuid::Uuid;
+use warp::Filter;
+
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const TASK_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+// API
+
+pub static TASK_SUBMISSIONS: LazyLock<CounterVec> = LazyLock::new(|| {
+  register_counter_vec!("dtqs_task_submissions_total", "Task submissions by task type and result (an error code, or `accepted`).", &["task_type", "result"]).unwrap()
+});
+
+pub static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+  register_histogram_vec!("dtqs_http_request_duration_seconds", "HTTP request latency by route template, method and status.", &["route", "method", "status"], LATENCY_BUCKETS.to_vec()).unwrap()
+});
+
+pub static PUBLISH_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+  register_histogram!("dtqs_publish_duration_seconds", "Time to publish a task message to RabbitMQ, including retries.", LATENCY_BUCKETS.to_vec()).unwrap()
+});
+
+pub static PUBLISH_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+  register_int_counter!("dtqs_publish_failures_total", "Task messages that could not be published after all retries.").unwrap()
+});
+
+// Worker
+
+pub static TASK_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+  register_histogram_vec!("dtqs_worker_task_duration_seconds", "Time spent processing one attempt of a task.", &["task_type", "result"], TASK_BUCKETS.to_vec()).unwrap()
+});
+
+pub static QUEUE_WAIT: LazyLock<HistogramVec> = LazyLock::new(|| {
+  register_histogram_vec!("dtqs_worker_queue_wait_seconds", "Time from publishing a task to a worker starting it.", &["task_type"], TASK_BUCKETS.to_vec()).unwrap()
+});
+
+pub static TASK_ATTEMPTS: LazyLock<CounterVec> = LazyLock::new(|| {
+  register_counter_vec!("dtqs_worker_task_attempts_total", "Processing attempts by task type and outcome (completed, retried, failed, skipped).", &["task_type", "outcome"]).unwrap()
+});
+
+pub static TASKS_IN_FLIGHT: LazyLock<IntGauge> = LazyLock::new(|| {
+  register_int_gauge!("dtqs_worker_tasks_in_flight", "Tasks currently being processed.").unwrap()
+});
+
+pub static CONCURRENCY_LIMIT: LazyLock<IntGauge> = LazyLock::new(|| {
+  register_int_gauge!("dtqs_worker_concurrency_limit", "Permits in the worker's task semaphore.").unwrap()
+});
+
+pub static SEMAPHORE_UTILIZATION: LazyLock<Gauge> = LazyLock::new(|| {
+  register_gauge!("dtqs_worker_semaphore_utilization", "Fraction of semaphore permits in use.").unwrap()
+});
+
+/// Updates the in-flight and utilization gauges from the semaphore's free permits.
+pub fn record_worker_load(available_permits: usize) {
+  let limit = CONCURRENCY_LIMIT.get();
+  let in_use = limit - available_permits as i64;
+  TASKS_IN_FLIGHT.set(in_use);
+  if limit > 0 {
+    SEMAPHORE_UTILIZATION.set(in_use as f64 / limit as f64);
+  }
+}
+
+/// Feeds `HTTP_REQUEST_DURATION`; use with `warp::log::custom`.
+pub fn record_request(info: warp::log::Info) {
+  HTTP_REQUEST_DURATION
+    .with_label_values(&[&route_label(info.path()), info.method().as_str(), info.status().as_str()])
+    .observe(info.elapsed().as_secs_f64());
+}
+
+/// Maps a request path to its route template so ids and unknown paths don't each get a series.
+fn route_label(path: &str) -> String {
+  let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+  let template: Vec<&str> = segments
+    .iter()
+    .enumerate()
+    .map(|(i, segment)| {
+      if Uuid::parse_str(segment).is_ok() {
+        "{id}"
+      } else if i == 2 && segments[..2] == ["admin", "task_types"] {
+        "{name}"
+      } else {
+        segment
+      }
+    })
+    .collect();
+  let route = format!("/{}", template.join("/"));
+  match route.as_str() {
+    "/submit" | "/sse" | "/quotas" | "/tasks" | "/tasks/{id}" | "/tasks/{id}/cancel" | "/tasks/{id}/retry"
+    | "/admin/rate_limits" | "/admin/task_types" | "/admin/task_types/{name}"
+    | "/openapi.json" | "/docs" | "/metrics" => route,
+    _ => "other".to_string(),
+  }
+}
+
+/// Serves everything registered in the default registry in the Prometheus text format.
+pub fn metrics_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  warp::path("metrics")
+    .and(warp::get())
+    .map(|| {
+      let encoder = TextEncoder::new();
+      let mut body = Vec::new();
+      if let Err(e) = encoder.encode(&prometheus::gather(), &mut body) {
+        tracing::error!("Failed to encode metrics: {:?}", e);
+      }
+      warp::reply::with_header(body, "content-type", encoder.format_type())
+    })
+}
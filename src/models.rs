@@ -2,18 +2,7 @@ use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Task {
-  pub id: Uuid,
-  pub task_type: String,
-  pub payload: serde_json::Value,
-  pub status: String,
-  pub priority: i32,
-  pub progress: i32,
-  pub attempts: i32,
-  pub created_at: DateTime<Utc>,
-  pub updated_at: DateTime<Utc>,
-}
+pub use dtqs_types::Task;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WorkerNode {
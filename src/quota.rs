@@ -0,0 +1,96 @@
+use anyhow::Result;
+use serde::Serialize;
+use utoipa::ToSchema;
+use sqlx::PgConnection;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct QuotaUsage {
+  pub task_type: String,
+  pub max_outstanding: Option<i32>,
+  pub outstanding: i64,
+  pub max_payload_bytes: Option<i64>,
+  pub payload_bytes: i64,
+}
+
+#[derive(Debug)]
+pub struct QuotaExceeded {
+  pub message: String,
+}
+
+/// Quota rows with `tenant_id = '*'` apply to every tenant without its own row for that task type,
+/// and `task_type = '*'` covers all of a tenant's task types together. Outstanding counts only
+/// `pending` and `in_progress` tasks, while payload bytes count every task the tenant stores.
+pub async fn usage(conn: &mut PgConnection, tenant_id: &str) -> Result<Vec<QuotaUsage>> {
+  let limits = sqlx::query!(
+        r#"
+        SELECT DISTINCT ON (task_type) task_type, max_outstanding, max_payload_bytes
+        FROM tenant_quotas
+        WHERE tenant_id = $1 OR tenant_id = '*'
+        ORDER BY task_type, tenant_id = '*'
+        "#,
+        tenant_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+  let mut usage = Vec::with_capacity(limits.len());
+  for limit in limits {
+    let current = sqlx::query!(
+          r#"
+          SELECT
+            COUNT(*) FILTER (WHERE status IN ('pending', 'in_progress')) AS "outstanding!",
+            COALESCE(SUM(octet_length(payload::text)), 0)::BIGINT AS "payload_bytes!"
+          FROM tasks
+          WHERE tenant_id = $1 AND ($2 = '*' OR task_type = $2)
+          "#,
+          tenant_id,
+          limit.task_type
+      )
+      .fetch_one(&mut *conn)
+      .await?;
+    usage.push(QuotaUsage {
+      task_type: limit.task_type,
+      max_outstanding: limit.max_outstanding,
+      outstanding: current.outstanding,
+      max_payload_bytes: limit.max_payload_bytes,
+      payload_bytes: current.payload_bytes,
+    });
+  }
+  Ok(usage)
+}
+
+/// Size of `payload` as stored, measured the same way as `usage` so checks and accounting agree.
+pub async fn payload_bytes(conn: &mut PgConnection, payload: &serde_json::Value) -> Result<i64> {
+  let bytes = sqlx::query_scalar!(r#"SELECT octet_length($1::jsonb::text)::BIGINT AS "bytes!""#, payload)
+    .fetch_one(&mut *conn)
+    .await?;
+  Ok(bytes)
+}
+
+/// Must run inside the transaction that inserts the task: the tenant's advisory lock is held
+/// until commit so concurrent submissions cannot both squeeze under the limit.
+pub async fn check(conn: &mut PgConnection, tenant_id: &str, task_type: &str, payload_bytes: i64) -> Result<Option<QuotaExceeded>> {
+  sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+    .bind(tenant_id)
+    .execute(&mut *conn)
+    .await?;
+
+  for quota in usage(conn, tenant_id).await? {
+    if quota.task_type != "*" && quota.task_type != task_type {
+      continue;
+    }
+    if let Some(max) = quota.max_outstanding
+      && quota.outstanding + 1 > max as i64 {
+      return Ok(Some(QuotaExceeded {
+        message: format!("Quota exceeded: {} of {} outstanding '{}' tasks in use", quota.outstanding, max, quota.task_type),
+      }));
+    }
+    if let Some(max) = quota.max_payload_bytes
+      && quota.payload_bytes + payload_bytes > max {
+      return Ok(Some(QuotaExceeded {
+        message: format!("Quota exceeded: {} of {} payload bytes for '{}' tasks in use", quota.payload_bytes, max, quota.task_type),
+      }));
+    }
+  }
+  Ok(None)
+}
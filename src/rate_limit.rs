@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use sqlx::{Pool, Postgres};
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::Duration;
+use tracing::{error, info};
+use crate::auth::Principal;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+pub struct Limit {
+  pub capacity: f64,
+  pub refill_per_sec: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RateLimitRule {
+  pub bucket_key: String,
+  #[serde(flatten)]
+  pub limit: Limit,
+}
+
+#[derive(Debug)]
+pub struct RateLimited {
+  pub bucket_key: String,
+  pub retry_after: u64,
+}
+
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+enum BucketStore {
+  Memory(Mutex<HashMap<String, Bucket>>),
+  Postgres,
+}
+
+pub struct RateLimiter {
+  db_pool: Pool<Postgres>,
+  store: BucketStore,
+  rules: RwLock<HashMap<String, Limit>>,
+}
+
+impl RateLimiter {
+  pub fn new(db_pool: Pool<Postgres>, backend: &str) -> Self {
+    let store = match backend {
+      "postgres" => BucketStore::Postgres,
+      _ => BucketStore::Memory(Mutex::new(HashMap::new())),
+    };
+    Self { db_pool, store, rules: RwLock::new(HashMap::new()) }
+  }
+
+  pub async fn reload_rules(&self) -> Result<()> {
+    let rows = sqlx::query!("SELECT bucket_key, capacity, refill_per_sec FROM rate_limit_rules")
+      .fetch_all(&self.db_pool)
+      .await?;
+    let rules = rows
+      .into_iter()
+      .map(|row| (row.bucket_key, Limit { capacity: row.capacity, refill_per_sec: row.refill_per_sec }))
+      .collect();
+    *self.rules.write().await = rules;
+    Ok(())
+  }
+
+  /// Rules are edited through the admin API on any replica, so every replica re-reads them periodically.
+  pub fn spawn_rule_refresh(self: Arc<Self>, every: Duration) {
+    tokio::spawn(async move {
+      let mut interval = tokio::time::interval(every);
+      loop {
+        interval.tick().await;
+        if let Err(e) = self.reload_rules().await {
+          error!("Failed to reload rate limit rules: {:?}", e);
+        }
+      }
+    });
+  }
+
+  pub async fn list_rules(&self) -> Vec<RateLimitRule> {
+    self.rules.read().await
+      .iter()
+      .map(|(bucket_key, limit)| RateLimitRule { bucket_key: bucket_key.clone(), limit: *limit })
+      .collect()
+  }
+
+  pub async fn set_rule(&self, rule: RateLimitRule) -> Result<()> {
+    sqlx::query!(
+        "INSERT INTO rate_limit_rules (bucket_key, capacity, refill_per_sec, updated_at) VALUES ($1, $2, $3, NOW())
+         ON CONFLICT (bucket_key) DO UPDATE SET capacity = $2, refill_per_sec = $3, updated_at = NOW()",
+        rule.bucket_key,
+        rule.limit.capacity,
+        rule.limit.refill_per_sec
+      )
+      .execute(&self.db_pool)
+      .await?;
+    info!("Rate limit for {} set to {:?}", rule.bucket_key, rule.limit);
+    self.rules.write().await.insert(rule.bucket_key, rule.limit);
+    Ok(())
+  }
+
+  /// Takes one token from the API key, tenant and task type buckets. Bucket keys are
+  /// `key:<subject>`, `tenant:<tenant>` and `task_type:<tenant>:<type>`; a rule on the exact
+  /// key wins over the `*` wildcard rules, and buckets without any rule are unlimited. When a
+  /// bucket rejects, tokens already taken from the others are given back.
+  pub async fn check_submission(&self, principal: &Principal, task_type: &str) -> Result<(), RateLimited> {
+    let buckets = [
+      (format!("key:{}", principal.subject), vec!["key:*".to_string()]),
+      (format!("tenant:{}", principal.tenant_id), vec!["tenant:*".to_string()]),
+      (
+        format!("task_type:{}:{}", principal.tenant_id, task_type),
+        vec![format!("task_type:*:{}", task_type), "task_type:*".to_string()],
+      ),
+    ];
+
+    let mut taken = Vec::new();
+    for (bucket_key, fallbacks) in buckets {
+      let limit = {
+        let rules = self.rules.read().await;
+        std::iter::once(&bucket_key)
+          .chain(fallbacks.iter())
+          .find_map(|key| rules.get(key).copied())
+      };
+      let Some(limit) = limit else { continue };
+
+      match self.take(&bucket_key, limit).await {
+        Ok(None) => taken.push((bucket_key, limit)),
+        Ok(Some(retry_after)) => {
+          for (taken_key, taken_limit) in taken {
+            if let Err(e) = self.refund(&taken_key, taken_limit).await {
+              error!("Failed to refund rate limit token to {}: {:?}", taken_key, e);
+            }
+          }
+          return Err(RateLimited { bucket_key, retry_after });
+        }
+        Err(e) => error!("Rate limit check for {} failed, allowing request: {:?}", bucket_key, e),
+      }
+    }
+    Ok(())
+  }
+
+  /// Returns a token taken for a request that was rejected by another bucket.
+  async fn refund(&self, bucket_key: &str, limit: Limit) -> Result<()> {
+    match &self.store {
+      BucketStore::Memory(buckets) => {
+        if let Some(bucket) = buckets.lock().await.get_mut(bucket_key) {
+          bucket.tokens = (bucket.tokens + 1.0).min(limit.capacity);
+        }
+      }
+      BucketStore::Postgres => {
+        sqlx::query!(
+            "UPDATE rate_limit_buckets SET tokens = LEAST($2, tokens + 1) WHERE bucket_key = $1",
+            bucket_key,
+            limit.capacity
+          )
+          .execute(&self.db_pool)
+          .await?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Returns `None` when a token was taken, or the number of seconds until one is available.
+  async fn take(&self, bucket_key: &str, limit: Limit) -> Result<Option<u64>> {
+    let tokens = match &self.store {
+      BucketStore::Memory(buckets) => {
+        let mut buckets = buckets.lock().await;
+        let now = Instant::now();
+        let bucket = buckets
+          .entry(bucket_key.to_string())
+          .or_insert(Bucket { tokens: limit.capacity, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * limit.refill_per_sec)
+          .min(limit.capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+          bucket.tokens -= 1.0;
+          return Ok(None);
+        }
+        bucket.tokens
+      }
+      BucketStore::Postgres => {
+        let taken = sqlx::query!(
+            r#"
+            INSERT INTO rate_limit_buckets AS b (bucket_key, tokens, updated_at)
+            VALUES ($1, $2::DOUBLE PRECISION - 1, NOW())
+            ON CONFLICT (bucket_key) DO UPDATE
+            SET tokens = LEAST($2, b.tokens + EXTRACT(EPOCH FROM NOW() - b.updated_at)::DOUBLE PRECISION * $3) - 1,
+                updated_at = NOW()
+            WHERE LEAST($2, b.tokens + EXTRACT(EPOCH FROM NOW() - b.updated_at)::DOUBLE PRECISION * $3) >= 1
+            RETURNING tokens
+            "#,
+            bucket_key,
+            limit.capacity,
+            limit.refill_per_sec
+          )
+          .fetch_optional(&self.db_pool)
+          .await?;
+        if taken.is_some() {
+          return Ok(None);
+        }
+        sqlx::query_scalar!(
+            r#"SELECT LEAST($2, tokens + EXTRACT(EPOCH FROM NOW() - updated_at)::DOUBLE PRECISION * $3) AS "tokens!"
+               FROM rate_limit_buckets WHERE bucket_key = $1"#,
+            bucket_key,
+            limit.capacity,
+            limit.refill_per_sec
+          )
+          .fetch_one(&self.db_pool)
+          .await?
+      }
+    };
+
+    Ok(Some(((1.0 - tokens) / limit.refill_per_sec).ceil().max(1.0) as u64))
+  }
+}
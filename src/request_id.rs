@@ -0,0 +1,46 @@
+//! Correlates a request's logs, error body and `x-request-id` response header. The id is taken from
+//! the client's `x-request-id` when it looks like one, and generated otherwise.
+
+use std::convert::Infallible;
+use tracing::Span;
+use uuid::Uuid;
+use warp::http::HeaderValue;
+use warp::hyper::service::Service;
+use warp::hyper::{Body, Request, Response};
+
+pub const HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+  static REQUEST_ID: String;
+}
+
+/// The id of the request being handled, or a fresh one outside of `handle`.
+pub fn current() -> String {
+  REQUEST_ID.try_with(Clone::clone).unwrap_or_else(|_| Uuid::new_v4().to_string())
+}
+
+fn incoming(request: &Request<Body>) -> Option<String> {
+  let id = request.headers().get(HEADER)?.to_str().ok()?;
+  let plausible = !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b));
+  plausible.then(|| id.to_string())
+}
+
+/// Runs `service` with the request's id assigned and echoes the id on the response. The id is also
+/// written back into the request headers, so filters (and `span`) see the one in use.
+pub async fn handle<S>(mut service: S, mut request: Request<Body>) -> Result<Response<Body>, Infallible>
+where
+  S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+{
+  let id = incoming(&request).unwrap_or_else(|| Uuid::new_v4().to_string());
+  let header = HeaderValue::from_str(&id).expect("request ids are visible ASCII");
+  request.headers_mut().insert(HEADER, header.clone());
+  let mut response = REQUEST_ID.scope(id, service.call(request)).await?;
+  response.headers_mut().insert(HEADER, header);
+  Ok(response)
+}
+
+/// The tracing span for a request, for `warp::trace`.
+pub fn span(info: warp::trace::Info) -> Span {
+  let id = info.request_headers().get(HEADER).and_then(|v| v.to_str().ok()).unwrap_or_default();
+  tracing::info_span!("request", method = %info.method(), path = info.path(), request_id = id)
+}
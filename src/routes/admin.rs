@@ -0,0 +1,140 @@
+use warp::Filter;
+use std::sync::Arc;
+use tracing::{error, info};
+use crate::auth::{Authenticator, Principal, with_principal, SCOPE_ADMIN};
+use crate::rate_limit::{RateLimitRule, RateLimiter};
+use crate::error::{ApiError, ErrorBody};
+use crate::sanitize::FieldPolicy;
+use crate::task_types::{TaskTypeRegistry, TaskTypeVersion};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+fn with_rate_limiter(rate_limiter: Arc<RateLimiter>) -> impl Filter<Extract = (Arc<RateLimiter>,), Error = std::convert::Infallible> + Clone {
+  warp::any().map(move || rate_limiter.clone())
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct NewTaskTypeVersion {
+  /// JSON Schema the payload must satisfy.
+  pub schema: serde_json::Value,
+  /// Defaults to the previous version's policies; pass `{}` to drop them.
+  #[schema(value_type = Option<std::collections::HashMap<String, FieldPolicy>>)]
+  pub field_policies: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RegisteredTaskType {
+  pub name: String,
+  pub version: i32,
+}
+
+fn with_task_types(task_types: Arc<TaskTypeRegistry>) -> impl Filter<Extract = (Arc<TaskTypeRegistry>,), Error = std::convert::Infallible> + Clone {
+  warp::any().map(move || task_types.clone())
+}
+
+pub fn admin_routes(rate_limiter: Arc<RateLimiter>, task_types: Arc<TaskTypeRegistry>, auth: Arc<Authenticator>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  let list_rate_limits = warp::path!("admin" / "rate_limits")
+    .and(warp::get())
+    .and(with_principal(auth.clone(), SCOPE_ADMIN))
+    .and(with_rate_limiter(rate_limiter.clone()))
+    .and_then(handle_list_rate_limits);
+
+  let set_rate_limit = warp::path!("admin" / "rate_limits")
+    .and(warp::put())
+    .and(with_principal(auth.clone(), SCOPE_ADMIN))
+    .and(warp::body::json())
+    .and(with_rate_limiter(rate_limiter))
+    .and_then(handle_set_rate_limit);
+
+  let list_task_types = warp::path!("admin" / "task_types")
+    .and(warp::get())
+    .and(with_principal(auth.clone(), SCOPE_ADMIN))
+    .and(with_task_types(task_types.clone()))
+    .and_then(handle_list_task_types);
+
+  let register_task_type = warp::path!("admin" / "task_types" / String)
+    .and(warp::post())
+    .and(with_principal(auth, SCOPE_ADMIN))
+    .and(warp::body::json())
+    .and(with_task_types(task_types))
+    .and_then(handle_register_task_type);
+
+  list_rate_limits
+    .or(set_rate_limit)
+    .or(list_task_types)
+    .or(register_task_type)
+}
+
+#[utoipa::path(
+  get,
+  path = "/admin/rate_limits",
+  tag = "admin",
+  responses((status = 200, description = "All rate limit rules", body = Vec<RateLimitRule>)),
+  security(("bearer" = ["admin"]))
+)]
+async fn handle_list_rate_limits(_principal: Principal, rate_limiter: Arc<RateLimiter>) -> Result<impl warp::Reply, warp::Rejection> {
+  Ok(warp::reply::json(&rate_limiter.list_rules().await))
+}
+
+#[utoipa::path(
+  put,
+  path = "/admin/rate_limits",
+  tag = "admin",
+  request_body = RateLimitRule,
+  responses(
+    (status = 200, description = "Rule stored; returns all rules", body = Vec<RateLimitRule>),
+    (status = 400, description = "Invalid limit", body = ErrorBody),
+  ),
+  security(("bearer" = ["admin"]))
+)]
+async fn handle_set_rate_limit(principal: Principal, rule: RateLimitRule, rate_limiter: Arc<RateLimiter>) -> Result<impl warp::Reply, warp::Rejection> {
+  if rule.limit.capacity < 1.0 || rule.limit.refill_per_sec <= 0.0 {
+    return Err(warp::reject::custom(ApiError::Validation(
+      "capacity must be at least 1 and refill_per_sec must be positive".into(),
+    )));
+  }
+  info!("{} is updating rate limit {}", principal.subject, rule.bucket_key);
+  rate_limiter.set_rule(rule).await.map_err(|e| {
+    error!("Failed to store rate limit rule: {:?}", e);
+    warp::reject::custom(ApiError::Database("Failed to store rate limit rule.".to_string()))
+  })?;
+  Ok(warp::reply::json(&rate_limiter.list_rules().await))
+}
+
+#[utoipa::path(
+  get,
+  path = "/admin/task_types",
+  tag = "admin",
+  responses((status = 200, description = "Every version of every task type", body = Vec<TaskTypeVersion>)),
+  security(("bearer" = ["admin"]))
+)]
+async fn handle_list_task_types(_principal: Principal, task_types: Arc<TaskTypeRegistry>) -> Result<impl warp::Reply, warp::Rejection> {
+  let versions = task_types.list().await.map_err(|e| {
+    error!("Failed to list task types: {:?}", e);
+    warp::reject::custom(ApiError::Database("Failed to list task types.".to_string()))
+  })?;
+  Ok(warp::reply::json(&versions))
+}
+
+#[utoipa::path(
+  post,
+  path = "/admin/task_types/{name}",
+  tag = "admin",
+  params(("name" = String, Path, description = "Task type name")),
+  request_body = NewTaskTypeVersion,
+  responses(
+    (status = 201, description = "New version registered", body = RegisteredTaskType),
+    (status = 400, description = "Invalid schema or field policies", body = ErrorBody),
+  ),
+  security(("bearer" = ["admin"]))
+)]
+async fn handle_register_task_type(name: String, principal: Principal, body: NewTaskTypeVersion, task_types: Arc<TaskTypeRegistry>) -> Result<impl warp::Reply, warp::Rejection> {
+  info!("{} is registering a new schema for task type {}", principal.subject, name);
+  let version = task_types.register(&name, body.schema, body.field_policies)
+    .await
+    .map_err(|e| warp::reject::custom(ApiError::from(e)))?;
+  Ok(warp::reply::with_status(
+    warp::reply::json(&RegisteredTaskType { name, version }),
+    warp::http::StatusCode::CREATED,
+  ))
+}
@@ -0,0 +1,95 @@
+use std::sync::Arc;
+use utoipa_swagger_ui::Config;
+use warp::Filter;
+use warp::http::Uri;
+use warp::path::{FullPath, Tail};
+use warp::Reply;
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use crate::error::{ApiError, ErrorBody, ErrorDetail};
+use crate::models::Task;
+use crate::quota::QuotaUsage;
+use crate::rate_limit::{Limit, RateLimitRule};
+use crate::sanitize::FieldPolicy;
+use crate::task_types::{FieldError, TaskTypeVersion};
+use super::{admin, quotas, sse, tasks};
+
+/// The OpenAPI document for every public route. `tests/openapi.rs` fails when the committed
+/// `openapi.json` no longer matches it.
+#[derive(OpenApi)]
+#[openapi(
+  info(title = "Distributed Task Queue API", description = "Submit tasks, follow their progress and manage per-tenant limits."),
+  paths(
+    tasks::handle_submit_task,
+    tasks::handle_get_task,
+    tasks::handle_list_tasks,
+    tasks::handle_cancel_task,
+    tasks::handle_retry_task,
+    sse::handle_sse,
+    quotas::handle_get_quotas,
+    admin::handle_list_rate_limits,
+    admin::handle_set_rate_limit,
+    admin::handle_list_task_types,
+    admin::handle_register_task_type,
+  ),
+  components(schemas(
+    tasks::NewTask,
+    tasks::TaskResponse,
+    Task,
+    sse::TaskEvent,
+    quotas::QuotasResponse,
+    QuotaUsage,
+    admin::NewTaskTypeVersion,
+    admin::RegisteredTaskType,
+    TaskTypeVersion,
+    FieldPolicy,
+    FieldError,
+    RateLimitRule,
+    Limit,
+    ErrorBody,
+    ErrorDetail,
+  )),
+  modifiers(&BearerAuth),
+  tags(
+    (name = "tasks", description = "Submit and follow tasks"),
+    (name = "admin", description = "Rate limits and task type registry; requires the admin scope"),
+  )
+)]
+pub struct ApiDoc;
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+  fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+    let components = openapi.components.get_or_insert_with(Default::default);
+    components.add_security_scheme("bearer", SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)));
+  }
+}
+
+/// Swagger UI, served from the copy vendored into `utoipa-swagger-ui` at build time, so the page
+/// needs no CDN.
+pub fn docs_routes() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  let config = Arc::new(Config::from("/openapi.json"));
+  let spec = warp::path("openapi.json")
+    .and(warp::get())
+    .map(|| warp::reply::json(&ApiDoc::openapi()));
+  let page = warp::path("docs")
+    .and(warp::get())
+    .and(warp::path::full())
+    .and(warp::path::tail())
+    .and(warp::any().map(move || config.clone()))
+    .and_then(handle_docs);
+  spec.or(page)
+}
+
+async fn handle_docs(full: FullPath, tail: Tail, config: Arc<Config<'static>>) -> Result<warp::reply::Response, warp::Rejection> {
+  // The page loads its assets by relative URL, so it must be served from `/docs/`.
+  if full.as_str() == "/docs" {
+    return Ok(warp::redirect::found(Uri::from_static("/docs/")).into_response());
+  }
+  match utoipa_swagger_ui::serve(tail.as_str(), config) {
+    Ok(Some(file)) => Ok(warp::reply::with_header(file.bytes.into_owned(), "content-type", file.content_type).into_response()),
+    Ok(None) => Err(warp::reject::not_found()),
+    Err(e) => Err(warp::reject::custom(ApiError::Internal(format!("Failed to serve Swagger UI: {}", e)))),
+  }
+}
@@ -1,14 +1,30 @@
 use warp::Filter;
 use sqlx::Pool;
 use sqlx::Postgres;
-use lapin::Channel;
+use std::sync::Arc;
+use crate::auth::Authenticator;
+use crate::config::Config;
+use crate::broker::Broker;
+use crate::rate_limit::RateLimiter;
+use crate::task_types::TaskTypeRegistry;
+pub mod admin;
+pub mod docs;
+pub mod quotas;
 pub mod tasks;
 pub mod sse;
 
 pub fn routes(
   db_pool: Pool<Postgres>,
-  rabbit_channel: Channel
+  broker: Arc<dyn Broker>,
+  auth: Arc<Authenticator>,
+  rate_limiter: Arc<RateLimiter>,
+  task_types: Arc<TaskTypeRegistry>,
+  config: Config
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-  tasks::submit_route(db_pool.clone(), rabbit_channel.clone())
-    .or(sse::sse_route(db_pool))
-}
\ No newline at end of file
+  tasks::submit_route(db_pool.clone(), broker.clone(), auth.clone(), rate_limiter.clone(), task_types.clone(), config.clone())
+    .or(tasks::task_routes(db_pool.clone(), broker, auth.clone(), config))
+    .or(sse::sse_route(db_pool.clone(), auth.clone()))
+    .or(quotas::quotas_route(db_pool, auth.clone()))
+    .or(admin::admin_routes(rate_limiter, task_types, auth))
+    .or(docs::docs_routes())
+}
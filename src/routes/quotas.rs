@@ -0,0 +1,49 @@
+use warp::Filter;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+use serde::Serialize;
+use utoipa::ToSchema;
+use tracing::error;
+use crate::auth::{Authenticator, Principal, with_principal, SCOPE_TASKS_READ};
+use crate::quota::{self, QuotaUsage};
+use crate::error::{ApiError, ErrorBody};
+
+fn with_db(db_pool: Pool<Postgres>) -> impl Filter<Extract = (Pool<Postgres>,), Error = std::convert::Infallible> + Clone {
+  warp::any().map(move || db_pool.clone())
+}
+
+pub fn quotas_route(db_pool: Pool<Postgres>, auth: Arc<Authenticator>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  warp::path("quotas")
+    .and(warp::get())
+    .and(with_principal(auth, SCOPE_TASKS_READ))
+    .and(with_db(db_pool))
+    .and_then(handle_get_quotas)
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct QuotasResponse {
+  pub tenant_id: String,
+  pub quotas: Vec<QuotaUsage>,
+}
+
+#[utoipa::path(
+  get,
+  path = "/quotas",
+  tag = "tasks",
+  responses(
+    (status = 200, description = "Usage against every quota that applies to the caller's tenant", body = QuotasResponse),
+    (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+  ),
+  security(("bearer" = ["tasks:read"]))
+)]
+async fn handle_get_quotas(principal: Principal, db_pool: Pool<Postgres>) -> Result<impl warp::Reply, warp::Rejection> {
+  let mut conn = db_pool.acquire().await.map_err(|e| {
+    error!("Failed to acquire connection: {:?}", e);
+    warp::reject::custom(ApiError::Database("Failed to load quotas.".to_string()))
+  })?;
+  let usage = quota::usage(&mut conn, &principal.tenant_id).await.map_err(|e| {
+    error!("Failed to load quotas for {}: {:?}", principal.tenant_id, e);
+    warp::reject::custom(ApiError::Database("Failed to load quotas.".to_string()))
+  })?;
+  Ok(warp::reply::json(&QuotasResponse { tenant_id: principal.tenant_id, quotas: usage }))
+}
@@ -3,49 +3,74 @@ use std::convert::Infallible;
 use std::time::Duration;
 use tokio_stream::{wrappers::IntervalStream, StreamExt};
 use sqlx::{Pool, Postgres};
-use serde_json::json;
+use uuid::Uuid;
+use tracing::error;
+use std::sync::Arc;
+use crate::error::{ApiError, ErrorBody};
+use crate::auth::{Authenticator, Principal, with_principal, SCOPE_TASKS_READ};
 
-#[derive(Debug)]
-struct CustomError {
-  message: String
-}
-impl warp::reject::Reject for CustomError {}
+pub use dtqs_types::TaskEvent;
 
 fn with_db(db_pool: Pool<Postgres>) -> impl Filter<Extract = (Pool<Postgres>,), Error = Infallible> + Clone {
   warp::any().map(move || db_pool.clone())
 }
 
-pub fn sse_route(db_pool: Pool<Postgres>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+pub fn sse_route(db_pool: Pool<Postgres>, auth: Arc<Authenticator>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
   warp::path("sse")
     .and(warp::get())
+    .and(with_principal(auth, SCOPE_TASKS_READ))
     .and(warp::query::<std::collections::HashMap<String, String>>())
     .and(with_db(db_pool))
     .and_then(handle_sse)
 }
 
-async fn handle_sse(query: std::collections::HashMap<String, String>, db_pool: Pool<Postgres>) -> Result<impl warp::Reply, warp::Rejection> {
-  let task_id = query.get("task_id").ok_or_else(|| warp::reject::custom(CustomError {message: "Missing task_id".to_string()}))?.clone();
+#[utoipa::path(
+  get,
+  path = "/sse",
+  tag = "tasks",
+  params(("task_id" = Uuid, Query, description = "Task to follow")),
+  responses(
+    (status = 200, description = "Stream of events once the task leaves `pending`", content_type = "text/event-stream", body = TaskEvent),
+    (status = 400, description = "Missing or malformed task_id", body = ErrorBody),
+    (status = 404, description = "Task not found for this tenant", body = ErrorBody),
+  ),
+  security(("bearer" = ["tasks:read"]))
+)]
+async fn handle_sse(principal: Principal, query: std::collections::HashMap<String, String>, db_pool: Pool<Postgres>) -> Result<impl warp::Reply, warp::Rejection> {
+  let task_id = query.get("task_id").ok_or_else(|| warp::reject::custom(ApiError::BadRequest("Missing task_id".to_string())))?.clone();
+  let task_uuid = Uuid::parse_str(&task_id)
+    .map_err(|_| warp::reject::custom(ApiError::BadRequest("task_id must be a UUID".to_string())))?;
+
+  let exists = sqlx::query_scalar!("SELECT 1 FROM tasks WHERE id = $1 AND tenant_id = $2", task_uuid, principal.tenant_id)
+    .fetch_optional(&db_pool)
+    .await
+    .map_err(|e| {
+      error!("Failed to look up task {}: {:?}", task_id, e);
+      warp::reject::custom(ApiError::Database("Failed to look up task.".to_string()))
+    })?;
+  if exists.is_none() {
+    return Err(warp::reject::custom(ApiError::NotFound(format!("Task {} not found", task_id))));
+  }
 
   let interval = IntervalStream::new(tokio::time::interval(Duration::from_secs(2)));
   let stream = interval.then(move |_| {
     let db_pool = db_pool.clone();
-    let task_id = task_id.clone();
+    let tenant_id = principal.tenant_id.clone();
     async move {
-      let row = sqlx::query!("SELECT status, progress FROM tasks WHERE id = $1", Uuid::parse_str(&task_id).unwrap())
+      let row = sqlx::query!("SELECT status, progress FROM tasks WHERE id = $1 AND tenant_id = $2", task_uuid, tenant_id)
         .fetch_optional(&db_pool)
         .await;
       match row {
         Ok(Some(record)) => {
           if record.status != "pending" {
-            let event = warp::sse::Event::default()
-              .data(json!({"task_id": task_id, "status": record.status, "progress": record.progress}).to_string());
-            return Some(Ok(event));
+            let event = TaskEvent { task_id: task_uuid, status: record.status, progress: record.progress };
+            return Some(warp::sse::Event::default().json_data(event));
           }
         },
         Ok(None) => {
         },
         Err(e) => {
-          println!("Error fetching task status: {:?}", e);
+          error!("Failed to fetch the status of task {}: {:?}", task_uuid, e);
         }
       }
       None
@@ -1,152 +1,472 @@
 use warp::{Filter};
-use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use chrono::Utc;
 use sqlx::Pool;
 use sqlx::Postgres;
-use lapin::Channel;
-use tracing::{info, error};
+use tracing::{info, error, info_span, Instrument};
+use crate::broker::Broker;
 use crate::messaging::publish_message;
-use regex::Regex;
+use crate::topology::TASK_QUEUE;
+use crate::config::Config;
+use crate::metrics::TASK_SUBMISSIONS;
+use crate::models::Task;
+use crate::rate_limit::RateLimiter;
+use crate::quota;
+use crate::task_types::TaskTypeRegistry;
+use crate::error::{ApiError, ErrorBody};
+use crate::auth::{Authenticator, Principal, with_principal, SCOPE_TASKS_READ, SCOPE_TASKS_WRITE};
 use std::format;
+use std::sync::Arc;
 
-#[derive(Deserialize)]
-pub struct NewTask {
-  pub task_type: String,
-  pub payload: serde_json::Value,
-  pub priority: Option<u8>,
-}
+pub use dtqs_types::{NewTask, TaskListQuery, TaskResponse, TERMINAL_STATUSES};
 
-#[derive(Serialize)]
-pub struct TaskResponse {
-  pub task_id: Uuid,
-  pub status: String,
-  pub sse_url: String,
+pub fn submit_route(db_pool: Pool<Postgres>, broker: Arc<dyn Broker>, auth: Arc<Authenticator>, rate_limiter: Arc<RateLimiter>, task_types: Arc<TaskTypeRegistry>, config: Config) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  warp::path("submit")
+    .and(warp::post())
+    .and(with_principal(auth, SCOPE_TASKS_WRITE))
+    .and(warp::body::json())
+    .and(with_db(db_pool))
+    .and(with_broker(broker))
+    .and(with_rate_limiter(rate_limiter))
+    .and(with_task_types(task_types))
+    .and(with_config(config))
+    .and_then(handle_submit_task)
 }
 
-#[derive(Debug)]
-struct CustomError {
-  message: String
+/// What workers receive for a task. `generation` is the task's retry generation: workers only claim
+/// a task with a message of its current generation, so a message left over from before a retry is
+/// skipped instead of running the task twice.
+struct TaskMessage<'a> {
+  task_id: Uuid,
+  generation: i32,
+  tenant_id: &'a str,
+  task_type: &'a str,
+  payload: &'a serde_json::Value,
+  priority: i32,
 }
-impl warp::reject::Reject for CustomError {}
 
-fn sanitize_input(input: &str) -> bool {
-  let re = Regex::new(r"^[\w\s.,@!?\-]+$").unwrap();
-  re.is_match(input)
-}
+async fn enqueue(broker: &dyn Broker, config: &Config, message: TaskMessage<'_>) -> Result<(), warp::Rejection> {
+  let TaskMessage { task_id, generation, tenant_id, task_type, payload, priority } = message;
+  let task_message = serde_json::json!({
+        "task_id": task_id.to_string(),
+        "generation": generation,
+        "tenant_id": tenant_id,
+        "task_type": task_type,
+        "payload": payload,
+        "priority": priority,
+        "enqueued_at": Utc::now().to_rfc3339(),
+    });
+
+  let payload_bytes = serde_json::to_vec(&task_message).map_err(|e| {
+    error!("Serialization failed: {:?}", e);
+    warp::reject::custom(ApiError::Internal("Serialization Failed.".to_string()))
+  })?;
 
-fn validate_payload(task_type: &str, payload: &serde_json::Value) -> Result<(), String> {
-  match task_type {
-    "email" => {
-      for field in &["from", "to", "subject", "content"] {
-        if let Some(val) = payload.get(*field) {
-          if !val.is_string() || !sanitize_input(val.as_str().unwrap()) {
-            return Err(format!("Invalid or unsafe value for field '{}'", field));
-          }
-        } else {
-          return Err(format!("Missing field '{}'", field));
-        }
-      }
-    },
-    "image" => {
-      if let Some(val) = payload.get("img_src") {
-        if !val.is_string() || !sanitize_input(val.as_str().unwrap()) {
-          return Err("Invalid or unsafe 'img_src'".into());
-        }
-      } else {
-        return Err("Missing 'img_src' field".into());
-      }
-      if payload.get("resize_factor").is_none() {
-        return Err("Missing 'resize_factor' field".into());
-      }
-    },
-    "video" => {
-      if let Some(val) = payload.get("vid_src") {
-        if !val.is_string() || !sanitize_input(val.as_str().unwrap()) {
-          return Err("Invalid or unsafe 'vid_src'".into());
-        }
-      } else {
-        return Err("Missing 'vid_src' field".into());
-      }
-      if payload.get("resize_factor").is_none() {
-        return Err("Missing 'resize_factor' field".into());
-      }
-    },
-    _ => return Err("Unsupported task type".into()),
+  let queue = config.routing().queue(tenant_id, task_type);
+  if queue != TASK_QUEUE {
+    broker.declare_queue(&queue)
+      .await
+      .map_err(|e| {
+        error!("Failed to declare queue {}: {:?}", queue, e);
+        warp::reject::custom(ApiError::Publish("An error occurred when publishing task.".to_string()))
+      })?;
   }
-  Ok(())
+
+  publish_message(broker, &queue, &payload_bytes, priority)
+    .await
+    .map_err(|e| {
+      error!("Failed to publish task {}: {:?}", task_id, e);
+      warp::reject::custom(ApiError::Publish("An error occurred when publishing task.".to_string()))
+    })
 }
 
-pub fn submit_route(db_pool: Pool<Postgres>, rabbit_channel: Channel) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-  warp::path("submit")
+pub fn task_routes(db_pool: Pool<Postgres>, broker: Arc<dyn Broker>, auth: Arc<Authenticator>, config: Config) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  let get_task = warp::path!("tasks" / Uuid)
+    .and(warp::get())
+    .and(with_principal(auth.clone(), SCOPE_TASKS_READ))
+    .and(with_db(db_pool.clone()))
+    .and_then(handle_get_task);
+
+  let list_tasks = warp::path!("tasks")
+    .and(warp::get())
+    .and(with_principal(auth.clone(), SCOPE_TASKS_READ))
+    .and(warp::query::<TaskListQuery>())
+    .and(with_db(db_pool.clone()))
+    .and_then(handle_list_tasks);
+
+  let cancel_task = warp::path!("tasks" / Uuid / "cancel")
     .and(warp::post())
-    .and(warp::body::json())
+    .and(with_principal(auth.clone(), SCOPE_TASKS_WRITE))
+    .and(with_db(db_pool.clone()))
+    .and_then(handle_cancel_task);
+
+  let retry_task = warp::path!("tasks" / Uuid / "retry")
+    .and(warp::post())
+    .and(with_principal(auth, SCOPE_TASKS_WRITE))
     .and(with_db(db_pool))
-    .and(with_channel(rabbit_channel))
-    .and_then(handle_submit_task)
+    .and(with_broker(broker))
+    .and(with_config(config))
+    .and_then(handle_retry_task);
+
+  get_task.or(list_tasks).or(cancel_task).or(retry_task)
 }
 
 fn with_db(db_pool: Pool<Postgres>) -> impl Filter<Extract = (Pool<Postgres>,), Error = std::convert::Infallible> + Clone {
   warp::any().map(move || db_pool.clone())
 }
 
-fn with_channel(channel: Channel) -> impl Filter<Extract = (Channel,), Error = std::convert::Infallible> + Clone {
-  warp::any().map(move || channel.clone())
+fn with_broker(broker: Arc<dyn Broker>) -> impl Filter<Extract = (Arc<dyn Broker>,), Error = std::convert::Infallible> + Clone {
+  warp::any().map(move || broker.clone())
 }
 
-async fn handle_submit_task(new_task: NewTask, db_pool: Pool<Postgres>, channel: Channel) -> Result<impl warp::Reply, warp::Rejection> {
-  if let Err(e) = validate_payload(&new_task.task_type, &new_task.payload) {
-    error!("Payload validation failed: {}", e);
-    return Err(warp::reject::custom(CustomError {message: e}));
-  }
+fn with_rate_limiter(rate_limiter: Arc<RateLimiter>) -> impl Filter<Extract = (Arc<RateLimiter>,), Error = std::convert::Infallible> + Clone {
+  warp::any().map(move || rate_limiter.clone())
+}
+
+fn with_task_types(task_types: Arc<TaskTypeRegistry>) -> impl Filter<Extract = (Arc<TaskTypeRegistry>,), Error = std::convert::Infallible> + Clone {
+  warp::any().map(move || task_types.clone())
+}
+
+fn with_config(config: Config) -> impl Filter<Extract = (Config,), Error = std::convert::Infallible> + Clone {
+  warp::any().map(move || config.clone())
+}
+
+#[utoipa::path(
+  post,
+  path = "/submit",
+  tag = "tasks",
+  request_body = NewTask,
+  responses(
+    (status = 200, description = "Task stored and queued", body = TaskResponse),
+    (status = 400, description = "Payload failed validation", body = ErrorBody),
+    (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+    (status = 403, description = "Missing the tasks:write scope", body = ErrorBody),
+    (status = 429, description = "Rate limit or quota exceeded", body = ErrorBody),
+    (status = 503, description = "Task could not be published and was marked failed", body = ErrorBody),
+  ),
+  security(("bearer" = ["tasks:write"]))
+)]
+#[tracing::instrument(name = "submit_task", skip_all, fields(task_type = %new_task.task_type, tenant_id = %principal.tenant_id, task_id = tracing::field::Empty))]
+async fn handle_submit_task(principal: Principal, new_task: NewTask, db_pool: Pool<Postgres>, broker: Arc<dyn Broker>, rate_limiter: Arc<RateLimiter>, task_types: Arc<TaskTypeRegistry>, config: Config) -> Result<impl warp::Reply, warp::Rejection> {
+  // Unregistered names are caller input, so they share one label instead of each creating a series.
+  let task_type_label = if task_types.contains(&new_task.task_type).await { new_task.task_type.clone() } else { "unknown".to_string() };
+  let result = submit_task(principal, new_task, db_pool, broker, rate_limiter, task_types, config).await;
+  let outcome = match &result {
+    Ok(_) => "accepted",
+    Err(rejection) => rejection.find::<ApiError>().map(ApiError::code).unwrap_or("internal_error"),
+  };
+  TASK_SUBMISSIONS.with_label_values(&[&task_type_label, outcome]).inc();
+  result.map(|response| warp::reply::json(&response))
+}
+
+async fn submit_task(principal: Principal, mut new_task: NewTask, db_pool: Pool<Postgres>, broker: Arc<dyn Broker>, rate_limiter: Arc<RateLimiter>, task_types: Arc<TaskTypeRegistry>, config: Config) -> Result<TaskResponse, warp::Rejection> {
+  let task_type_version = task_types.validate(&new_task.task_type, new_task.schema_version, &mut new_task.payload)
+    .instrument(info_span!("validate_payload"))
+    .await
+    .map_err(|e| {
+      error!("Payload validation failed: {:?}", e);
+      warp::reject::custom(ApiError::from(e))
+    })?;
+
+  rate_limiter.check_submission(&principal, &new_task.task_type)
+    .instrument(info_span!("check_rate_limit"))
+    .await
+    .map_err(|e| {
+      info!("Rejecting task from {}: rate limit {} exceeded", principal.subject, e.bucket_key);
+      warp::reject::custom(ApiError::from(e))
+    })?;
 
   let task_id = Uuid::new_v4();
-  let now = Utc::now().naive_utc();
+  tracing::Span::current().record("task_id", tracing::field::display(task_id));
+  let now = Utc::now();
   let status = "pending";
   let priority = new_task.priority.unwrap_or(5) as i32;
 
+  let mut tx = db_pool.begin().await.map_err(|e| {
+    error!("Failed to start transaction: {:?}", e);
+    warp::reject::custom(ApiError::Database("Failed to store task.".to_string()))
+  })?;
+
+  let payload_size = quota::payload_bytes(&mut tx, &new_task.payload)
+    .await
+    .map_err(|e| {
+      error!("Failed to measure payload: {:?}", e);
+      warp::reject::custom(ApiError::Database("Failed to check quota.".to_string()))
+    })?;
+  let exceeded = quota::check(&mut tx, &principal.tenant_id, &new_task.task_type, payload_size)
+    .await
+    .map_err(|e| {
+      error!("Quota check failed: {:?}", e);
+      warp::reject::custom(ApiError::Database("Failed to check quota.".to_string()))
+    })?;
+  if let Some(exceeded) = exceeded {
+    info!("Rejecting task from {}: {}", principal.subject, exceeded.message);
+    return Err(warp::reject::custom(ApiError::from(exceeded)));
+  }
+
   sqlx::query!(
-        "INSERT INTO tasks (id, task_type, payload, status, priority, progress, attempts, created_at, updated_at)
-         VALUES ($1, $2, $3, $4, $5, 0, 0, $6, $6)",
+        "INSERT INTO tasks (id, tenant_id, task_type, task_type_version, payload, status, priority, progress, attempts, created_at, updated_at)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, 0, 0, $8, $8)",
         task_id,
+        principal.tenant_id,
         new_task.task_type,
+        task_type_version,
         new_task.payload,
         status,
         priority,
         now
     )
-    .execute(&db_pool)
+    .execute(&mut *tx)
     .await
     .map_err(|e| {
       error!("DB insertion failed: {:?}", e);
-      warp::reject::custom(e)
+      warp::reject::custom(ApiError::Database("Failed to store task.".to_string()))
     })?;
 
-  let task_message = serde_json::json!({
-        "task_id": task_id.to_string(),
-        "task_type": new_task.task_type,
-        "payload": new_task.payload,
-        "priority": priority,
-    });
+  tx.commit().await.map_err(|e| {
+    error!("Failed to commit task {}: {:?}", task_id, e);
+    warp::reject::custom(ApiError::Database("Failed to store task.".to_string()))
+  })?;
 
-  let payload_bytes = serde_json::to_vec(&task_message).map_err(|e| {
-    error!("Serialization failed: {:?}", e);
-    warp::reject::custom(CustomError {message: "Serialization Failed.".to_string()})
+  // The row is committed first because the postgres broker publishes by updating it. A task that
+  // cannot be published is marked failed, so it neither lingers as pending nor counts against
+  // quotas, and can be retried.
+  let message = TaskMessage {
+    task_id,
+    generation: 0,
+    tenant_id: &principal.tenant_id,
+    task_type: &new_task.task_type,
+    payload: &new_task.payload,
+    priority,
+  };
+  if let Err(rejection) = enqueue(broker.as_ref(), &config, message).await {
+    if let Err(e) = sqlx::query!("UPDATE tasks SET status = 'failed', updated_at = NOW() WHERE id = $1", task_id).execute(&db_pool).await {
+      error!("Failed to mark unpublished task {} failed: {:?}", task_id, e);
+    }
+    return Err(rejection);
+  }
+
+  info!("Task {} submitted successfully by {}", task_id, principal.subject);
+  let response = TaskResponse {
+    task_id,
+    status: "submitted".into(),
+    sse_url: format!("/sse?task_id={}", task_id),
+  };
+
+  Ok(response)
+}
+
+#[utoipa::path(
+  get,
+  path = "/tasks/{task_id}",
+  tag = "tasks",
+  params(("task_id" = Uuid, Path, description = "Task to fetch")),
+  responses(
+    (status = 200, description = "The task", body = Task),
+    (status = 404, description = "Task not found for this tenant", body = ErrorBody),
+  ),
+  security(("bearer" = ["tasks:read"]))
+)]
+async fn handle_get_task(task_id: Uuid, principal: Principal, db_pool: Pool<Postgres>) -> Result<impl warp::Reply, warp::Rejection> {
+  let task = sqlx::query_as!(
+        Task,
+        "SELECT id, tenant_id, task_type, task_type_version, payload, status, priority, progress, attempts, created_at, updated_at
+         FROM tasks WHERE id = $1 AND tenant_id = $2",
+        task_id,
+        principal.tenant_id
+    )
+    .fetch_optional(&db_pool)
+    .await
+    .map_err(|e| {
+      error!("Failed to fetch task {}: {:?}", task_id, e);
+      warp::reject::custom(ApiError::Database("Failed to fetch task.".to_string()))
+    })?
+    .ok_or_else(|| warp::reject::custom(ApiError::NotFound(format!("Task {} not found", task_id))))?;
+  Ok(warp::reply::json(&task))
+}
+
+#[utoipa::path(
+  get,
+  path = "/tasks",
+  tag = "tasks",
+  params(TaskListQuery),
+  responses(
+    (status = 200, description = "The caller's tasks, newest first", body = Vec<Task>),
+    (status = 400, description = "Invalid limit or offset", body = ErrorBody),
+  ),
+  security(("bearer" = ["tasks:read"]))
+)]
+async fn handle_list_tasks(principal: Principal, query: TaskListQuery, db_pool: Pool<Postgres>) -> Result<impl warp::Reply, warp::Rejection> {
+  let limit = query.limit.unwrap_or(50);
+  let offset = query.offset.unwrap_or(0);
+  if !(1..=500).contains(&limit) || offset < 0 {
+    return Err(warp::reject::custom(ApiError::BadRequest("limit must be between 1 and 500 and offset must not be negative".to_string())));
+  }
+  let tasks = sqlx::query_as!(
+        Task,
+        "SELECT id, tenant_id, task_type, task_type_version, payload, status, priority, progress, attempts, created_at, updated_at
+         FROM tasks
+         WHERE tenant_id = $1 AND ($2::VARCHAR IS NULL OR status = $2) AND ($3::VARCHAR IS NULL OR task_type = $3)
+         ORDER BY created_at DESC
+         LIMIT $4 OFFSET $5",
+        principal.tenant_id,
+        query.status,
+        query.task_type,
+        limit,
+        offset
+    )
+    .fetch_all(&db_pool)
+    .await
+    .map_err(|e| {
+      error!("Failed to list tasks: {:?}", e);
+      warp::reject::custom(ApiError::Database("Failed to list tasks.".to_string()))
+    })?;
+  Ok(warp::reply::json(&tasks))
+}
+
+/// Only tasks that have not finished can be cancelled. A worker that picks up a cancelled task
+/// acknowledges it without processing, and one already running it does not overwrite the status.
+#[utoipa::path(
+  post,
+  path = "/tasks/{task_id}/cancel",
+  tag = "tasks",
+  params(("task_id" = Uuid, Path, description = "Task to cancel")),
+  responses(
+    (status = 200, description = "The cancelled task", body = Task),
+    (status = 404, description = "Task not found for this tenant", body = ErrorBody),
+    (status = 409, description = "Task has already finished", body = ErrorBody),
+  ),
+  security(("bearer" = ["tasks:write"]))
+)]
+async fn handle_cancel_task(task_id: Uuid, principal: Principal, db_pool: Pool<Postgres>) -> Result<impl warp::Reply, warp::Rejection> {
+  let cancelled = sqlx::query_as!(
+        Task,
+        "UPDATE tasks SET status = 'cancelled', updated_at = NOW()
+         WHERE id = $1 AND tenant_id = $2 AND status IN ('pending', 'in_progress')
+         RETURNING id, tenant_id, task_type, task_type_version, payload, status, priority, progress, attempts, created_at, updated_at",
+        task_id,
+        principal.tenant_id
+    )
+    .fetch_optional(&db_pool)
+    .await
+    .map_err(|e| {
+      error!("Failed to cancel task {}: {:?}", task_id, e);
+      warp::reject::custom(ApiError::Database("Failed to cancel task.".to_string()))
+    })?;
+  if let Some(task) = cancelled {
+    info!("Task {} cancelled by {}", task_id, principal.subject);
+    return Ok(warp::reply::json(&task));
+  }
+
+  let status = sqlx::query_scalar!("SELECT status FROM tasks WHERE id = $1 AND tenant_id = $2", task_id, principal.tenant_id)
+    .fetch_optional(&db_pool)
+    .await
+    .map_err(|e| {
+      error!("Failed to look up task {}: {:?}", task_id, e);
+      warp::reject::custom(ApiError::Database("Failed to cancel task.".to_string()))
+    })?;
+  match status {
+    Some(status) => Err(warp::reject::custom(ApiError::Conflict(format!("Task {} is already {}", task_id, status)))),
+    None => Err(warp::reject::custom(ApiError::NotFound(format!("Task {} not found", task_id)))),
+  }
+}
+
+/// Puts a `failed` or `cancelled` task back on the queue with its attempts reset. The task counts
+/// against the tenant's quotas again, so a retry can be rejected like a new submission.
+#[utoipa::path(
+  post,
+  path = "/tasks/{task_id}/retry",
+  tag = "tasks",
+  params(("task_id" = Uuid, Path, description = "Task to retry")),
+  responses(
+    (status = 200, description = "The requeued task", body = Task),
+    (status = 404, description = "Task not found for this tenant", body = ErrorBody),
+    (status = 409, description = "Task is neither failed nor cancelled", body = ErrorBody),
+    (status = 429, description = "Quota exceeded", body = ErrorBody),
+    (status = 503, description = "Task could not be published and kept its previous status", body = ErrorBody),
+  ),
+  security(("bearer" = ["tasks:write"]))
+)]
+async fn handle_retry_task(task_id: Uuid, principal: Principal, db_pool: Pool<Postgres>, broker: Arc<dyn Broker>, config: Config) -> Result<impl warp::Reply, warp::Rejection> {
+  let mut tx = db_pool.begin().await.map_err(|e| {
+    error!("Failed to start transaction: {:?}", e);
+    warp::reject::custom(ApiError::Database("Failed to retry task.".to_string()))
   })?;
 
-  publish_message(&channel, "task_queue", &payload_bytes)
+  let current = sqlx::query!(
+        "SELECT task_type, status, generation FROM tasks WHERE id = $1 AND tenant_id = $2 FOR UPDATE",
+        task_id,
+        principal.tenant_id
+    )
+    .fetch_optional(&mut *tx)
     .await
     .map_err(|e| {
-      error!("Failed to publish task {}: {:?}", task_id, e);
-      warp::reject::custom(CustomError {message: "An error occurred when publishing task.".to_string()})
+      error!("Failed to look up task {}: {:?}", task_id, e);
+      warp::reject::custom(ApiError::Database("Failed to retry task.".to_string()))
+    })?
+    .ok_or_else(|| warp::reject::custom(ApiError::NotFound(format!("Task {} not found", task_id))))?;
+  if current.status != "failed" && current.status != "cancelled" {
+    return Err(warp::reject::custom(ApiError::Conflict(format!("Task {} is {}; only failed or cancelled tasks can be retried", task_id, current.status))));
+  }
+
+  // The payload is already stored, so a retry only adds to the outstanding count.
+  let exceeded = quota::check(&mut tx, &principal.tenant_id, &current.task_type, 0)
+    .await
+    .map_err(|e| {
+      error!("Quota check failed: {:?}", e);
+      warp::reject::custom(ApiError::Database("Failed to check quota.".to_string()))
     })?;
+  if let Some(exceeded) = exceeded {
+    info!("Rejecting retry of {} from {}: {}", task_id, principal.subject, exceeded.message);
+    return Err(warp::reject::custom(ApiError::from(exceeded)));
+  }
 
-  info!("Task {} submitted successfully", task_id);
-  let response = TaskResponse {
+  let generation = current.generation + 1;
+  let task = sqlx::query_as!(
+        Task,
+        "UPDATE tasks SET status = 'pending', progress = 0, attempts = 0, generation = $2, updated_at = NOW()
+         WHERE id = $1
+         RETURNING id, tenant_id, task_type, task_type_version, payload, status, priority, progress, attempts, created_at, updated_at",
+        task_id,
+        generation
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| {
+      error!("Failed to reset task {}: {:?}", task_id, e);
+      warp::reject::custom(ApiError::Database("Failed to retry task.".to_string()))
+    })?;
+
+  tx.commit().await.map_err(|e| {
+    error!("Failed to commit retry of task {}: {:?}", task_id, e);
+    warp::reject::custom(ApiError::Database("Failed to retry task.".to_string()))
+  })?;
+
+  // Committed before publishing for the same reason as in `submit_task`. If publishing fails the
+  // task goes back to its previous status, so it can be retried again.
+  let message = TaskMessage {
     task_id,
-    status: "submitted".into(),
-    sse_url: format!("/sse?task_id={}", task_id),
+    generation,
+    tenant_id: &task.tenant_id,
+    task_type: &task.task_type,
+    payload: &task.payload,
+    priority: task.priority,
   };
+  if let Err(rejection) = enqueue(broker.as_ref(), &config, message).await {
+    let restored = sqlx::query!(
+        "UPDATE tasks SET status = $3, updated_at = NOW() WHERE id = $1 AND generation = $2 AND status = 'pending'",
+        task_id,
+        generation,
+        current.status
+      )
+      .execute(&db_pool)
+      .await;
+    if let Err(e) = restored {
+      error!("Failed to restore status of unpublished retry of task {}: {:?}", task_id, e);
+    }
+    return Err(rejection);
+  }
 
-  Ok(warp::reply::json(&response))
+  info!("Task {} requeued by {}", task_id, principal.subject);
+  Ok(warp::reply::json(&task))
 }
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::path::{Component, Path};
+use std::sync::LazyLock;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use url::Url;
+use utoipa::ToSchema;
+use crate::task_types::FieldError;
+
+static EMAIL_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[^\s@<>]+@[^\s@<>]+\.[^\s@<>]+$").unwrap());
+/// Parses text as HTML and keeps only its text; `script` and `style` contents go as well. The result
+/// stays escaped for HTML, so `a & b` is stored as `a &amp; b`; only fields rendered as HTML opt in.
+static HTML_STRIPPER: LazyLock<ammonia::Builder<'static>> = LazyLock::new(ammonia::Builder::empty);
+
+/// How one payload field is checked before a task is accepted. Policies are keyed by field
+/// name, or by a JSON pointer such as `/attachment/url` for nested fields.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FieldPolicy {
+  Email {
+    max_length: Option<usize>,
+  },
+  Url {
+    #[serde(default = "default_url_schemes")]
+    schemes: Vec<String>,
+    /// Exact host names; an entry starting with `.` also matches any subdomain.
+    hosts: Option<Vec<String>>,
+    max_length: Option<usize>,
+  },
+  Path {
+    root: String,
+    max_length: Option<usize>,
+  },
+  Text {
+    max_length: Option<usize>,
+    #[serde(default)]
+    strip_html: bool,
+  },
+}
+
+fn default_url_schemes() -> Vec<String> {
+  vec!["https".to_string()]
+}
+
+pub type FieldPolicies = HashMap<String, FieldPolicy>;
+
+/// Checks every field that has a policy, rewriting values in place where the policy cleans them
+/// (HTML stripping). Fields absent from the payload are left to the schema's `required` list.
+pub fn apply(policies: &FieldPolicies, payload: &mut Value) -> Result<(), Vec<FieldError>> {
+  let mut errors = Vec::new();
+  for (field, policy) in policies {
+    let pointer = if field.starts_with('/') { field.clone() } else { format!("/{}", field) };
+    let Some(value) = payload.pointer_mut(&pointer) else { continue };
+    let Some(text) = value.as_str() else {
+      errors.push(FieldError { path: pointer, message: "must be a string".to_string() });
+      continue;
+    };
+    match policy.check(text) {
+      Ok(Some(cleaned)) => *value = Value::String(cleaned),
+      Ok(None) => {}
+      Err(message) => errors.push(FieldError { path: pointer, message }),
+    }
+  }
+  if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+impl FieldPolicy {
+  /// Returns the cleaned value when it differs from the input.
+  fn check(&self, text: &str) -> Result<Option<String>, String> {
+    match self {
+      FieldPolicy::Email { max_length } => {
+        check_length(text, max_length.or(Some(254)))?;
+        if !EMAIL_RE.is_match(text) {
+          return Err("must be an email address".to_string());
+        }
+        Ok(None)
+      }
+      FieldPolicy::Url { schemes, hosts, max_length } => {
+        check_length(text, max_length.or(Some(2048)))?;
+        let url = Url::parse(text).map_err(|e| format!("must be a URL ({})", e))?;
+        if !schemes.iter().any(|s| s == url.scheme()) {
+          return Err(format!("URL scheme must be one of {}", schemes.join(", ")));
+        }
+        if let Some(hosts) = hosts {
+          let host = url.host_str().unwrap_or_default();
+          let allowed = hosts.iter().any(|allowed| match allowed.strip_prefix('.') {
+            Some(domain) => host == domain || host.ends_with(allowed.as_str()),
+            None => host == allowed,
+          });
+          if !allowed {
+            return Err(format!("URL host '{}' is not allowed", host));
+          }
+        }
+        Ok(None)
+      }
+      FieldPolicy::Path { root, max_length } => {
+        check_length(text, *max_length)?;
+        let path = Path::new(text);
+        if path.components().any(|c| matches!(c, Component::ParentDir)) {
+          return Err("path must not contain '..'".to_string());
+        }
+        let full = Path::new(root).join(path);
+        if !full.starts_with(root) {
+          return Err(format!("path must be under {}", root));
+        }
+        Ok(None)
+      }
+      FieldPolicy::Text { max_length, strip_html } => {
+        let cleaned = if *strip_html { HTML_STRIPPER.clean(text).to_string() } else { text.to_string() };
+        if cleaned.chars().any(|c| c.is_control() && c != '\n' && c != '\r' && c != '\t') {
+          return Err("must not contain control characters".to_string());
+        }
+        check_length(&cleaned, *max_length)?;
+        Ok(if cleaned != text { Some(cleaned) } else { None })
+      }
+    }
+  }
+}
+
+fn check_length(text: &str, max_length: Option<usize>) -> Result<(), String> {
+  match max_length {
+    Some(max) if text.chars().count() > max => Err(format!("must be at most {} characters", max)),
+    _ => Ok(()),
+  }
+}
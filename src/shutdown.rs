@@ -0,0 +1,22 @@
+use tracing::info;
+
+/// Resolves on SIGTERM (what Kubernetes sends before killing a pod) or Ctrl-C.
+pub async fn signal() {
+  let ctrl_c = async {
+    tokio::signal::ctrl_c().await.expect("Failed to listen for Ctrl-C");
+  };
+  #[cfg(unix)]
+  let terminate = async {
+    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+      .expect("Failed to listen for SIGTERM")
+      .recv()
+      .await;
+  };
+  #[cfg(not(unix))]
+  let terminate = std::future::pending::<()>();
+
+  tokio::select! {
+    _ = ctrl_c => info!("Received Ctrl-C"),
+    _ = terminate => info!("Received SIGTERM"),
+  }
+}
@@ -0,0 +1,180 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use jsonschema::Validator;
+use serde::Serialize;
+use utoipa::ToSchema;
+use serde_json::{json, Value};
+use sqlx::{Pool, Postgres};
+use tokio::sync::RwLock;
+use tokio::time::Duration;
+use tracing::{error, info};
+use crate::sanitize::{self, FieldPolicies};
+
+pub use dtqs_types::FieldError;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TaskTypeVersion {
+  pub name: String,
+  pub version: i32,
+  pub schema: Value,
+  pub field_policies: Value,
+  pub created_at: DateTime<Utc>,
+}
+
+struct CompiledTaskType {
+  validator: Validator,
+  field_policies: FieldPolicies,
+}
+
+#[derive(Debug)]
+pub enum TaskTypeError {
+  UnknownType(String),
+  UnknownVersion(String, i32),
+  InvalidPayload(Vec<FieldError>),
+  InvalidSchema(String),
+  Database(anyhow::Error),
+}
+
+/// Compiled JSON Schemas and field policies for every registered task type, keyed by name and then version.
+pub struct TaskTypeRegistry {
+  db_pool: Pool<Postgres>,
+  task_types: RwLock<HashMap<String, BTreeMap<i32, Arc<CompiledTaskType>>>>,
+}
+
+impl TaskTypeRegistry {
+  pub fn new(db_pool: Pool<Postgres>) -> Self {
+    Self { db_pool, task_types: RwLock::new(HashMap::new()) }
+  }
+
+  pub async fn reload(&self) -> Result<()> {
+    let rows = sqlx::query!("SELECT name, version, schema, field_policies FROM task_types")
+      .fetch_all(&self.db_pool)
+      .await?;
+    let mut task_types: HashMap<String, BTreeMap<i32, Arc<CompiledTaskType>>> = HashMap::new();
+    for row in rows {
+      match compile(&row.schema, row.field_policies) {
+        Ok(compiled) => {
+          task_types.entry(row.name).or_default().insert(row.version, Arc::new(compiled));
+        }
+        Err(e) => error!("Skipping invalid task type {} v{}: {:?}", row.name, row.version, e),
+      }
+    }
+    *self.task_types.write().await = task_types;
+    Ok(())
+  }
+
+  /// New versions may be registered through any API replica, so every replica re-reads the table periodically.
+  pub fn spawn_refresh(self: Arc<Self>, every: Duration) {
+    tokio::spawn(async move {
+      let mut interval = tokio::time::interval(every);
+      loop {
+        interval.tick().await;
+        if let Err(e) = self.reload().await {
+          error!("Failed to reload task types: {:?}", e);
+        }
+      }
+    });
+  }
+
+  pub async fn contains(&self, name: &str) -> bool {
+    self.task_types.read().await.contains_key(name)
+  }
+
+  pub async fn list(&self) -> Result<Vec<TaskTypeVersion>> {
+    let rows = sqlx::query_as!(
+        TaskTypeVersion,
+        "SELECT name, version, schema, field_policies, created_at FROM task_types ORDER BY name, version"
+      )
+      .fetch_all(&self.db_pool)
+      .await?;
+    Ok(rows)
+  }
+
+  /// Stores `schema` and `field_policies` as the next version of `name` and returns that version number.
+  /// Without `field_policies` the previous version's policies carry over, so a schema change cannot
+  /// silently drop them. Registrations of the same name are serialised by an advisory lock, so
+  /// concurrent ones through different replicas get consecutive versions instead of colliding on
+  /// `MAX(version) + 1`.
+  pub async fn register(&self, name: &str, schema: Value, field_policies: Option<Value>) -> Result<i32, TaskTypeError> {
+    let database = |e: sqlx::Error| TaskTypeError::Database(e.into());
+    let mut tx = self.db_pool.begin().await.map_err(database)?;
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext('task_types:' || $1))")
+      .bind(name)
+      .execute(&mut *tx)
+      .await
+      .map_err(database)?;
+    let field_policies = match field_policies {
+      Some(field_policies) => field_policies,
+      None => sqlx::query_scalar!("SELECT field_policies FROM task_types WHERE name = $1 ORDER BY version DESC LIMIT 1", name)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(database)?
+        .unwrap_or_else(|| json!({})),
+    };
+    let compiled = compile(&schema, field_policies.clone())?;
+    let version = sqlx::query_scalar!(
+        r#"
+        INSERT INTO task_types (name, version, schema, field_policies)
+        SELECT $1::VARCHAR, COALESCE(MAX(version), 0) + 1, $2, $3 FROM task_types WHERE name = $1::VARCHAR
+        RETURNING version
+        "#,
+        name,
+        schema,
+        field_policies
+      )
+      .fetch_one(&mut *tx)
+      .await
+      .map_err(database)?;
+    tx.commit().await.map_err(database)?;
+    info!("Registered task type {} v{}", name, version);
+    self.task_types.write().await
+      .entry(name.to_string())
+      .or_default()
+      .insert(version, Arc::new(compiled));
+    Ok(version)
+  }
+
+  /// Validates `payload` against the requested schema version, or the latest one, then applies that
+  /// version's field policies, which may clean values in place. Returns the version used.
+  pub async fn validate(&self, name: &str, version: Option<i32>, payload: &mut Value) -> Result<i32, TaskTypeError> {
+    let (version, task_type) = {
+      let task_types = self.task_types.read().await;
+      let versions = task_types.get(name).ok_or_else(|| TaskTypeError::UnknownType(name.to_string()))?;
+      match version {
+        Some(v) => versions.get(&v).map(|task_type| (v, task_type.clone())),
+        None => versions.last_key_value().map(|(v, task_type)| (*v, task_type.clone())),
+      }
+      .ok_or_else(|| TaskTypeError::UnknownVersion(name.to_string(), version.unwrap_or_default()))?
+    };
+
+    let errors: Vec<FieldError> = task_type.validator
+      .iter_errors(payload)
+      .map(|e| FieldError {
+        path: e.instance_path.to_string(),
+        message: e.to_string(),
+      })
+      .collect();
+    if !errors.is_empty() {
+      return Err(TaskTypeError::InvalidPayload(errors));
+    }
+    sanitize::apply(&task_type.field_policies, payload).map_err(TaskTypeError::InvalidPayload)?;
+    Ok(version)
+  }
+}
+
+/// Every registered task type name, for tools that list the queues tasks are routed to.
+pub async fn names(db_pool: &Pool<Postgres>) -> Result<Vec<String>> {
+  let names = sqlx::query_scalar!(r#"SELECT DISTINCT name AS "name!" FROM task_types ORDER BY name"#)
+    .fetch_all(db_pool)
+    .await?;
+  Ok(names)
+}
+
+fn compile(schema: &Value, field_policies: Value) -> Result<CompiledTaskType, TaskTypeError> {
+  let validator = jsonschema::validator_for(schema).map_err(|e| TaskTypeError::InvalidSchema(e.to_string()))?;
+  let field_policies = serde_json::from_value(field_policies)
+    .map_err(|e| TaskTypeError::InvalidSchema(format!("invalid field policies: {}", e)))?;
+  Ok(CompiledTaskType { validator, field_policies })
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use std::env;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry::{global, Context};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{SdkTracerProvider, SpanExporter};
+use opentelemetry_sdk::Resource;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Installs the global `tracing` subscriber and W3C trace-context propagator. Spans are exported
+/// over OTLP/HTTP when `OTEL_EXPORTER_OTLP_ENDPOINT` is set; otherwise only logs are written.
+/// Keep the returned provider alive and call `shutdown` on it before exiting to flush spans.
+pub fn init(service_name: &'static str) -> Option<SdkTracerProvider> {
+  let provider = if env::var("OTEL_EXPORTER_OTLP_ENDPOINT").is_ok() {
+    match opentelemetry_otlp::SpanExporter::builder().with_http().build() {
+      Ok(exporter) => Some(tracer_provider(service_name, exporter)),
+      Err(e) => {
+        eprintln!("Failed to create OTLP exporter, tracing export disabled: {:?}", e);
+        None
+      }
+    }
+  } else {
+    None
+  };
+  init_with_provider(service_name, provider.clone());
+  provider
+}
+
+/// Batches spans from `service_name` into `exporter`. Tests can pass an in-memory exporter here.
+pub fn tracer_provider(service_name: &'static str, exporter: impl SpanExporter + 'static) -> SdkTracerProvider {
+  SdkTracerProvider::builder()
+    .with_batch_exporter(exporter)
+    .with_resource(Resource::builder().with_service_name(service_name).build())
+    .build()
+}
+
+pub fn init_with_provider(service_name: &'static str, provider: Option<SdkTracerProvider>) {
+  global::set_text_map_propagator(TraceContextPropagator::new());
+  let otel_layer = provider.map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(service_name)));
+  tracing_subscriber::registry()
+    .with(LevelFilter::INFO)
+    .with(tracing_subscriber::fmt::layer())
+    .with(otel_layer)
+    .init();
+}
+
+/// Message headers carrying the current span's context (`traceparent`, `tracestate`).
+pub fn trace_headers() -> HashMap<String, String> {
+  let mut headers = HashMap::new();
+  let context = tracing::Span::current().context();
+  global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut headers));
+  headers
+}
+
+/// The remote parent context from a delivery's headers; empty when the publisher sent none.
+pub fn trace_context(headers: &HashMap<String, String>) -> Context {
+  global::get_text_map_propagator(|propagator| propagator.extract(headers))
+}
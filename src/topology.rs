@@ -0,0 +1,115 @@
+//! Queue names and declarations shared by the API, workers and CLI. RabbitMQ refuses to redeclare
+//! a queue with different arguments, so every process must declare them from here.
+
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+use anyhow::Result;
+use lapin::options::QueueDeclareOptions;
+use lapin::types::{AMQPValue, FieldTable};
+use lapin::{BasicProperties, Channel, Queue};
+
+pub static TASK_QUEUE: &str = "task_queue";
+
+/// `x-max-priority` of every task queue. Task priorities run the other way, with 0 the most
+/// urgent; see `amqp_priority`.
+pub const MAX_PRIORITY: u8 = 10;
+
+/// AMQP `delivery-mode` for messages that survive a broker restart.
+const PERSISTENT: u8 = 2;
+
+pub fn tenant_queue(tenant_id: &str, per_tenant_queues: bool) -> String {
+  if per_tenant_queues {
+    format!("{}.{}", TASK_QUEUE, tenant_id)
+  } else {
+    TASK_QUEUE.to_string()
+  }
+}
+
+/// Which queue a task goes to. With per-type queues each tenant queue is split by task type, and
+/// `pools` maps task types that should share a queue (e.g. `image` and `video` onto `media`).
+#[derive(Clone, Debug, Default)]
+pub struct Routing {
+  pub per_tenant_queues: bool,
+  pub per_type_queues: bool,
+  pub pools: HashMap<String, String>,
+}
+
+impl Routing {
+  /// `task_queue`, then `.<tenant>` with per-tenant queues and `.<pool>` with per-type queues.
+  pub fn queue(&self, tenant_id: &str, task_type: &str) -> String {
+    let queue = tenant_queue(tenant_id, self.per_tenant_queues);
+    if self.per_type_queues {
+      format!("{}.{}", queue, self.pool(task_type))
+    } else {
+      queue
+    }
+  }
+
+  /// Distinct queues of `tenant_id` carrying any of `task_types`.
+  pub fn queues(&self, tenant_id: &str, task_types: &[String]) -> Vec<String> {
+    let queues: BTreeSet<String> = task_types.iter().map(|task_type| self.queue(tenant_id, task_type)).collect();
+    queues.into_iter().collect()
+  }
+
+  /// The pool `task_type` is routed to; every type not listed in `pools` is its own pool.
+  pub fn pool<'a>(&'a self, task_type: &'a str) -> &'a str {
+    self.pools.get(task_type).map(String::as_str).unwrap_or(task_type)
+  }
+}
+
+/// Declares `queue` as durable with native priorities.
+pub async fn declare_queue(channel: &Channel, queue: &str) -> Result<()> {
+  let mut arguments = FieldTable::default();
+  arguments.insert("x-max-priority".into(), AMQPValue::LongInt(MAX_PRIORITY.into()));
+  channel.queue_declare(queue, QueueDeclareOptions { durable: true, ..Default::default() }, arguments).await?;
+  Ok(())
+}
+
+/// Where messages from `queue` that cannot be processed end up. Nothing consumes it; operators
+/// inspect or move its messages by hand.
+pub fn dead_letter_queue(queue: &str) -> String {
+  format!("{}.dead", queue)
+}
+
+/// Declares the durable dead-letter queue of `queue` and returns its name.
+pub async fn declare_dead_letter_queue(channel: &Channel, queue: &str) -> Result<String> {
+  let dead = dead_letter_queue(queue);
+  channel.queue_declare(&dead, QueueDeclareOptions { durable: true, ..Default::default() }, FieldTable::default()).await?;
+  Ok(dead)
+}
+
+/// Declares a holding queue whose messages expire after `delay` and are then dead-lettered back
+/// onto `queue`, and returns its name. One queue per delay keeps expiry in publish order; each is
+/// deleted once unused for twice its delay.
+pub async fn declare_delay_queue(channel: &Channel, queue: &str, delay: Duration) -> Result<String> {
+  let ttl = delay.as_millis().min((i32::MAX / 4) as u128) as i32;
+  let holding = format!("{}.delay.{}", queue, ttl);
+  let mut arguments = FieldTable::default();
+  arguments.insert("x-message-ttl".into(), AMQPValue::LongInt(ttl));
+  arguments.insert("x-expires".into(), AMQPValue::LongInt(ttl * 2 + 60_000));
+  arguments.insert("x-dead-letter-exchange".into(), AMQPValue::LongString("".into()));
+  arguments.insert("x-dead-letter-routing-key".into(), AMQPValue::LongString(queue.into()));
+  channel.queue_declare(&holding, QueueDeclareOptions { durable: true, ..Default::default() }, arguments).await?;
+  Ok(holding)
+}
+
+/// Message and consumer counts of an existing queue, without declaring it.
+pub async fn inspect_queue(channel: &Channel, queue: &str) -> Result<Queue> {
+  let declared = channel
+    .queue_declare(queue, QueueDeclareOptions { passive: true, ..Default::default() }, FieldTable::default())
+    .await?;
+  Ok(declared)
+}
+
+/// Maps a task priority (0 first, 5 by default) onto RabbitMQ's scale, where higher is delivered
+/// first. Anything at or above `MAX_PRIORITY` gets the lowest AMQP priority.
+pub fn amqp_priority(priority: i32) -> u8 {
+  MAX_PRIORITY - priority.clamp(0, MAX_PRIORITY.into()) as u8
+}
+
+/// Persistent, with the task's priority.
+pub fn message_properties(priority: i32) -> BasicProperties {
+  BasicProperties::default()
+    .with_delivery_mode(PERSISTENT)
+    .with_priority(amqp_priority(priority))
+}
@@ -0,0 +1,455 @@
+//! The worker's consume, schedule and process loop. `dtqs_worker` runs it against the configured
+//! broker; in single-node mode the API runs it in-process against the in-memory broker.
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use sqlx::{Pool, Postgres};
+use tokio::sync::{watch, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+use tracing::{info, error, warn, info_span, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use crate::broker::{Broker, Delivery};
+use crate::config::{parse_map, Config};
+use crate::health::WorkerHealth;
+use crate::metrics::{self, CONCURRENCY_LIMIT, QUEUE_WAIT, TASK_ATTEMPTS, TASK_DURATION};
+use crate::telemetry::trace_context;
+use crate::topology::Routing;
+use crate::worker_processing::{process_email_task, process_video_task, process_image_task};
+use crate::worker_scheduler::{Policy, Scheduler, ScheduledTask};
+
+/// Task types `worker_processing` implements, and the default `WORKER_TASK_TYPES`.
+const SUPPORTED_TASK_TYPES: [&str; 3] = ["email", "image", "video"];
+
+/// Attempts before a task is marked failed and dead-lettered.
+const MAX_ATTEMPTS: i32 = 5;
+
+/// A running consumer's tag and task.
+type Consumer = (String, JoinHandle<()>);
+
+/// A delivery being processed and, once spawned, the task processing it.
+type Running = (Delivery, Option<JoinHandle<()>>);
+
+pub struct WorkerSettings {
+  pub worker_id: String,
+  /// Capabilities; with per-type queues only the queues carrying these types are consumed.
+  pub task_types: Vec<String>,
+  pub queues: Vec<String>,
+  pub routing: Routing,
+  /// How often to look for tenants with queued work, when per-tenant queues are consumed without
+  /// a fixed `WORKER_TENANTS` list.
+  pub tenant_discovery: Option<Duration>,
+  pub concurrency: usize,
+  /// Unsettled deliveries each consumer may hold; the rest stay on the queue for other workers.
+  pub prefetch: u16,
+  /// Waiting time that raises a buffered task by one priority level; zero disables aging.
+  pub aging_interval: Duration,
+  pub policy: Policy,
+  pub class_limits: HashMap<String, usize>,
+  pub task_type_limits: HashMap<String, usize>,
+  pub grace_period: Duration,
+}
+
+impl WorkerSettings {
+  /// Routes tasks exactly as the API configured by `config` does; the `WORKER_*` settings only
+  /// the worker uses come from the environment.
+  pub fn from_config(worker_id: String, config: &Config) -> Self {
+    let per_tenant_queues = config.per_tenant_queues;
+    let routing = config.routing();
+    let task_types: Vec<String> = match env::var("WORKER_TASK_TYPES") {
+      Ok(types) => types.split(',').map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).collect(),
+      Err(_) => SUPPORTED_TASK_TYPES.iter().map(|t| t.to_string()).collect(),
+    };
+    if let Some(unsupported) = task_types.iter().find(|t| !SUPPORTED_TASK_TYPES.contains(&t.as_str())) {
+      panic!("WORKER_TASK_TYPES lists {}, which this worker cannot process", unsupported);
+    }
+    if !routing.per_type_queues && task_types.len() < SUPPORTED_TASK_TYPES.len() {
+      warn!("WORKER_TASK_TYPES only restricts consumption with PER_TYPE_QUEUES; the shared queue carries every task type");
+    }
+    for (task_type, pool) in &routing.pools {
+      if routing.per_type_queues && !task_types.contains(task_type) && task_types.iter().any(|t| routing.pool(t) == pool) {
+        warn!("Consuming pool {} without declaring its task type {}", pool, task_type);
+      }
+    }
+    let worker_tenants = env::var("WORKER_TENANTS").ok();
+    let tenants: Vec<String> = match &worker_tenants {
+      Some(tenants) if per_tenant_queues => tenants.split(',').map(|tenant| tenant.trim().to_string()).filter(|t| !t.is_empty()).collect(),
+      // Discovered at runtime.
+      None if per_tenant_queues => Vec::new(),
+      // Not part of the queue name without per-tenant queues.
+      _ => vec![String::new()],
+    };
+    let queues = tenants.iter().flat_map(|tenant| routing.queues(tenant, &task_types)).collect();
+    let tenant_discovery = (per_tenant_queues && worker_tenants.is_none())
+      .then(|| Duration::from_secs(env::var("WORKER_TENANT_DISCOVERY_SECS").ok().and_then(|s| s.parse().ok()).unwrap_or(10)));
+
+    let concurrency: usize = env::var("WORKER_CONCURRENCY").ok().and_then(|c| c.parse().ok()).filter(|c| *c > 0).unwrap_or(4);
+    let policy = match env::var("WORKER_SCHEDULING_POLICY").as_deref() {
+      Ok("weighted-task-types") => Policy::WeightedTaskTypes { weights: parse_map("WORKER_TASK_TYPE_WEIGHTS", env::var("WORKER_TASK_TYPE_WEIGHTS").ok()) },
+      Ok("tenant-deficit") => Policy::TenantDeficit {
+        quantum: env::var("WORKER_DRR_QUANTUM").ok().and_then(|q| q.parse().ok()).unwrap_or(1),
+        weights: parse_map("WORKER_TENANT_WEIGHTS", env::var("WORKER_TENANT_WEIGHTS").ok()),
+        costs: parse_map("WORKER_TASK_COSTS", env::var("WORKER_TASK_COSTS").ok()),
+      },
+      Ok("priority") | Err(_) => Policy::Priority,
+      Ok(other) => panic!("Unknown WORKER_SCHEDULING_POLICY: {}", other),
+    };
+    Self {
+      worker_id,
+      task_types,
+      queues,
+      routing,
+      tenant_discovery,
+      concurrency,
+      prefetch: env::var("WORKER_PREFETCH").ok().and_then(|p| p.parse().ok()).unwrap_or(u16::try_from(concurrency * 2).unwrap_or(u16::MAX)),
+      aging_interval: Duration::from_secs(env::var("WORKER_PRIORITY_AGING_SECS").ok().and_then(|s| s.parse().ok()).unwrap_or(30)),
+      policy,
+      class_limits: parse_map("WORKER_CLASS_CONCURRENCY", env::var("WORKER_CLASS_CONCURRENCY").ok()),
+      task_type_limits: parse_map("WORKER_TYPE_CONCURRENCY", env::var("WORKER_TYPE_CONCURRENCY").ok()),
+      grace_period: Duration::from_secs(config.shutdown_grace_secs),
+    }
+  }
+}
+
+/// Consumes `settings.queues` and processes tasks until `shutdown` is set, then stops consuming,
+/// requeues what has not started and gives running tasks the grace period before requeueing them
+/// too. Records the worker's lifecycle in `health`.
+pub async fn run(settings: WorkerSettings, broker: Arc<dyn Broker>, db_pool: Pool<Postgres>, health: Arc<WorkerHealth>, mut shutdown: watch::Receiver<bool>) {
+  let WorkerSettings { worker_id, queues, concurrency, prefetch, grace_period, .. } = settings;
+  info!("Accepting {:?} tasks from {:?}", settings.task_types, queues);
+  info!("Scheduling with {:?}", settings.policy);
+  let scheduler = Arc::new(
+    Scheduler::new(prefetch as usize, Some(settings.aging_interval))
+      .with_policy(settings.policy)
+      .with_class_limits(settings.class_limits)
+      .with_task_type_limits(settings.task_type_limits)
+  );
+  let semaphore = Arc::new(Semaphore::new(concurrency));
+  CONCURRENCY_LIMIT.set(concurrency as i64);
+  metrics::record_worker_load(semaphore.available_permits());
+
+  let consumers: Arc<Mutex<Vec<Consumer>>> = Arc::new(Mutex::new(Vec::new()));
+  for queue in &queues {
+    let consumer_tag = format!("{}-{}", worker_id, queue);
+    let consumer = spawn_consumer(queue.clone(), consumer_tag.clone(), prefetch, broker.clone(), scheduler.clone(), health.clone(), shutdown.clone());
+    consumers.lock().unwrap().push((consumer_tag, consumer));
+  }
+
+  // Tenants created after the worker started get consumers once they have work queued.
+  let discovery = settings.tenant_discovery.map(|every| {
+    let routing = settings.routing.clone();
+    let task_types = settings.task_types.clone();
+    let worker_id = worker_id.clone();
+    let broker = broker.clone();
+    let scheduler = scheduler.clone();
+    let health = health.clone();
+    let db_pool = db_pool.clone();
+    let consumers = consumers.clone();
+    let mut known: HashSet<String> = queues.iter().cloned().collect();
+    let mut shutdown = shutdown.clone();
+    tokio::spawn(async move {
+      let mut interval = tokio::time::interval(every);
+      loop {
+        tokio::select! {
+          _ = interval.tick() => {}
+          _ = shutdown.wait_for(|stop| *stop) => break,
+        }
+        let tenants = match sqlx::query_scalar!("SELECT DISTINCT tenant_id FROM tasks WHERE status IN ('pending', 'in_progress')")
+          .fetch_all(&db_pool)
+          .await {
+          Ok(tenants) => tenants,
+          Err(e) => {
+            error!("Failed to discover tenants: {:?}", e);
+            continue;
+          }
+        };
+        for queue in tenants.iter().flat_map(|tenant| routing.queues(tenant, &task_types)) {
+          if known.contains(&queue) {
+            continue;
+          }
+          if let Err(e) = broker.declare_queue(&queue).await {
+            error!("Failed to declare discovered queue {}: {:?}", queue, e);
+            continue;
+          }
+          info!("Discovered tenant queue {}", queue);
+          known.insert(queue.clone());
+          health.consumer_added();
+          let consumer_tag = format!("{}-{}", worker_id, queue);
+          let consumer = spawn_consumer(queue, consumer_tag.clone(), prefetch, broker.clone(), scheduler.clone(), health.clone(), shutdown.clone());
+          consumers.lock().unwrap().push((consumer_tag, consumer));
+        }
+      }
+    })
+  });
+
+  // Deliveries handed to a processing task and not yet settled, so anything still running when
+  // the grace period ends can be stopped and requeued.
+  let in_flight: Arc<Mutex<HashMap<u64, Running>>> = Arc::new(Mutex::new(HashMap::new()));
+  loop {
+    // Reserve a worker slot first, so a task only leaves the scheduler once it can start.
+    let permit = tokio::select! {
+      permit = semaphore.clone().acquire_owned() => permit.unwrap(),
+      _ = shutdown.wait_for(|stop| *stop) => break,
+    };
+    let dispatched = tokio::select! {
+      dispatched = scheduler.next() => dispatched,
+      _ = shutdown.wait_for(|stop| *stop) => break,
+    };
+    let scheduled_task = dispatched.task;
+    let class_slot = dispatched.slot;
+    metrics::record_worker_load(semaphore.available_permits());
+    let tag = scheduled_task.delivery.tag;
+    in_flight.lock().unwrap().insert(tag, (scheduled_task.delivery.clone(), None));
+    let in_flight_clone = in_flight.clone();
+    let broker_clone = broker.clone();
+    let semaphore_clone = semaphore.clone();
+    let db_pool_clone = db_pool.clone();
+    let task_data = scheduled_task.task_data.clone();
+    let delivery = scheduled_task.delivery;
+    let worker_id_clone = worker_id.clone();
+    let span = info_span!(
+      "process_task",
+      task_id = task_data.get("task_id").and_then(|v| v.as_str()).unwrap_or("unknown"),
+      task_type = task_data.get("task_type").and_then(|v| v.as_str()).unwrap_or(""),
+      worker_id = %worker_id,
+    );
+    span.set_parent(scheduled_task.trace_context);
+    let task = tokio::spawn(async move {
+      let _class_slot = class_slot;
+      let task_type = task_data.get("task_type").and_then(|v| v.as_str()).unwrap_or("");
+      let task_id = task_data.get("task_id").and_then(|v| v.as_str()).unwrap_or("unknown");
+      process(&task_data, task_type, task_id, &delivery, broker_clone.as_ref(), &db_pool_clone, &worker_id_clone).await;
+      in_flight_clone.lock().unwrap().remove(&delivery.tag);
+      drop(permit);
+      metrics::record_worker_load(semaphore_clone.available_permits());
+    }.instrument(span));
+    // Unless it has already finished and removed its entry.
+    if let Some((_, handle)) = in_flight.lock().unwrap().get_mut(&tag) {
+      *handle = Some(task);
+    }
+  }
+
+  info!("Shutting down: no longer consuming, waiting up to {:?} for in-flight tasks", grace_period);
+  health.set_status("draining").await;
+  if let Some(discovery) = discovery {
+    let _ = discovery.await;
+  }
+  let consumers = std::mem::take(&mut *consumers.lock().unwrap());
+  for (consumer_tag, _) in &consumers {
+    if let Err(e) = broker.cancel(consumer_tag).await {
+      error!("Failed to cancel consumer {}: {:?}", consumer_tag, e);
+    }
+  }
+  for (_, consumer) in consumers {
+    let _ = consumer.await;
+  }
+  let queued = scheduler.drain().await;
+  info!("Requeueing {} tasks that had not started", queued.len());
+  for scheduled_task in queued {
+    let _ = broker.nack(&scheduled_task.delivery, true).await;
+  }
+
+  // Every permit is back once the last processing task has finished.
+  match tokio::time::timeout(grace_period, semaphore.acquire_many(concurrency as u32)).await {
+    Ok(_) => info!("All in-flight tasks finished"),
+    Err(_) => {
+      let unfinished: Vec<Running> = in_flight.lock().unwrap().drain().map(|(_, running)| running).collect();
+      warn!("Grace period elapsed, stopping and requeueing {} unfinished tasks", unfinished.len());
+      for (delivery, task) in unfinished {
+        // Stopped first, so the task cannot still finish after another worker has picked it up.
+        if let Some(task) = task {
+          task.abort();
+          let _ = task.await;
+        }
+        let _ = broker.nack(&delivery, true).await;
+      }
+    }
+  }
+  health.set_status("stopped").await;
+}
+
+/// Consumes `queue` into `scheduler` until `shutdown` is set, restarting the consumer whenever
+/// the broker connection is re-established.
+fn spawn_consumer(queue: String, consumer_tag: String, prefetch: u16, broker: Arc<dyn Broker>, scheduler: Arc<Scheduler>, health: Arc<WorkerHealth>, mut shutdown: watch::Receiver<bool>) -> JoinHandle<()> {
+  tokio::spawn(async move {
+    let mut generation = 0;
+    loop {
+      tokio::select! {
+        _ = broker.ready() => {}
+        _ = shutdown.wait_for(|stop| *stop) => break,
+      }
+      generation += 1;
+      let mut deliveries = match broker.consume(&queue, &consumer_tag, prefetch).await {
+        Ok(deliveries) => deliveries,
+        Err(e) => {
+          error!("Failed to start consumer for {}: {:?}", queue, e);
+          tokio::time::sleep(Duration::from_secs(1)).await;
+          continue;
+        }
+      };
+      info!("Consuming from {}", queue);
+      health.consumer_started();
+      let mut lost = false;
+      while let Some(delivery) = deliveries.next().await {
+        match delivery {
+          Ok(delivery) => {
+            match serde_json::from_slice::<serde_json::Value>(&delivery.message.payload) {
+              Ok(task_data) => {
+                let priority = task_data.get("priority")
+                  .and_then(|v| v.as_u64())
+                  .unwrap_or(5) as u8;
+                let scheduled_task = ScheduledTask {
+                  priority,
+                  trace_context: trace_context(&delivery.message.headers),
+                  delivery: delivery.clone(),
+                  task_data,
+                  consumer_generation: generation,
+                };
+                // Waits while the scheduler is full, which stops this consumer reading further.
+                let buffered = tokio::select! {
+                  _ = scheduler.add_task(scheduled_task) => true,
+                  _ = shutdown.wait_for(|stop| *stop) => false,
+                };
+                if !buffered {
+                  let _ = broker.nack(&delivery, true).await;
+                }
+              }
+              Err(e) => {
+                error!("Failed to parse task, dead-lettering it: {:?}", e);
+                let _ = broker.dead_letter(&delivery).await;
+              }
+            }
+          }
+          Err(e) => {
+            error!("Consumer error on {}: {:?}", queue, e);
+            lost = true;
+            break;
+          }
+        }
+      }
+      health.consumer_stopped();
+      if *shutdown.borrow() {
+        info!("Consumer for {} cancelled", queue);
+        break;
+      }
+      if lost {
+        // Only this run's deliveries are stale; other consumers share the scheduler. They are
+        // nacked so backends that outlive the connection (postgres, redis) release them now rather
+        // than when their leases expire; on RabbitMQ the nack fails and the broker redelivers them.
+        let stale = scheduler.drain_where(|task| task.delivery.queue == queue && task.consumer_generation == generation).await;
+        if !stale.is_empty() {
+          warn!("Returning {} queued tasks received on the lost connection to {}", stale.len(), queue);
+        }
+        for task in stale {
+          let _ = broker.nack(&task.delivery, true).await;
+        }
+      }
+      warn!("Consumer for {} stopped, restarting once the broker is reachable", queue);
+    }
+  })
+}
+
+/// Runs one task and settles its delivery: acked once done (or skipped), delayed with exponential
+/// backoff for another attempt (or when the task cannot be claimed), and dead-lettered once it has
+/// failed `MAX_ATTEMPTS` times.
+async fn process(task_data: &serde_json::Value, task_type: &str, task_id: &str, delivery: &Delivery, broker: &dyn Broker, db_pool: &Pool<Postgres>, worker_id: &str) {
+  // Messages published before the task was last retried carry an older generation.
+  let generation = task_data.get("generation").and_then(|v| v.as_i64()).unwrap_or(0) as i32;
+  let claimed = sqlx::query_scalar!(
+      "UPDATE tasks SET status = 'in_progress', updated_at = NOW()
+       WHERE id::text = $1 AND generation = $2 AND status IN ('pending', 'in_progress')
+       RETURNING id",
+      task_id,
+      generation
+    )
+    .fetch_optional(db_pool)
+    .await;
+  match claimed {
+    Ok(Some(_)) => {}
+    Ok(None) => {
+      info!("Skipping task {}: cancelled, already finished or superseded by a retry", task_id);
+      TASK_ATTEMPTS.with_label_values(&[task_type, "skipped"]).inc();
+      let _ = broker.ack(delivery).await;
+      return;
+    }
+    Err(e) => {
+      // Without the claim the task may have been cancelled meanwhile, so it is not run blind.
+      error!("Failed to mark task {} in progress, retrying later: {:?}", task_id, e);
+      let _ = broker.delay(delivery, retry_delay(1)).await;
+      return;
+    }
+  }
+  if let Some(enqueued_at) = task_data.get("enqueued_at").and_then(|v| v.as_str()).and_then(|v| DateTime::parse_from_rfc3339(v).ok()) {
+    let waited = (Utc::now() - enqueued_at.with_timezone(&Utc)).to_std().unwrap_or_default();
+    QUEUE_WAIT.with_label_values(&[task_type]).observe(waited.as_secs_f64());
+  }
+  let started = Instant::now();
+  let processing_result = match task_type {
+    "email" => process_email_task(task_data, db_pool, worker_id).await,
+    "video" => process_video_task(task_data, db_pool, worker_id).await,
+    "image" => process_image_task(task_data, db_pool, worker_id).await,
+    other   => Err(anyhow::anyhow!("Unknown task type: {}", other)),
+  };
+  let result_label = if processing_result.is_ok() { "success" } else { "error" };
+  TASK_DURATION.with_label_values(&[task_type, result_label]).observe(started.elapsed().as_secs_f64());
+  match processing_result {
+    Ok(_) => {
+      info!("Task {} processed successfully", task_id);
+      TASK_ATTEMPTS.with_label_values(&[task_type, "completed"]).inc();
+      let _ = sqlx::query!(
+                      "UPDATE tasks SET status = 'completed', progress = 100, updated_at = NOW() WHERE id::text = $1 AND generation = $2 AND status <> 'cancelled'",
+                      task_id,
+                      generation
+                  )
+        .execute(db_pool)
+        .await;
+      let _ = broker.ack(delivery).await;
+    }
+    Err(e) => {
+      error!("Processing failed for task {}: {:?}", task_id, e);
+      match sqlx::query!("UPDATE tasks SET attempts = attempts + 1, updated_at = NOW() WHERE id::text = $1 AND generation = $2 RETURNING attempts", task_id, generation)
+        .fetch_optional(db_pool)
+        .await {
+        // Retried while this run was going; the new generation's message carries on from here.
+        Ok(None) => {
+          info!("Dropping failed run of task {}: superseded by a retry", task_id);
+          TASK_ATTEMPTS.with_label_values(&[task_type, "skipped"]).inc();
+          let _ = broker.ack(delivery).await;
+        }
+        Ok(Some(record)) => {
+          let attempts: i32 = record.attempts;
+          if attempts < MAX_ATTEMPTS {
+            error!("Retrying task {} (attempt {})", task_id, attempts);
+            TASK_ATTEMPTS.with_label_values(&[task_type, "retried"]).inc();
+            let _ = broker.delay(delivery, retry_delay(attempts)).await;
+          } else {
+            error!("Max attempts reached for task {}. Marking as failed.", task_id);
+            TASK_ATTEMPTS.with_label_values(&[task_type, "failed"]).inc();
+            let _ = sqlx::query!(
+                              "UPDATE tasks SET status = 'failed', updated_at = NOW() WHERE id::text = $1 AND generation = $2 AND status <> 'cancelled'",
+                              task_id,
+                              generation
+                          )
+              .execute(db_pool)
+              .await;
+            let _ = broker.dead_letter(delivery).await;
+          }
+        }
+        Err(err) => {
+          error!("Failed to update attempt count for task {}: {:?}", task_id, err);
+          TASK_ATTEMPTS.with_label_values(&[task_type, "retried"]).inc();
+          let _ = broker.delay(delivery, retry_delay(1)).await;
+        }
+      }
+    }
+  }
+}
+
+/// 2, 4, 8 and 16 seconds after the first to fourth failed attempts.
+fn retry_delay(attempts: i32) -> Duration {
+  Duration::from_secs(1 << attempts.clamp(1, 10))
+}
\ No newline at end of file
@@ -1,52 +1,379 @@
-use std::collections::BinaryHeap;
-use tokio::sync::Mutex;
-use std::cmp::Ordering;
-use lapin::message::Delivery;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify, Semaphore};
+use tokio::time::Instant;
+use crate::broker::Delivery;
 use serde_json::Value;
+use opentelemetry::Context;
 
 #[derive(Debug)]
 pub struct ScheduledTask {
+  /// Lower values run first.
   pub priority: u8,
   pub delivery: Delivery,
   pub task_data: Value,
+  /// Trace context propagated from the publisher through the message headers.
+  pub trace_context: Context,
+  /// Which run of its consumer received the delivery, so the deliveries of a consumer that lost
+  /// its connection can be told apart from those of its restart.
+  pub consumer_generation: u64,
 }
 
-impl Eq for ScheduledTask {}
+impl ScheduledTask {
+  fn field(&self, name: &str) -> &str {
+    self.task_data.get(name).and_then(Value::as_str).unwrap_or_default()
+  }
+}
+
+/// How the scheduler shares worker slots between classes of tasks. The class is the task type,
+/// except under `TenantDeficit` where it is the tenant.
+#[derive(Clone, Debug, Default)]
+pub enum Policy {
+  /// Lowest (aged) priority first across all classes.
+  #[default]
+  Priority,
+  /// Task types take turns, each starting up to its weight (default 1) tasks per turn.
+  WeightedTaskTypes { weights: HashMap<String, u32> },
+  /// Deficit round-robin across tenants: each turn a tenant earns `quantum` times its weight
+  /// (default 1) in credit and starts tasks while it can pay for them. A task costs the amount
+  /// set for its type in `costs` (default 1), so tenants get a fair share of work, not of messages.
+  TenantDeficit { quantum: u32, weights: HashMap<String, u32>, costs: HashMap<String, u32> },
+}
+
+impl Policy {
+  fn class_of(&self, task: &ScheduledTask) -> String {
+    match self {
+      Policy::TenantDeficit { .. } => task.field("tenant_id").to_string(),
+      _ => task.field("task_type").to_string(),
+    }
+  }
+}
+
+struct Queued {
+  /// Enqueue order, used to break ties between equal effective priorities.
+  seq: u64,
+  enqueued_at: Instant,
+  task: ScheduledTask,
+}
+
+#[derive(Default)]
+struct ClassQueue {
+  /// FIFO per priority level; the front of each is its oldest task.
+  levels: BTreeMap<u8, VecDeque<Queued>>,
+  /// Unspent credit under `TenantDeficit`.
+  deficit: u64,
+}
+
+/// Where a candidate sits in its class: the priority level and the index within it.
+type Position = (u8, usize);
+
+impl ClassQueue {
+  /// `(effective priority, seq, position)` of the task this class would start next, skipping
+  /// tasks that are not `startable`. Only the first startable task of each level needs comparing:
+  /// it has waited longest, so aging has boosted it the most.
+  fn next(&self, aging_interval: Option<Duration>, now: Instant, startable: impl Fn(&ScheduledTask) -> bool) -> Option<(u8, u64, Position)> {
+    self.levels
+      .iter()
+      .filter_map(|(level, tasks)| {
+        let (index, queued) = tasks.iter().enumerate().find(|(_, queued)| startable(&queued.task))?;
+        Some((effective_priority(*level, queued, aging_interval, now), queued.seq, (*level, index)))
+      })
+      .min()
+  }
+}
+
+fn effective_priority(priority: u8, queued: &Queued, aging_interval: Option<Duration>, now: Instant) -> u8 {
+  match aging_interval {
+    Some(interval) => {
+      let boost = (now - queued.enqueued_at).as_nanos() / interval.as_nanos();
+      priority.saturating_sub(boost.min(u8::MAX.into()) as u8)
+    }
+    None => priority,
+  }
+}
+
+#[derive(Default)]
+struct Queues {
+  /// Only classes with queued tasks.
+  classes: BTreeMap<String, ClassQueue>,
+  /// Round-robin order of `classes`; the front has the current turn.
+  turns: VecDeque<String>,
+  /// Whether the front class has been given its picks or credit for the current turn.
+  turn_started: bool,
+  /// Tasks the front class may still start this turn under `WeightedTaskTypes`.
+  picks_left: u32,
+  next_seq: u64,
+}
 
-impl PartialEq for ScheduledTask {
-  fn eq(&self, other: &Self) -> bool {
-    self.priority == other.priority
+impl Queues {
+  fn rotate(&mut self) {
+    if !self.turns.is_empty() {
+      self.turns.rotate_left(1);
+    }
+    self.turn_started = false;
   }
+
+  fn pop(&mut self, class: &str, (level, index): Position) -> ScheduledTask {
+    let class_queue = self.classes.get_mut(class).expect("class has a queue");
+    let tasks = class_queue.levels.get_mut(&level).expect("level has tasks");
+    let queued = tasks.remove(index).expect("index is in the level");
+    if tasks.is_empty() {
+      class_queue.levels.remove(&level);
+    }
+    if class_queue.levels.is_empty() {
+      self.classes.remove(class);
+      if self.turns.front().is_some_and(|front| front == class) {
+        self.turn_started = false;
+      }
+      self.turns.retain(|turn| turn != class);
+    }
+    queued.task
+  }
+}
+
+#[derive(Default)]
+struct RunningCounts {
+  classes: HashMap<String, usize>,
+  task_types: HashMap<String, usize>,
+}
+
+/// State shared with the `ClassSlot`s handed out.
+#[derive(Default)]
+struct Shared {
+  running: std::sync::Mutex<RunningCounts>,
+  /// Notified whenever a task is queued or a running one finishes, either of which can make a
+  /// task startable.
+  ready: Notify,
 }
 
-impl PartialOrd for ScheduledTask {
-  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-    Some(other.priority.cmp(&self.priority))
+fn at_limit(limits: &HashMap<String, usize>, running: &HashMap<String, usize>, key: &str) -> bool {
+  limits.get(key).is_some_and(|limit| running.get(key).copied().unwrap_or(0) >= *limit)
+}
+
+fn release(running: &mut HashMap<String, usize>, key: &str) {
+  if let Some(count) = running.get_mut(key) {
+    *count -= 1;
+    if *count == 0 {
+      running.remove(key);
+    }
   }
 }
 
-impl Ord for ScheduledTask {
-  fn cmp(&self, other: &Self) -> Ordering {
-    other.priority.cmp(&self.priority)
+/// Counts its task against its class and task type concurrency limits until dropped.
+pub struct ClassSlot {
+  class: String,
+  task_type: String,
+  shared: Arc<Shared>,
+}
+
+impl Drop for ClassSlot {
+  fn drop(&mut self) {
+    let mut running = self.shared.running.lock().unwrap();
+    release(&mut running.classes, &self.class);
+    release(&mut running.task_types, &self.task_type);
+    drop(running);
+    self.shared.ready.notify_waiters();
   }
 }
 
+/// A task handed out by `Scheduler::get_next`. Keep `slot` alive for as long as the task runs.
+pub struct Dispatched {
+  pub task: ScheduledTask,
+  pub slot: ClassSlot,
+}
+
+/// Buffers deliveries until a worker slot frees up and decides which runs next according to its
+/// `Policy`. Tasks of the same class and priority run in arrival order, and with aging a waiting
+/// task is promoted one level per `aging_interval`, so a steady stream of urgent work cannot
+/// starve low-priority tasks forever.
 pub struct Scheduler {
-  queue: Mutex<BinaryHeap<ScheduledTask>>,
+  queues: Mutex<Queues>,
+  /// One permit per free slot, so at most `capacity` deliveries are buffered.
+  slots: Semaphore,
+  aging_interval: Option<Duration>,
+  policy: Policy,
+  class_limits: HashMap<String, usize>,
+  task_type_limits: HashMap<String, usize>,
+  shared: Arc<Shared>,
 }
 
 impl Scheduler {
-  pub fn new() -> Self {
+  /// `aging_interval` of `None` (or zero) schedules by strict priority. A `capacity` of 0 buffers
+  /// without limit, like a prefetch of 0.
+  pub fn new(capacity: usize, aging_interval: Option<Duration>) -> Self {
     Self {
-      queue: Mutex::new(BinaryHeap::new()),
+      queues: Mutex::new(Queues::default()),
+      slots: Semaphore::new(if capacity == 0 { Semaphore::MAX_PERMITS } else { capacity }),
+      aging_interval: aging_interval.filter(|interval| !interval.is_zero()),
+      policy: Policy::default(),
+      class_limits: HashMap::new(),
+      task_type_limits: HashMap::new(),
+      shared: Arc::default(),
     }
   }
 
+  pub fn with_policy(mut self, policy: Policy) -> Self {
+    self.policy = policy;
+    self
+  }
+
+  /// Caps how many tasks of each listed class run at once. A class at its cap is skipped, so the
+  /// others keep being scheduled.
+  pub fn with_class_limits(mut self, class_limits: HashMap<String, usize>) -> Self {
+    self.class_limits = class_limits;
+    self
+  }
+
+  /// Caps how many tasks of each listed type run at once, whatever the policy. Tasks of a type at
+  /// its cap stay queued while later tasks of other types start.
+  pub fn with_task_type_limits(mut self, task_type_limits: HashMap<String, usize>) -> Self {
+    self.task_type_limits = task_type_limits;
+    self
+  }
+
+  /// Waits for a free slot when the buffer is full.
   pub async fn add_task(&self, task: ScheduledTask) {
-    self.queue.lock().await.push(task);
+    self.slots.acquire().await.expect("scheduler semaphore is never closed").forget();
+    let class = self.policy.class_of(&task);
+    let mut queues = self.queues.lock().await;
+    let seq = queues.next_seq;
+    queues.next_seq += 1;
+    if !queues.classes.contains_key(&class) {
+      queues.turns.push_back(class.clone());
+    }
+    queues.classes
+      .entry(class)
+      .or_default()
+      .levels
+      .entry(task.priority)
+      .or_default()
+      .push_back(Queued { seq, enqueued_at: Instant::now(), task });
+    drop(queues);
+    self.shared.ready.notify_waiters();
+  }
+
+  /// Waits until a task can be started and takes it. Cancel-safe: a task is only removed from the
+  /// buffer by the call that returns it.
+  pub async fn next(&self) -> Dispatched {
+    loop {
+      // Registered before checking, so a task queued in between still wakes this call.
+      let ready = self.shared.ready.notified();
+      tokio::pin!(ready);
+      ready.as_mut().enable();
+      if let Some(dispatched) = self.get_next().await {
+        return dispatched;
+      }
+      ready.await;
+    }
+  }
+
+  /// Like `next`, but returns `None` instead of waiting when nothing is queued or everything
+  /// queued is held back by a concurrency limit.
+  pub async fn get_next(&self) -> Option<Dispatched> {
+    let mut queues = self.queues.lock().await;
+    let mut running = self.shared.running.lock().unwrap();
+    let class_full = |class: &str| at_limit(&self.class_limits, &running.classes, class);
+    let startable = |task: &ScheduledTask| !at_limit(&self.task_type_limits, &running.task_types, task.field("task_type"));
+    let now = Instant::now();
+
+    let (class, position) = match &self.policy {
+      Policy::Priority => {
+        let (_, _, class, position) = queues.classes
+          .iter()
+          .filter(|(class, _)| !class_full(class))
+          .filter_map(|(class, tasks)| tasks.next(self.aging_interval, now, startable).map(|(priority, seq, position)| (priority, seq, class, position)))
+          .min()?;
+        (class.clone(), position)
+      }
+      Policy::WeightedTaskTypes { weights } => {
+        let mut picked = None;
+        for _ in 0..queues.turns.len() {
+          let class = queues.turns.front()?.clone();
+          if class_full(&class) {
+            queues.rotate();
+            continue;
+          }
+          if !queues.turn_started {
+            queues.turn_started = true;
+            queues.picks_left = weights.get(&class).copied().unwrap_or(1).max(1);
+          }
+          let Some((_, _, position)) = queues.classes[&class].next(self.aging_interval, now, startable) else {
+            queues.rotate();
+            continue;
+          };
+          queues.picks_left -= 1;
+          if queues.picks_left == 0 {
+            queues.rotate();
+          }
+          picked = Some((class, position));
+          break;
+        }
+        picked?
+      }
+      Policy::TenantDeficit { quantum, weights, costs } => {
+        let eligible = |queues: &Queues, class: &str| !class_full(class) && queues.classes[class].next(self.aging_interval, now, startable).is_some();
+        if !queues.turns.iter().any(|class| eligible(&queues, class)) {
+          return None;
+        }
+        // Terminates: every eligible class gains at least one credit per round.
+        loop {
+          let class = queues.turns.front()?.clone();
+          if !eligible(&queues, &class) {
+            queues.rotate();
+            continue;
+          }
+          let turn_started = queues.turn_started;
+          queues.turn_started = true;
+          let class_queue = queues.classes.get_mut(&class)?;
+          if !turn_started {
+            class_queue.deficit += u64::from((*quantum).max(1) * weights.get(&class).copied().unwrap_or(1).max(1));
+          }
+          let (_, _, (level, index)) = class_queue.next(self.aging_interval, now, startable)?;
+          let task_type = class_queue.levels[&level][index].task.field("task_type");
+          let cost = u64::from(costs.get(task_type).copied().unwrap_or(1));
+          if class_queue.deficit >= cost {
+            class_queue.deficit -= cost;
+            break (class, (level, index));
+          }
+          queues.rotate();
+        }
+      }
+    };
+
+    let task = queues.pop(&class, position);
+    let task_type = task.field("task_type").to_string();
+    *running.classes.entry(class.clone()).or_default() += 1;
+    *running.task_types.entry(task_type.clone()).or_default() += 1;
+    self.slots.add_permits(1);
+    Some(Dispatched { task, slot: ClassSlot { class, task_type, shared: self.shared.clone() } })
   }
 
-  pub async fn get_next(&self) -> Option<ScheduledTask> {
-    self.queue.lock().await.pop()
+  /// Removes every task that has not been started yet.
+  pub async fn drain(&self) -> Vec<ScheduledTask> {
+    self.drain_where(|_| true).await
+  }
+
+  /// Removes the tasks not started yet that match `filter`. The others keep their place, and
+  /// their classes keep their turns and credit.
+  pub async fn drain_where(&self, filter: impl Fn(&ScheduledTask) -> bool) -> Vec<ScheduledTask> {
+    let mut queues = self.queues.lock().await;
+    let mut tasks = Vec::new();
+    for class_queue in queues.classes.values_mut() {
+      for level in class_queue.levels.values_mut() {
+        let (drained, kept) = std::mem::take(level).into_iter().partition(|queued| filter(&queued.task));
+        *level = kept;
+        tasks.extend(drained.into_iter().map(|queued: Queued| queued.task));
+      }
+      class_queue.levels.retain(|_, level| !level.is_empty());
+    }
+    queues.classes.retain(|_, class_queue| !class_queue.levels.is_empty());
+    let Queues { classes, turns, turn_started, .. } = &mut *queues;
+    if turns.front().is_some_and(|front| !classes.contains_key(front)) {
+      *turn_started = false;
+    }
+    turns.retain(|turn| classes.contains_key(turn));
+    self.slots.add_permits(tasks.len());
+    tasks
   }
 }
@@ -0,0 +1,105 @@
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use dtqs::auth::{Authenticator, Principal};
+use dtqs::config::Config;
+use dtqs::error::ApiError;
+use jsonwebtoken::{encode, EncodingKey, Header};
+use serde_json::{json, Value};
+
+/// The key behind `tests/fixtures/jwks.json`.
+const SECRET: &[u8] = b"dtqs-auth-test-fixture-hmac-secret!!";
+
+async fn authenticator() -> Authenticator {
+  let config = Config::from_file(Path::new("tests/fixtures/auth.env")).unwrap();
+  Authenticator::from_config(&config).await.unwrap()
+}
+
+fn now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn claims() -> Value {
+  json!({
+    "sub": "user-1",
+    "tenant_id": "acme",
+    "scope": "tasks:read tasks:write",
+    "aud": "dtqs",
+    "iss": "https://issuer.test",
+    "exp": now() + 600,
+  })
+}
+
+fn token(kid: &str, claims: &Value) -> String {
+  let header = Header { kid: Some(kid.to_string()), ..Header::default() };
+  encode(&header, claims, &EncodingKey::from_secret(SECRET)).unwrap()
+}
+
+fn authenticate(auth: &Authenticator, token: &str) -> Result<Principal, ApiError> {
+  auth.authenticate(Some(&format!("Bearer {}", token)))
+}
+
+fn assert_unauthorized(result: Result<Principal, ApiError>) {
+  assert!(matches!(result, Err(ApiError::Unauthorized(_))), "expected 401, got {:?}", result);
+}
+
+#[tokio::test]
+async fn accepts_a_valid_jwt() {
+  let auth = authenticator().await;
+  let principal = authenticate(&auth, &token("test", &claims())).unwrap();
+  assert_eq!(principal.subject, "user-1");
+  assert_eq!(principal.tenant_id, "acme");
+  assert!(principal.has_scope("tasks:write"));
+}
+
+#[tokio::test]
+async fn rejects_expired_tokens() {
+  let auth = authenticator().await;
+  let mut claims = claims();
+  claims["exp"] = json!(now() - 3600);
+  assert_unauthorized(authenticate(&auth, &token("test", &claims)));
+}
+
+#[tokio::test]
+async fn rejects_the_wrong_audience_or_issuer() {
+  let auth = authenticator().await;
+  let mut wrong_audience = claims();
+  wrong_audience["aud"] = json!("someone-else");
+  assert_unauthorized(authenticate(&auth, &token("test", &wrong_audience)));
+
+  let mut wrong_issuer = claims();
+  wrong_issuer["iss"] = json!("https://evil.test");
+  assert_unauthorized(authenticate(&auth, &token("test", &wrong_issuer)));
+}
+
+#[tokio::test]
+async fn rejects_unknown_key_ids() {
+  let auth = authenticator().await;
+  assert_unauthorized(authenticate(&auth, &token("rotated-away", &claims())));
+}
+
+#[tokio::test]
+async fn rejects_tokens_without_a_tenant() {
+  let auth = authenticator().await;
+  let mut claims = claims();
+  claims.as_object_mut().unwrap().remove("tenant_id");
+  assert_unauthorized(authenticate(&auth, &token("test", &claims)));
+}
+
+#[tokio::test]
+async fn rejects_missing_and_forged_tokens() {
+  let auth = authenticator().await;
+  assert_unauthorized(auth.authenticate(None));
+  let forged = encode(&Header { kid: Some("test".into()), ..Header::default() }, &claims(), &EncodingKey::from_secret(b"guessed")).unwrap();
+  assert_unauthorized(authenticate(&auth, &forged));
+}
+
+#[tokio::test]
+async fn api_keys_sharing_a_prefix_get_distinct_subjects() {
+  let auth = authenticator().await;
+  let alpha = authenticate(&auth, "dtqs_alpha").unwrap();
+  let bravo = authenticate(&auth, "dtqs_bravo").unwrap();
+  assert_ne!(alpha.subject, bravo.subject);
+  assert!(!alpha.subject.contains("dtqs_"), "subject {} leaks the key", alpha.subject);
+  assert_eq!((alpha.tenant_id.as_str(), bravo.tenant_id.as_str()), ("acme", "globex"));
+  assert!(!bravo.has_scope("tasks:write"));
+}
@@ -0,0 +1,349 @@
+mod common;
+
+use std::time::Duration;
+use dtqs::broker::{Broker, Deliveries, Delivery, MemoryBroker, Message};
+use futures::StreamExt;
+
+fn message(body: &str, priority: i32) -> Message {
+  Message { payload: body.as_bytes().to_vec(), priority, ..Default::default() }
+}
+
+async fn next(deliveries: &mut Deliveries) -> Delivery {
+  tokio::time::timeout(Duration::from_secs(1), deliveries.next())
+    .await
+    .expect("no delivery within a second")
+    .expect("stream ended")
+    .expect("consumer error")
+}
+
+/// `true` if nothing is delivered within 100ms.
+async fn idle(deliveries: &mut Deliveries) -> bool {
+  tokio::time::timeout(Duration::from_millis(100), deliveries.next()).await.is_err()
+}
+
+fn body(delivery: &Delivery) -> &str {
+  std::str::from_utf8(&delivery.message.payload).unwrap()
+}
+
+#[tokio::test]
+async fn delivers_by_priority_then_publish_order() {
+  let broker = MemoryBroker::new();
+  for (name, priority) in [("low", 9), ("first", 1), ("second", 1), ("default", 5)] {
+    broker.publish("tasks", message(name, priority)).await.unwrap();
+  }
+  let mut deliveries = broker.consume("tasks", "c", 0).await.unwrap();
+  let mut order = Vec::new();
+  for _ in 0..4 {
+    let delivery = next(&mut deliveries).await;
+    order.push(body(&delivery).to_string());
+    broker.ack(&delivery).await.unwrap();
+  }
+  assert_eq!(order, ["first", "second", "default", "low"]);
+  assert_eq!(broker.len("tasks"), 0);
+}
+
+#[tokio::test]
+async fn prefetch_bounds_unsettled_deliveries() {
+  let broker = MemoryBroker::new();
+  for name in ["a", "b", "c"] {
+    broker.publish("tasks", message(name, 5)).await.unwrap();
+  }
+  let mut deliveries = broker.consume("tasks", "c", 2).await.unwrap();
+  let a = next(&mut deliveries).await;
+  next(&mut deliveries).await;
+  assert!(idle(&mut deliveries).await);
+
+  broker.ack(&a).await.unwrap();
+  assert_eq!(body(&next(&mut deliveries).await), "c");
+}
+
+#[tokio::test]
+async fn requeued_deliveries_keep_their_place() {
+  let broker = MemoryBroker::new();
+  for name in ["a", "b"] {
+    broker.publish("tasks", message(name, 5)).await.unwrap();
+  }
+  let mut deliveries = broker.consume("tasks", "c", 1).await.unwrap();
+  let a = next(&mut deliveries).await;
+  broker.nack(&a, true).await.unwrap();
+  let again = next(&mut deliveries).await;
+  assert_eq!(body(&again), "a");
+
+  broker.nack(&again, false).await.unwrap();
+  let b = next(&mut deliveries).await;
+  assert_eq!(body(&b), "b");
+  broker.ack(&b).await.unwrap();
+  assert!(idle(&mut deliveries).await);
+}
+
+#[tokio::test]
+async fn deliveries_settle_only_once() {
+  let broker = MemoryBroker::new();
+  broker.publish("tasks", message("a", 5)).await.unwrap();
+  let mut deliveries = broker.consume("tasks", "c", 0).await.unwrap();
+  let a = next(&mut deliveries).await;
+  broker.ack(&a).await.unwrap();
+  assert!(broker.ack(&a).await.is_err());
+  assert!(broker.nack(&a, true).await.is_err());
+  assert_eq!(broker.len("tasks"), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn delayed_deliveries_return_after_the_delay() {
+  let broker = MemoryBroker::new();
+  broker.publish("tasks", message("retry", 5)).await.unwrap();
+  let mut deliveries = broker.consume("tasks", "c", 0).await.unwrap();
+  let delivery = next(&mut deliveries).await;
+  broker.delay(&delivery, Duration::from_secs(8)).await.unwrap();
+
+  let started = tokio::time::Instant::now();
+  let retried = tokio::time::timeout(Duration::from_secs(10), deliveries.next()).await.unwrap().unwrap().unwrap();
+  assert_eq!(body(&retried), "retry");
+  assert!(started.elapsed() >= Duration::from_secs(8));
+}
+
+#[tokio::test]
+async fn dead_letters_go_to_the_dead_letter_queue() {
+  let broker = MemoryBroker::new();
+  broker.publish("tasks", message("poison", 5)).await.unwrap();
+  let mut deliveries = broker.consume("tasks", "c", 0).await.unwrap();
+  let delivery = next(&mut deliveries).await;
+  broker.dead_letter(&delivery).await.unwrap();
+  assert_eq!(broker.len("tasks"), 0);
+  assert_eq!(broker.len("tasks.dead"), 1);
+  assert!(idle(&mut deliveries).await);
+}
+
+#[tokio::test]
+async fn cancelling_ends_the_stream() {
+  let broker = MemoryBroker::new();
+  let mut deliveries = broker.consume("tasks", "c", 0).await.unwrap();
+  let waiting = tokio::spawn(async move { deliveries.next().await.is_none() });
+  tokio::task::yield_now().await;
+  broker.cancel("c").await.unwrap();
+  assert!(waiting.await.unwrap());
+  broker.publish("tasks", message("later", 5)).await.unwrap();
+  assert_eq!(broker.len("tasks"), 1);
+}
+
+/// The PostgreSQL queue lives in the `tasks` table, so these tests need a migrated database at
+/// `DATABASE_URL`. Each uses its own queue.
+mod postgres {
+  use std::time::Duration;
+  use dtqs::broker::{Broker, Delivery, PostgresBroker};
+  use sqlx::{Postgres, Pool};
+  use uuid::Uuid;
+  use super::{body, common, idle, message, next};
+
+  async fn setup(lease: Duration) -> (Pool<Postgres>, PostgresBroker, String) {
+    let db_pool = common::db_pool().await;
+    let broker = PostgresBroker::connect(db_pool.clone(), lease).await.unwrap();
+    (db_pool, broker, format!("test.{}", Uuid::new_v4()))
+  }
+
+  /// Inserts a task and publishes it with `name` as its payload.
+  async fn publish(db_pool: &Pool<Postgres>, broker: &PostgresBroker, queue: &str, name: &str, priority: i32) {
+    let task_id: Uuid = sqlx::query_scalar("INSERT INTO tasks (task_type, payload, status, priority) VALUES ('test', '{}', 'pending', $1) RETURNING id")
+      .bind(priority)
+      .fetch_one(db_pool)
+      .await
+      .unwrap();
+    let payload = serde_json::json!({ "task_id": task_id.to_string(), "name": name }).to_string();
+    broker.publish(queue, message(&payload, priority)).await.unwrap();
+  }
+
+  fn name(delivery: &Delivery) -> String {
+    serde_json::from_str::<serde_json::Value>(body(delivery)).unwrap()["name"].as_str().unwrap().to_string()
+  }
+
+  #[tokio::test]
+  #[ignore = "needs DATABASE_URL"]
+  async fn consumers_share_a_queue_by_priority() {
+    let (db_pool, broker, queue) = setup(Duration::from_secs(30)).await;
+    for (task, priority) in [("low", 9), ("first", 1), ("second", 1)] {
+      publish(&db_pool, &broker, &queue, task, priority).await;
+    }
+    let mut a = broker.consume(&queue, "a", 1).await.unwrap();
+    let mut b = broker.consume(&queue, "b", 1).await.unwrap();
+    let first = next(&mut a).await;
+    let second = next(&mut b).await;
+    assert_eq!((name(&first), name(&second)), ("first".to_string(), "second".to_string()));
+    assert!(idle(&mut a).await);
+
+    broker.ack(&first).await.unwrap();
+    assert_eq!(name(&next(&mut a).await), "low");
+    assert!(broker.ack(&first).await.is_err());
+  }
+
+  #[tokio::test]
+  #[ignore = "needs DATABASE_URL"]
+  async fn expired_leases_are_redelivered() {
+    let (db_pool, broker, queue) = setup(Duration::from_secs(1)).await;
+    let other = PostgresBroker::connect(db_pool.clone(), Duration::from_secs(1)).await.unwrap();
+    publish(&db_pool, &broker, &queue, "task", 5).await;
+    let mut deliveries = broker.consume(&queue, "c", 0).await.unwrap();
+    let delivery = next(&mut deliveries).await;
+    // A crashed worker stops renewing its leases.
+    drop(deliveries);
+    broker.close().await;
+
+    let mut others = other.consume(&queue, "c", 0).await.unwrap();
+    let redelivered = tokio::time::timeout(Duration::from_secs(5), futures::StreamExt::next(&mut others))
+      .await
+      .unwrap()
+      .unwrap()
+      .unwrap();
+    assert_eq!(name(&redelivered), "task");
+    assert!(broker.ack(&delivery).await.is_err());
+    other.ack(&redelivered).await.unwrap();
+  }
+
+  #[tokio::test]
+  #[ignore = "needs DATABASE_URL"]
+  async fn delays_and_dead_letters() {
+    let (db_pool, broker, queue) = setup(Duration::from_secs(30)).await;
+    publish(&db_pool, &broker, &queue, "task", 5).await;
+    let mut deliveries = broker.consume(&queue, "c", 0).await.unwrap();
+    let delivery = next(&mut deliveries).await;
+    broker.delay(&delivery, Duration::from_millis(1500)).await.unwrap();
+    assert!(idle(&mut deliveries).await);
+    let retried = tokio::time::timeout(Duration::from_secs(5), futures::StreamExt::next(&mut deliveries))
+      .await
+      .unwrap()
+      .unwrap()
+      .unwrap();
+
+    broker.dead_letter(&retried).await.unwrap();
+    let mut dead = broker.consume(&format!("{}.dead", queue), "d", 0).await.unwrap();
+    assert_eq!(name(&next(&mut dead).await), "task");
+    assert!(idle(&mut deliveries).await);
+  }
+}
+
+/// These need a Redis server at `REDIS_URL`. Each uses its own queue.
+mod redis {
+  use std::time::Duration;
+  use dtqs::broker::{Broker, RedisBroker};
+  use futures::StreamExt;
+  use uuid::Uuid;
+  use super::{body, common, idle, message, next};
+
+  async fn setup(lease: Duration) -> (String, RedisBroker, String) {
+    let url = common::require("REDIS_URL");
+    let broker = RedisBroker::connect(&url, lease).await.unwrap();
+    (url, broker, format!("test.{}", Uuid::new_v4()))
+  }
+
+  #[tokio::test]
+  #[ignore = "needs REDIS_URL"]
+  async fn delivers_by_priority_within_prefetch() {
+    let (_, broker, queue) = setup(Duration::from_secs(30)).await;
+    for (name, priority) in [("low", 9), ("first", 1), ("second", 1), ("urgent", 0)] {
+      broker.publish(&queue, message(name, priority)).await.unwrap();
+    }
+    let mut deliveries = broker.consume(&queue, "c", 2).await.unwrap();
+    let urgent = next(&mut deliveries).await;
+    let first = next(&mut deliveries).await;
+    assert_eq!((body(&urgent), body(&first)), ("urgent", "first"));
+    assert_eq!(first.message.priority, 1);
+    assert!(idle(&mut deliveries).await);
+
+    broker.ack(&urgent).await.unwrap();
+    assert!(broker.ack(&urgent).await.is_err());
+    assert_eq!(body(&next(&mut deliveries).await), "second");
+  }
+
+  #[tokio::test]
+  #[ignore = "needs REDIS_URL"]
+  async fn requeued_entries_come_back_first() {
+    let (_, broker, queue) = setup(Duration::from_secs(30)).await;
+    for name in ["a", "b"] {
+      broker.publish(&queue, message(name, 5)).await.unwrap();
+    }
+    let mut deliveries = broker.consume(&queue, "c", 1).await.unwrap();
+    let a = next(&mut deliveries).await;
+    broker.nack(&a, true).await.unwrap();
+    let again = next(&mut deliveries).await;
+    assert_eq!(body(&again), "a");
+
+    broker.nack(&again, false).await.unwrap();
+    let b = next(&mut deliveries).await;
+    assert_eq!(body(&b), "b");
+    broker.ack(&b).await.unwrap();
+    assert!(idle(&mut deliveries).await);
+  }
+
+  #[tokio::test]
+  #[ignore = "needs REDIS_URL"]
+  async fn expired_leases_are_reclaimed() {
+    let (url, broker, queue) = setup(Duration::from_secs(1)).await;
+    let other = RedisBroker::connect(&url, Duration::from_secs(1)).await.unwrap();
+    broker.publish(&queue, message("task", 5)).await.unwrap();
+    let mut deliveries = broker.consume(&queue, "a", 0).await.unwrap();
+    let delivery = next(&mut deliveries).await;
+    // A crashed worker stops renewing its entries.
+    drop(deliveries);
+    broker.close().await;
+
+    let mut others = other.consume(&queue, "b", 0).await.unwrap();
+    let reclaimed = tokio::time::timeout(Duration::from_secs(5), others.next()).await.unwrap().unwrap().unwrap();
+    assert_eq!(body(&reclaimed), "task");
+    assert!(broker.ack(&delivery).await.is_err());
+    other.ack(&reclaimed).await.unwrap();
+  }
+
+  #[tokio::test]
+  #[ignore = "needs REDIS_URL"]
+  async fn expired_entries_behind_live_ones_are_reclaimed() {
+    let (url, live, queue) = setup(Duration::from_secs(2)).await;
+    let crashed = RedisBroker::connect(&url, Duration::from_secs(2)).await.unwrap();
+    let other = RedisBroker::connect(&url, Duration::from_secs(2)).await.unwrap();
+    for i in 0..30 {
+      live.publish(&queue, message(&format!("live{}", i), 5)).await.unwrap();
+    }
+    live.publish(&queue, message("stranded", 5)).await.unwrap();
+    let mut renewed = live.consume(&queue, "live", 30).await.unwrap();
+    for _ in 0..30 {
+      next(&mut renewed).await;
+    }
+    let mut deliveries = crashed.consume(&queue, "crashed", 0).await.unwrap();
+    assert_eq!(body(&next(&mut deliveries).await), "stranded");
+    drop(deliveries);
+    crashed.close().await;
+
+    // Thirty entries still inside their leases sit ahead of it in the pending list.
+    let mut others = other.consume(&queue, "other", 0).await.unwrap();
+    let reclaimed = tokio::time::timeout(Duration::from_secs(5), others.next()).await.unwrap().unwrap().unwrap();
+    assert_eq!(body(&reclaimed), "stranded");
+  }
+
+  #[tokio::test]
+  #[ignore = "needs REDIS_URL"]
+  async fn delays_and_dead_letters() {
+    let (_, broker, queue) = setup(Duration::from_secs(30)).await;
+    broker.publish(&queue, message("task", 3)).await.unwrap();
+    let mut deliveries = broker.consume(&queue, "c", 0).await.unwrap();
+    let delivery = next(&mut deliveries).await;
+    broker.delay(&delivery, Duration::from_millis(1500)).await.unwrap();
+    assert!(idle(&mut deliveries).await);
+    let retried = tokio::time::timeout(Duration::from_secs(5), deliveries.next()).await.unwrap().unwrap().unwrap();
+    assert_eq!(body(&retried), "task");
+    assert_eq!(retried.message.priority, 3);
+
+    broker.dead_letter(&retried).await.unwrap();
+    let mut dead = broker.consume(&format!("{}.dead", queue), "d", 0).await.unwrap();
+    assert_eq!(body(&next(&mut dead).await), "task");
+    assert!(idle(&mut deliveries).await);
+  }
+
+  #[tokio::test]
+  #[ignore = "needs REDIS_URL"]
+  async fn cancelling_ends_the_stream() {
+    let (_, broker, queue) = setup(Duration::from_secs(30)).await;
+    let mut deliveries = broker.consume(&queue, "c", 0).await.unwrap();
+    let waiting = tokio::spawn(async move { deliveries.next().await.is_none() });
+    tokio::task::yield_now().await;
+    broker.cancel("c").await.unwrap();
+    assert!(tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap());
+  }
+}
@@ -0,0 +1,15 @@
+//! Setup for the suites that talk to PostgreSQL or Redis. Their tests are `#[ignore]`d so a plain
+//! `cargo test` needs no services; `cargo test -- --ignored` runs them and fails if the service
+//! variable is missing.
+
+use sqlx::PgPool;
+
+/// The value of `var`, which must be set when the ignored tests are run.
+pub fn require(var: &str) -> String {
+  std::env::var(var).unwrap_or_else(|_| panic!("{} must be set to run the ignored tests", var))
+}
+
+/// A pool on the migrated database at `DATABASE_URL`.
+pub async fn db_pool() -> PgPool {
+  PgPool::connect(&require("DATABASE_URL")).await.expect("Failed to connect to DATABASE_URL")
+}
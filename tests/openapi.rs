@@ -0,0 +1,89 @@
+use std::collections::BTreeSet;
+use dtqs::routes::docs::ApiDoc;
+use regex::Regex;
+use utoipa::OpenApi;
+
+const SPEC_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+
+/// Fails when a route or request/response type changes without regenerating `openapi.json`.
+/// Regenerate with `UPDATE_OPENAPI=1 cargo test --test openapi`.
+#[test]
+fn openapi_document_is_up_to_date() {
+  let generated = ApiDoc::openapi().to_pretty_json().expect("serialize OpenAPI document") + "\n";
+  if std::env::var_os("UPDATE_OPENAPI").is_some() {
+    std::fs::write(SPEC_PATH, &generated).expect("write openapi.json");
+    return;
+  }
+  let committed = std::fs::read_to_string(SPEC_PATH).unwrap_or_default();
+  assert!(
+    committed == generated,
+    "openapi.json is out of date; run `UPDATE_OPENAPI=1 cargo test --test openapi` and commit the result"
+  );
+}
+
+/// Every `warp::path` route under `src/routes`, as `(METHOD, /path/{})`. Routes are declared as a
+/// path filter directly followed by a method filter, which is all this needs to recognise.
+fn routed_operations() -> BTreeSet<(String, String)> {
+  let route = Regex::new(r#"warp::path!?\(([^)]*)\)\s*\.and\(warp::(get|post|put|patch|delete)\(\)\)"#).unwrap();
+  let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/src/routes");
+  let mut operations = BTreeSet::new();
+  for entry in std::fs::read_dir(dir).unwrap() {
+    let path = entry.unwrap().path();
+    // The docs routes serve the document itself.
+    if path.file_name().unwrap() == "docs.rs" {
+      continue;
+    }
+    let source = std::fs::read_to_string(&path).unwrap();
+    for route in route.captures_iter(&source) {
+      let segments: Vec<String> = route[1]
+        .split('/')
+        .map(|segment| match segment.trim().strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+          Some(literal) => literal.to_string(),
+          None => "{}".to_string(),
+        })
+        .collect();
+      operations.insert((route[2].to_uppercase(), format!("/{}", segments.join("/"))));
+    }
+  }
+  operations
+}
+
+fn documented_operations() -> BTreeSet<(String, String)> {
+  let param = Regex::new(r"\{[^}]+\}").unwrap();
+  let value = serde_json::to_value(ApiDoc::openapi()).unwrap();
+  let mut operations = BTreeSet::new();
+  for (path, item) in value["paths"].as_object().unwrap() {
+    for method in item.as_object().unwrap().keys() {
+      operations.insert((method.to_uppercase(), param.replace_all(path, "{}").into_owned()));
+    }
+  }
+  operations
+}
+
+#[test]
+fn every_route_is_documented() {
+  let routed = routed_operations();
+  assert!(routed.contains(&("POST".to_string(), "/tasks/{}/cancel".to_string())), "route scan found {:?}", routed);
+  let documented = documented_operations();
+  let undocumented: Vec<_> = routed.difference(&documented).collect();
+  assert!(undocumented.is_empty(), "routes missing from ApiDoc: {:?}", undocumented);
+  let stale: Vec<_> = documented.difference(&routed).collect();
+  assert!(stale.is_empty(), "ApiDoc documents routes that do not exist: {:?}", stale);
+}
+
+#[tokio::test]
+async fn swagger_ui_is_served_without_a_cdn() {
+  let docs = dtqs::routes::docs::docs_routes();
+  let redirect = warp::test::request().path("/docs").reply(&docs).await;
+  assert_eq!(redirect.headers()["location"], "/docs/");
+
+  let page = warp::test::request().path("/docs/").reply(&docs).await;
+  assert_eq!(page.status(), 200);
+  let page = String::from_utf8_lossy(page.body());
+  assert!(!page.contains("unpkg.com"));
+
+  let initializer = warp::test::request().path("/docs/swagger-initializer.js").reply(&docs).await;
+  assert!(String::from_utf8_lossy(initializer.body()).contains("/openapi.json"));
+  let stylesheet = warp::test::request().path("/docs/swagger-ui.css").reply(&docs).await;
+  assert_eq!(stylesheet.headers()["content-type"], "text/css");
+}
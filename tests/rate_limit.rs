@@ -0,0 +1,95 @@
+//! Rules live in `rate_limit_rules`, so these tests need a migrated database at `DATABASE_URL`.
+//! Each test uses its own tenant and task type, and runs against both bucket stores.
+
+mod common;
+
+use std::time::Duration;
+use dtqs::auth::Principal;
+use dtqs::rate_limit::{Limit, RateLimitRule, RateLimiter};
+use uuid::Uuid;
+
+const BACKENDS: [&str; 2] = ["memory", "postgres"];
+
+struct Fixture {
+  limiter: RateLimiter,
+  principal: Principal,
+  task_type: String,
+}
+
+async fn setup(backend: &str) -> Fixture {
+  let id = Uuid::new_v4();
+  Fixture {
+    limiter: RateLimiter::new(common::db_pool().await, backend),
+    principal: Principal { subject: format!("test-{}", id), tenant_id: format!("tenant-{}", id), scopes: vec![] },
+    task_type: format!("type-{}", id),
+  }
+}
+
+impl Fixture {
+  async fn rule(&self, bucket_key: String, capacity: f64, refill_per_sec: f64) {
+    self.limiter.set_rule(RateLimitRule { bucket_key, limit: Limit { capacity, refill_per_sec } }).await.unwrap();
+  }
+
+  /// `Ok`, or the bucket that rejected.
+  async fn submit(&self, task_type: &str) -> Result<(), String> {
+    self.limiter.check_submission(&self.principal, task_type).await.map_err(|e| e.bucket_key)
+  }
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL"]
+async fn exact_rules_win_over_wildcards() {
+  for backend in BACKENDS {
+    let f = setup(backend).await;
+    f.rule(format!("task_type:*:{}", f.task_type), 5.0, 0.001).await;
+    f.rule(format!("task_type:{}:{}", f.principal.tenant_id, f.task_type), 1.0, 0.001).await;
+    assert_eq!(f.submit(&f.task_type).await, Ok(()));
+    assert_eq!(f.submit(&f.task_type).await, Err(format!("task_type:{}:{}", f.principal.tenant_id, f.task_type)));
+  }
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL"]
+async fn wildcard_rules_give_each_tenant_its_own_bucket() {
+  for backend in BACKENDS {
+    let f = setup(backend).await;
+    f.rule(format!("task_type:*:{}", f.task_type), 1.0, 0.001).await;
+    assert_eq!(f.submit(&f.task_type).await, Ok(()));
+    assert_eq!(f.submit(&f.task_type).await, Err(format!("task_type:{}:{}", f.principal.tenant_id, f.task_type)));
+
+    let other = Principal { tenant_id: format!("{}-other", f.principal.tenant_id), ..f.principal.clone() };
+    assert!(f.limiter.check_submission(&other, &f.task_type).await.is_ok());
+  }
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL"]
+async fn buckets_refill_over_time() {
+  for backend in BACKENDS {
+    let f = setup(backend).await;
+    f.rule(format!("tenant:{}", f.principal.tenant_id), 1.0, 20.0).await;
+    assert_eq!(f.submit(&f.task_type).await, Ok(()));
+    let rejected = f.limiter.check_submission(&f.principal, &f.task_type).await.unwrap_err();
+    assert_eq!(rejected.retry_after, 1);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(f.submit(&f.task_type).await, Ok(()));
+  }
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL"]
+async fn rejected_requests_do_not_drain_other_buckets() {
+  for backend in BACKENDS {
+    let f = setup(backend).await;
+    f.rule(format!("tenant:{}", f.principal.tenant_id), 2.0, 0.001).await;
+    f.rule(format!("task_type:{}:{}", f.principal.tenant_id, f.task_type), 1.0, 0.001).await;
+    assert_eq!(f.submit(&f.task_type).await, Ok(()));
+    // Rejected by the task type bucket after a tenant token was taken.
+    assert!(f.submit(&f.task_type).await.is_err());
+
+    // The tenant's second token is still there for another task type.
+    assert_eq!(f.submit("another-type").await, Ok(()));
+    assert_eq!(f.submit("another-type").await, Err(format!("tenant:{}", f.principal.tenant_id)));
+  }
+}
@@ -0,0 +1,52 @@
+use dtqs::error::{handle_rejection, ApiError, ErrorBody};
+use dtqs::request_id;
+use warp::hyper::{body, Body, Request, Response};
+use warp::Filter;
+
+async fn call(path: &str, incoming: Option<&str>) -> Response<Body> {
+  let ok = warp::path("ok").map(warp::reply);
+  let fails = warp::path("fails").and_then(|| async { Err::<String, _>(warp::reject::custom(ApiError::Conflict("nope".into()))) });
+  let service = warp::service(ok.or(fails).recover(handle_rejection));
+
+  let mut request = Request::get(path);
+  if let Some(id) = incoming {
+    request = request.header("x-request-id", id);
+  }
+  request_id::handle(service, request.body(Body::empty()).unwrap()).await.unwrap()
+}
+
+fn header(response: &Response<Body>) -> String {
+  response.headers()["x-request-id"].to_str().unwrap().to_string()
+}
+
+async fn error_body(response: Response<Body>) -> ErrorBody {
+  serde_json::from_slice(&body::to_bytes(response.into_body()).await.unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn successful_responses_carry_a_request_id() {
+  let response = call("/ok", None).await;
+  assert!(response.status().is_success());
+  assert!(uuid::Uuid::parse_str(&header(&response)).is_ok());
+}
+
+#[tokio::test]
+async fn errors_report_the_same_id_as_the_header() {
+  let response = call("/fails", None).await;
+  let id = header(&response);
+  assert_eq!(error_body(response).await.error.request_id, id);
+}
+
+#[tokio::test]
+async fn incoming_ids_are_kept() {
+  assert_eq!(header(&call("/ok", Some("edge-42")).await), "edge-42");
+  let response = call("/fails", Some("edge-43")).await;
+  assert_eq!(header(&response), "edge-43");
+  assert_eq!(error_body(response).await.error.request_id, "edge-43");
+}
+
+#[tokio::test]
+async fn implausible_incoming_ids_are_replaced() {
+  let id = header(&call("/ok", Some("spaces are not allowed")).await);
+  assert!(uuid::Uuid::parse_str(&id).is_ok());
+}
@@ -0,0 +1,92 @@
+use dtqs::sanitize::{self, FieldPolicies};
+use serde_json::{json, Value};
+
+fn policies(policies: Value) -> FieldPolicies {
+  serde_json::from_value(policies).unwrap()
+}
+
+/// The cleaned payload, or the error messages by JSON pointer.
+fn apply(policies: &FieldPolicies, mut payload: Value) -> Result<Value, Vec<(String, String)>> {
+  sanitize::apply(policies, &mut payload)
+    .map(|_| payload)
+    .map_err(|errors| errors.into_iter().map(|e| (e.path, e.message)).collect())
+}
+
+fn rejected(policies: &FieldPolicies, payload: Value) -> bool {
+  apply(policies, payload).is_err()
+}
+
+#[test]
+fn emails_must_look_like_addresses() {
+  let p = policies(json!({"to": {"kind": "email", "max_length": 20}}));
+  assert!(apply(&p, json!({"to": "ops@example.com"})).is_ok());
+  assert!(rejected(&p, json!({"to": "ops@localhost"})));
+  assert!(rejected(&p, json!({"to": "<ops@example.com>"})));
+  assert!(rejected(&p, json!({"to": "two words@example.com"})));
+  assert!(rejected(&p, json!({"to": "someone.long@example.com"})));
+  assert_eq!(apply(&p, json!({"to": 42})), Err(vec![("/to".to_string(), "must be a string".to_string())]));
+}
+
+#[test]
+fn urls_are_limited_to_allowed_schemes_and_hosts() {
+  let p = policies(json!({"/image/src": {"kind": "url", "hosts": ["cdn.example.com", ".assets.test"]}}));
+  let payload = |url: &str| json!({"image": {"src": url}});
+  assert!(apply(&p, payload("https://cdn.example.com/a.png")).is_ok());
+  assert!(apply(&p, payload("https://eu.assets.test/a.png")).is_ok());
+  assert!(apply(&p, payload("https://assets.test/a.png")).is_ok());
+
+  assert!(rejected(&p, payload("http://cdn.example.com/a.png")));
+  assert!(rejected(&p, payload("file:///etc/passwd")));
+  assert!(rejected(&p, payload("https://evil.test/a.png")));
+  assert!(rejected(&p, payload("https://cdn.example.com.evil.test/a.png")));
+  assert!(rejected(&p, payload("https://evilassets.test/a.png")));
+  assert!(rejected(&p, payload("not a url")));
+
+  let any_host = policies(json!({"src": {"kind": "url", "schemes": ["https", "s3"]}}));
+  assert!(apply(&any_host, json!({"src": "s3://bucket/key"})).is_ok());
+  assert!(rejected(&any_host, json!({"src": "ftp://bucket/key"})));
+}
+
+#[test]
+fn paths_must_stay_under_their_root() {
+  let p = policies(json!({"file": {"kind": "path", "root": "/data"}}));
+  assert!(apply(&p, json!({"file": "videos/clip.mp4"})).is_ok());
+  assert!(rejected(&p, json!({"file": "../etc/passwd"})));
+  assert!(rejected(&p, json!({"file": "videos/../../etc/passwd"})));
+  assert!(rejected(&p, json!({"file": "/etc/passwd"})));
+  assert!(apply(&p, json!({"file": "/data/videos/clip.mp4"})).is_ok());
+}
+
+#[test]
+fn plain_text_is_kept_as_sent() {
+  let p = policies(json!({"subject": {"kind": "text", "max_length": 10}}));
+  assert_eq!(apply(&p, json!({"subject": "Q&A"})), Ok(json!({"subject": "Q&A"})));
+  assert_eq!(apply(&p, json!({"subject": "a < b"})), Ok(json!({"subject": "a < b"})));
+  assert_eq!(apply(&p, json!({"subject": "<b>bold</b>"})), Err(vec![("/subject".to_string(), "must be at most 10 characters".to_string())]));
+}
+
+#[test]
+fn text_is_stripped_of_html() {
+  let p = policies(json!({"content": {"kind": "text", "strip_html": true}}));
+  assert_eq!(apply(&p, json!({"content": "<p>Hello <b>there</b></p>"})), Ok(json!({"content": "Hello there"})));
+  assert_eq!(apply(&p, json!({"content": "Hi<script>alert(1)</script>"})), Ok(json!({"content": "Hi"})));
+  assert_eq!(apply(&p, json!({"content": "<img src=x onerror=alert(1)>ok"})), Ok(json!({"content": "ok"})));
+  // Markup the old tag regex let through.
+  assert_eq!(apply(&p, json!({"content": "<<b>script>alert(1)<</b>/script>"})), Ok(json!({"content": "&lt;script&gt;alert(1)&lt;/script&gt;"})));
+  assert_eq!(apply(&p, json!({"content": "plain\ttext\nlines"})), Ok(json!({"content": "plain\ttext\nlines"})));
+  // The result is HTML, so text that looks like markup stays escaped.
+  assert_eq!(apply(&p, json!({"content": "Q&A"})), Ok(json!({"content": "Q&amp;A"})));
+}
+
+#[test]
+fn text_rejects_control_characters() {
+  let p = policies(json!({"content": {"kind": "text"}}));
+  assert!(rejected(&p, json!({"content": "bell\u{7}"})));
+  assert!(rejected(&p, json!({"content": "esc\u{1b}[31m"})));
+}
+
+#[test]
+fn absent_fields_are_left_to_the_schema() {
+  let p = policies(json!({"to": {"kind": "email"}, "/a/b": {"kind": "text"}}));
+  assert_eq!(apply(&p, json!({"other": "x"})), Ok(json!({"other": "x"})));
+}
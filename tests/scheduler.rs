@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use dtqs::broker::{Delivery, Message};
+use dtqs::worker_scheduler::{Policy, ScheduledTask, Scheduler};
+use opentelemetry::Context;
+use serde_json::json;
+
+fn task(id: &str, priority: u8) -> ScheduledTask {
+  task_of(id, priority, "email", "default")
+}
+
+fn task_of(id: &str, priority: u8, task_type: &str, tenant_id: &str) -> ScheduledTask {
+  ScheduledTask {
+    priority,
+    delivery: Delivery { queue: "task_queue".into(), tag: 0, message: Message::default() },
+    task_data: json!({ "task_id": id, "task_type": task_type, "tenant_id": tenant_id }),
+    trace_context: Context::new(),
+    consumer_generation: 0,
+  }
+}
+
+async fn order(scheduler: &Scheduler) -> Vec<String> {
+  let mut ids = Vec::new();
+  while let Some(dispatched) = scheduler.get_next().await {
+    ids.push(dispatched.task.task_data["task_id"].as_str().unwrap().to_string());
+  }
+  ids
+}
+
+#[tokio::test]
+async fn lower_priority_values_run_first() {
+  let scheduler = Scheduler::new(10, None);
+  scheduler.add_task(task("five", 5)).await;
+  scheduler.add_task(task("one", 1)).await;
+  scheduler.add_task(task("nine", 9)).await;
+  assert_eq!(order(&scheduler).await, ["one", "five", "nine"]);
+}
+
+#[tokio::test]
+async fn equal_priorities_run_in_arrival_order() {
+  let scheduler = Scheduler::new(10, None);
+  for id in ["a", "b", "c", "d", "e"] {
+    scheduler.add_task(task(id, 5)).await;
+  }
+  scheduler.add_task(task("urgent", 0)).await;
+  scheduler.add_task(task("f", 5)).await;
+  assert_eq!(order(&scheduler).await, ["urgent", "a", "b", "c", "d", "e", "f"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn without_aging_priority_is_strict() {
+  let scheduler = Scheduler::new(10, None);
+  scheduler.add_task(task("old-low", 9)).await;
+  tokio::time::advance(Duration::from_secs(3600)).await;
+  scheduler.add_task(task("new-high", 1)).await;
+  assert_eq!(order(&scheduler).await, ["new-high", "old-low"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn aging_promotes_waiting_tasks() {
+  let scheduler = Scheduler::new(10, Some(Duration::from_secs(10)));
+  scheduler.add_task(task("old-low", 9)).await;
+  tokio::time::advance(Duration::from_secs(60)).await;
+  // 9 aged by six levels is 3, ahead of a fresh 5 but still behind a fresh 1.
+  scheduler.add_task(task("new-normal", 5)).await;
+  scheduler.add_task(task("new-high", 1)).await;
+  assert_eq!(order(&scheduler).await, ["new-high", "old-low", "new-normal"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn aged_ties_go_to_the_older_task() {
+  let scheduler = Scheduler::new(10, Some(Duration::from_secs(10)));
+  scheduler.add_task(task("old-low", 9)).await;
+  tokio::time::advance(Duration::from_secs(40)).await;
+  scheduler.add_task(task("new-normal", 5)).await;
+  assert_eq!(order(&scheduler).await, ["old-low", "new-normal"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn aging_keeps_fifo_within_a_level() {
+  let scheduler = Scheduler::new(10, Some(Duration::from_secs(10)));
+  scheduler.add_task(task("first", 7)).await;
+  tokio::time::advance(Duration::from_secs(5)).await;
+  scheduler.add_task(task("second", 7)).await;
+  tokio::time::advance(Duration::from_secs(30)).await;
+  assert_eq!(order(&scheduler).await, ["first", "second"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_full_buffer_applies_backpressure() {
+  let scheduler = Scheduler::new(1, None);
+  scheduler.add_task(task("a", 5)).await;
+  assert!(tokio::time::timeout(Duration::from_secs(1), scheduler.add_task(task("b", 5))).await.is_err());
+  assert!(scheduler.get_next().await.is_some());
+  tokio::time::timeout(Duration::from_secs(1), scheduler.add_task(task("c", 5))).await.unwrap();
+  assert_eq!(order(&scheduler).await, ["c"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn zero_capacity_buffers_without_limit() {
+  let scheduler = Scheduler::new(0, None);
+  for i in 0..100 {
+    tokio::time::timeout(Duration::from_secs(1), scheduler.add_task(task(&i.to_string(), 5))).await.unwrap();
+  }
+  assert_eq!(order(&scheduler).await.len(), 100);
+}
+
+#[tokio::test]
+async fn weighted_round_robin_interleaves_task_types() {
+  let weights = HashMap::from([("email".to_string(), 2)]);
+  let scheduler = Scheduler::new(20, None).with_policy(Policy::WeightedTaskTypes { weights });
+  for i in 1..=4 {
+    scheduler.add_task(task_of(&format!("video{}", i), 5, "video", "default")).await;
+  }
+  for i in 1..=4 {
+    scheduler.add_task(task_of(&format!("email{}", i), 5, "email", "default")).await;
+  }
+  // A burst of videos queued first still only gets one start per turn against two emails.
+  assert_eq!(
+    order(&scheduler).await,
+    ["video1", "email1", "email2", "video2", "email3", "email4", "video3", "video4"]
+  );
+}
+
+#[tokio::test]
+async fn deficit_round_robin_shares_work_across_tenants() {
+  let costs = HashMap::from([("video".to_string(), 3)]);
+  let scheduler = Scheduler::new(20, None)
+    .with_policy(Policy::TenantDeficit { quantum: 1, weights: HashMap::new(), costs });
+  for i in 1..=2 {
+    scheduler.add_task(task_of(&format!("a-video{}", i), 5, "video", "a")).await;
+  }
+  for i in 1..=6 {
+    scheduler.add_task(task_of(&format!("b-email{}", i), 5, "email", "b")).await;
+  }
+  // Each video costs tenant `a` three turns of credit, during which `b` runs three emails.
+  assert_eq!(
+    order(&scheduler).await,
+    ["b-email1", "b-email2", "a-video1", "b-email3", "b-email4", "b-email5", "a-video2", "b-email6"]
+  );
+}
+
+#[tokio::test]
+async fn class_limits_skip_saturated_classes() {
+  let limits = HashMap::from([("video".to_string(), 1)]);
+  let scheduler = Scheduler::new(10, None).with_class_limits(limits);
+  scheduler.add_task(task_of("video1", 0, "video", "default")).await;
+  scheduler.add_task(task_of("video2", 0, "video", "default")).await;
+  scheduler.add_task(task_of("email1", 5, "email", "default")).await;
+
+  let running = scheduler.get_next().await.unwrap();
+  assert_eq!(running.task.task_data["task_id"], "video1");
+  // video2 outranks email1 but has to wait for video1's slot.
+  let next = scheduler.get_next().await.unwrap();
+  assert_eq!(next.task.task_data["task_id"], "email1");
+  assert!(scheduler.get_next().await.is_none());
+
+  drop(running);
+  assert_eq!(order(&scheduler).await, ["video2"]);
+}
+
+#[tokio::test]
+async fn task_type_limits_let_other_types_through() {
+  let limits = HashMap::from([("video".to_string(), 1)]);
+  let scheduler = Scheduler::new(10, None)
+    .with_policy(Policy::TenantDeficit { quantum: 1, weights: HashMap::new(), costs: HashMap::new() })
+    .with_task_type_limits(limits);
+  scheduler.add_task(task_of("video1", 5, "video", "a")).await;
+  scheduler.add_task(task_of("video2", 5, "video", "a")).await;
+  scheduler.add_task(task_of("email1", 5, "email", "a")).await;
+
+  let running = scheduler.get_next().await.unwrap();
+  assert_eq!(running.task.task_data["task_id"], "video1");
+  // Within tenant `a`, email1 skips ahead of video2 while the only video slot is taken.
+  assert_eq!(order(&scheduler).await, ["email1"]);
+
+  drop(running);
+  assert_eq!(order(&scheduler).await, ["video2"]);
+}
+
+#[tokio::test]
+async fn next_waits_for_a_task_and_for_a_free_slot() {
+  let limits = HashMap::from([("video".to_string(), 1)]);
+  let scheduler = Arc::new(Scheduler::new(10, None).with_task_type_limits(limits));
+
+  let first = tokio::spawn({
+    let scheduler = scheduler.clone();
+    async move { scheduler.next().await }
+  });
+  tokio::task::yield_now().await;
+  scheduler.add_task(task_of("video1", 5, "video", "a")).await;
+  let running = first.await.unwrap();
+  assert_eq!(running.task.task_data["task_id"], "video1");
+
+  scheduler.add_task(task_of("video2", 5, "video", "a")).await;
+  let mut second = tokio::spawn({
+    let scheduler = scheduler.clone();
+    async move { scheduler.next().await }
+  });
+  assert!(tokio::time::timeout(Duration::from_millis(50), &mut second).await.is_err());
+  drop(running);
+  let next = tokio::time::timeout(Duration::from_secs(1), second).await.unwrap().unwrap();
+  assert_eq!(next.task.task_data["task_id"], "video2");
+}
+
+#[tokio::test]
+async fn drain_where_leaves_other_tasks_queued_and_frees_their_slots() {
+  let scheduler = Scheduler::new(3, None).with_policy(Policy::WeightedTaskTypes { weights: HashMap::new() });
+  let from = |id: &str, task_type: &str, queue: &str, consumer_generation: u64| ScheduledTask {
+    delivery: Delivery { queue: queue.into(), tag: 0, message: Message::default() },
+    consumer_generation,
+    ..task_of(id, 5, task_type, "default")
+  };
+  scheduler.add_task(from("lost", "email", "emails", 1)).await;
+  scheduler.add_task(from("restarted", "email", "emails", 2)).await;
+  scheduler.add_task(from("other", "video", "videos", 1)).await;
+
+  let drained = scheduler.drain_where(|task| task.delivery.queue == "emails" && task.consumer_generation == 1).await;
+  assert_eq!(drained.iter().map(|task| task.task_data["task_id"].as_str().unwrap()).collect::<Vec<_>>(), ["lost"]);
+
+  // The drained task's slot is free again, so the buffer takes a new one without waiting.
+  tokio::time::timeout(Duration::from_secs(1), scheduler.add_task(from("new", "video", "videos", 1))).await.unwrap();
+  assert_eq!(order(&scheduler).await, ["restarted", "other", "new"]);
+}
@@ -0,0 +1,87 @@
+//! Registers task types under fresh names, so these tests need a migrated database at
+//! `DATABASE_URL`.
+
+mod common;
+
+use std::sync::Arc;
+use dtqs::task_types::{TaskTypeError, TaskTypeRegistry};
+use serde_json::json;
+use uuid::Uuid;
+
+async fn setup() -> (Arc<TaskTypeRegistry>, String) {
+  let registry = TaskTypeRegistry::new(common::db_pool().await);
+  (Arc::new(registry), format!("type-{}", Uuid::new_v4().simple()))
+}
+
+fn schema() -> serde_json::Value {
+  json!({"type": "object", "required": ["to"], "properties": {"to": {"type": "string"}, "count": {"type": "integer"}}})
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL"]
+async fn registering_bumps_the_version() {
+  let (registry, name) = setup().await;
+  assert_eq!(registry.register(&name, schema(), Some(json!({}))).await.unwrap(), 1);
+  assert_eq!(registry.register(&name, json!({"type": "object"}), Some(json!({}))).await.unwrap(), 2);
+
+  // The latest version applies unless one is pinned.
+  assert_eq!(registry.validate(&name, None, &mut json!({})).await.unwrap(), 2);
+  assert!(matches!(registry.validate(&name, Some(1), &mut json!({})).await, Err(TaskTypeError::InvalidPayload(_))));
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL"]
+async fn concurrent_registrations_get_distinct_versions() {
+  let (registry, name) = setup().await;
+  let registrations = (0..8).map(|_| {
+    let (registry, name) = (registry.clone(), name.clone());
+    tokio::spawn(async move { registry.register(&name, schema(), Some(json!({}))).await.unwrap() })
+  });
+  let mut versions: Vec<i32> = futures::future::join_all(registrations).await.into_iter().map(Result::unwrap).collect();
+  versions.sort();
+  assert_eq!(versions, (1..=8).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL"]
+async fn schema_violations_are_reported_per_field() {
+  let (registry, name) = setup().await;
+  registry.register(&name, schema(), Some(json!({}))).await.unwrap();
+  let Err(TaskTypeError::InvalidPayload(errors)) = registry.validate(&name, None, &mut json!({"count": "three"})).await else {
+    panic!("expected the payload to be rejected");
+  };
+  let mut paths: Vec<&str> = errors.iter().map(|e| e.path.as_str()).collect();
+  paths.sort();
+  assert_eq!(paths, ["", "/count"]);
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL"]
+async fn unknown_types_and_versions_are_rejected() {
+  let (registry, name) = setup().await;
+  assert!(matches!(registry.validate(&name, None, &mut json!({})).await, Err(TaskTypeError::UnknownType(n)) if n == name));
+  registry.register(&name, schema(), Some(json!({}))).await.unwrap();
+  assert!(matches!(registry.validate(&name, Some(7), &mut json!({"to": "x"})).await, Err(TaskTypeError::UnknownVersion(n, 7)) if n == name));
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL"]
+async fn invalid_schemas_and_policies_are_not_stored() {
+  let (registry, name) = setup().await;
+  assert!(matches!(registry.register(&name, json!({"type": "no-such-type"}), Some(json!({}))).await, Err(TaskTypeError::InvalidSchema(_))));
+  assert!(matches!(registry.register(&name, schema(), Some(json!({"to": "no-such-policy"}))).await, Err(TaskTypeError::InvalidSchema(_))));
+  registry.reload().await.unwrap();
+  assert!(!registry.contains(&name).await);
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL"]
+async fn field_policies_carry_over_unless_replaced() {
+  let (registry, name) = setup().await;
+  registry.register(&name, schema(), Some(json!({"to": {"kind": "email"}}))).await.unwrap();
+  registry.register(&name, schema(), None).await.unwrap();
+  assert!(matches!(registry.validate(&name, Some(2), &mut json!({"to": "not an address"})).await, Err(TaskTypeError::InvalidPayload(_))));
+
+  registry.register(&name, schema(), Some(json!({}))).await.unwrap();
+  assert_eq!(registry.validate(&name, Some(3), &mut json!({"to": "not an address"})).await.unwrap(), 3);
+}
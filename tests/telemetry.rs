@@ -0,0 +1,41 @@
+use std::collections::HashMap;
+use dtqs::telemetry::{trace_context, trace_headers};
+use opentelemetry::global;
+use opentelemetry::trace::{TraceContextExt, TracerProvider};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// A worker span parented from the message headers must land in the publisher's trace.
+#[test]
+fn trace_context_round_trips_through_message_headers() {
+  let exporter = InMemorySpanExporter::default();
+  let provider = SdkTracerProvider::builder().with_simple_exporter(exporter.clone()).build();
+  global::set_text_map_propagator(TraceContextPropagator::new());
+  let subscriber = tracing_subscriber::registry()
+    .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+  tracing::subscriber::with_default(subscriber, || {
+    let publish = tracing::info_span!("publish_message");
+    let headers = publish.in_scope(trace_headers);
+    assert!(headers.contains_key("traceparent"));
+
+    let process = tracing::info_span!("process_task");
+    process.set_parent(trace_context(&headers));
+    drop(process);
+    drop(publish);
+  });
+
+  let spans = exporter.get_finished_spans().unwrap();
+  assert_eq!(spans.len(), 2);
+  let publish = spans.iter().find(|s| s.name == "publish_message").unwrap();
+  let process = spans.iter().find(|s| s.name == "process_task").unwrap();
+  assert_eq!(process.span_context.trace_id(), publish.span_context.trace_id());
+  assert_eq!(process.parent_span_id, publish.span_context.span_id());
+}
+
+#[test]
+fn missing_headers_give_an_empty_context() {
+  assert!(!trace_context(&HashMap::new()).span().span_context().is_valid());
+}
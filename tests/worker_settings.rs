@@ -0,0 +1,25 @@
+use std::path::Path;
+use dtqs::config::{parse_map, Config};
+use dtqs::worker_runtime::WorkerSettings;
+
+#[test]
+fn workers_route_like_the_api_config() {
+  let config = Config::from_file(Path::new("tests/fixtures/routing.env")).unwrap();
+  let settings = WorkerSettings::from_config("w1".into(), &config);
+  assert_eq!(settings.queues, ["task_queue.email", "task_queue.media"]);
+  assert_eq!(settings.routing.queue("acme", "video"), config.routing().queue("acme", "video"));
+}
+
+#[test]
+fn maps_parse_name_value_pairs() {
+  let weights: std::collections::HashMap<String, u32> = parse_map("WEIGHTS", Some("email=5, video=1,".into()));
+  assert_eq!(weights.len(), 2);
+  assert_eq!(weights["email"], 5);
+  assert!(parse_map::<u32>("WEIGHTS", None).is_empty());
+}
+
+#[test]
+#[should_panic(expected = "Invalid TASK_QUEUE_POOLS entry: image")]
+fn malformed_map_entries_are_rejected() {
+  parse_map::<String>("TASK_QUEUE_POOLS", Some("image,video=media".into()));
+}